@@ -0,0 +1,111 @@
+use std::cmp::Ordering;
+use std::time::Duration;
+
+use crate::error::Error;
+use crate::family::{ColumnFamily, SharedState};
+
+pub(crate) enum BatchOp {
+    Put(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+    DeleteRange(Vec<u8>, Vec<u8>),
+    Merge(Vec<u8>, Vec<u8>),
+    PutWithTtl(Vec<u8>, Vec<u8>, Duration)
+}
+
+/// Writes into one or more column families, applied atomically by `ScottDB::write`. Later
+/// writes of a batch take precedence over earlier ones.
+pub struct WriteBatch<'a> {
+    ops: Vec<(&'a ColumnFamily<'a>, BatchOp)>
+}
+
+impl<'a> Default for WriteBatch<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> WriteBatch<'a> {
+    pub fn new() -> Self {
+        Self { ops: Vec::new() }
+    }
+
+    pub fn put(&mut self, cf: &'a ColumnFamily<'a>, key: &[u8], value: &[u8]) {
+        self.ops.push((cf, BatchOp::Put(key.to_vec(), value.to_vec())));
+    }
+
+    /// Puts a value which reads as absent once `ttl` elapsed, by the clock in the options of `cf`.
+    pub fn put_with_ttl(&mut self,
+                        cf: &'a ColumnFamily<'a>,
+                        key: &[u8],
+                        value: &[u8],
+                        ttl: Duration) {
+        self.ops.push((cf, BatchOp::PutWithTtl(key.to_vec(), value.to_vec(), ttl)));
+    }
+
+    pub fn delete(&mut self, cf: &'a ColumnFamily<'a>, key: &[u8]) {
+        self.ops.push((cf, BatchOp::Delete(key.to_vec())));
+    }
+
+    /// Deletes all keys in `[start, end)`.
+    pub fn delete_range(&mut self,
+                        cf: &'a ColumnFamily<'a>,
+                        start: &[u8],
+                        end: &[u8]) {
+        self.ops.push((cf, BatchOp::DeleteRange(start.to_vec(), end.to_vec())));
+    }
+
+    /// Merges `operand` into the value of `key`, requires a merge operator in the options of `cf`.
+    pub fn merge(&mut self, cf: &'a ColumnFamily<'a>, key: &[u8], operand: &[u8]) {
+        self.ops.push((cf, BatchOp::Merge(key.to_vec(), operand.to_vec())));
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        for (family, op) in self.ops.iter() {
+            family.validate(op)?;
+        }
+        Ok(())
+    }
+
+    /// Applies the writes with consecutive seqs starting from `first_seq`. If a write fails, the
+    /// writes applied so far are reverted, thus either all writes are applied or none is.
+    pub(crate) fn apply(&self, shared: &'a SharedState, first_seq: u64) -> Result<(), Error> {
+        for (n, (seq, (family, op))) in (first_seq..).zip(self.ops.iter()).enumerate() {
+            if let Err(e) = family.apply(shared, seq, op) {
+                for (seq, (family, op)) in (first_seq..).zip(self.ops[..=n].iter()) {
+                    family.revert(seq, op);
+                }
+                return Err(e)
+            }
+        }
+        Ok(())
+    }
+
+    /// Looks up the value this batch leaves `key` with. Returns `None` if the batch does not
+    /// touch `key`, `Some(None)` if it deletes `key`. Merges are not taken into account.
+    pub(crate) fn lookup(&self, cf: &ColumnFamily<'a>, key: &[u8]) -> Option<Option<Vec<u8>>> {
+        for (family, op) in self.ops.iter().rev() {
+            if !std::ptr::eq(*family, cf) {
+                continue
+            }
+            match op {
+                BatchOp::Put(k, v) | BatchOp::PutWithTtl(k, v, _) if family.compare(k, key) == Ordering::Equal =>
+                    return Some(Some(v.clone())),
+                BatchOp::Delete(k) if family.compare(k, key) == Ordering::Equal =>
+                    return Some(None),
+                BatchOp::DeleteRange(start, end) if family.compare(start, key) != Ordering::Greater
+                    && family.compare(key, end) == Ordering::Less =>
+                    return Some(None),
+                _ => {}
+            }
+        }
+        None
+    }
+}
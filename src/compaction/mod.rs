@@ -0,0 +1,55 @@
+use crate::partition::memtable::Record;
+
+/// What a compaction filter does to a value.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum FilterDecision {
+    Keep,
+    /// Drops the value, older versions of the key stay hidden
+    Remove,
+    ChangeValue(Vec<u8>)
+}
+
+/// The compaction a filter gets invoked in.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct CompactionContext {
+    pub partition_id: u32,
+    /// Level the compacted tables get written into
+    pub output_level: usize,
+    /// Whether a memtable gets flushed, rather than tables compacted
+    pub is_flush: bool
+}
+
+/// Inspects each value during compaction, to drop or rewrite it without a separate scan. Memtable
+/// flushes run the filter only once `filter_flush` returns `true`. Merge operands bypass the
+/// filter and are written as they are.
+pub trait CompactionFilter: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn filter(&self, context: &CompactionContext, key: &[u8], value: &[u8]) -> FilterDecision;
+
+    /// Whether to filter memtable flushes as well, `false` by default.
+    fn filter_flush(&self) -> bool {
+        false
+    }
+}
+
+/// Runs a value record through `compaction_filter`. Removed values turn into deletions, so they
+/// keep shadowing older versions in deeper levels. Deletions and merge operands pass unchanged.
+pub(crate) fn filter_record(compaction_filter: &dyn CompactionFilter,
+                            context: &CompactionContext,
+                            key: &[u8],
+                            record: Record) -> Record {
+    match record {
+        Record::Value(value) => match compaction_filter.filter(context, key, &value) {
+            FilterDecision::Keep => Record::Value(value),
+            FilterDecision::Remove => Record::Deletion,
+            FilterDecision::ChangeValue(value) => Record::Value(value)
+        },
+        Record::Expiring(value, expire_at) => match compaction_filter.filter(context, key, &value) {
+            FilterDecision::Keep => Record::Expiring(value, expire_at),
+            FilterDecision::Remove => Record::Deletion,
+            FilterDecision::ChangeValue(value) => Record::Expiring(value, expire_at)
+        },
+        record => record
+    }
+}
@@ -0,0 +1,256 @@
+use std::cmp::Ordering;
+use std::sync::Arc;
+
+use crate::Comparator;
+use crate::encode::{decode_fixed32, decode_fixed64, encode_fixed32_ret};
+
+/// Orders keys bytewise, descending.
+pub struct ReverseBytewiseComparator();
+
+impl Comparator for ReverseBytewiseComparator {
+    fn name(&self) -> &str {
+        "pr65.reverse_bytewise"
+    }
+
+    fn compare(&self, lhs: &[u8], rhs: &[u8]) -> Ordering {
+        rhs.cmp(lhs)
+    }
+}
+
+/// Orders keys as big-endian unsigned integers of any width. Keys of the same value but different
+/// widths, e.g. `[0, 1]` and `[1]`, are ordered by width, so that distinct keys never compare equal.
+pub struct UnsignedIntComparator();
+
+impl Comparator for UnsignedIntComparator {
+    fn name(&self) -> &str {
+        "pr65.uint_be"
+    }
+
+    fn compare(&self, lhs: &[u8], rhs: &[u8]) -> Ordering {
+        let strip = |key: &[u8]| key.iter().position(|&b| b != 0).map_or(0, |n| key.len() - n);
+        let (lhs_digits, rhs_digits) = (strip(lhs), strip(rhs));
+        lhs_digits.cmp(&rhs_digits)
+            .then_with(|| lhs[lhs.len() - lhs_digits..].cmp(&rhs[rhs.len() - rhs_digits..]))
+            .then(lhs.len().cmp(&rhs.len()))
+    }
+}
+
+/// Orders keys as big-endian two's complement integers of any width. Keys of the same value but
+/// different widths are ordered by width.
+pub struct SignedIntComparator();
+
+impl Comparator for SignedIntComparator {
+    fn name(&self) -> &str {
+        "pr65.int_be"
+    }
+
+    fn compare(&self, lhs: &[u8], rhs: &[u8]) -> Ordering {
+        let width = lhs.len().max(rhs.len());
+        // Sign extended byte `n` of `width` bytes, with the sign bit flipped on the first byte
+        let byte = |key: &[u8], n: usize| {
+            let sign = if key.first().map_or(false, |&b| b & 0x80 != 0) { 0xff } else { 0 };
+            let b = if n + key.len() < width { sign } else { key[n + key.len() - width] };
+            if n == 0 { b ^ 0x80 } else { b }
+        };
+        (0..width).map(|n| byte(lhs, n).cmp(&byte(rhs, n)))
+            .find(|&ord| ord != Ordering::Equal)
+            .unwrap_or(Ordering::Equal)
+            .then(lhs.len().cmp(&rhs.len()))
+    }
+}
+
+/// Orders 8 byte keys as big-endian IEEE 754 doubles, in the total order of `f64::total_cmp`:
+/// negative NaNs first, then negative infinity through -0.0, 0.0 through infinity, and positive
+/// NaNs last. Keys of other sizes sort before all doubles, by size and then bytewise.
+pub struct FloatComparator();
+
+impl FloatComparator {
+    fn ordered_bits(key: &[u8]) -> u64 {
+        let bits = decode_fixed64(key);
+        if bits & (1 << 63) != 0 { !bits } else { bits | (1 << 63) }
+    }
+}
+
+impl Comparator for FloatComparator {
+    fn name(&self) -> &str {
+        "pr65.f64_be"
+    }
+
+    fn compare(&self, lhs: &[u8], rhs: &[u8]) -> Ordering {
+        match (lhs.len(), rhs.len()) {
+            (8, 8) => Self::ordered_bits(lhs).cmp(&Self::ordered_bits(rhs)),
+            (8, _) => Ordering::Greater,
+            (_, 8) => Ordering::Less,
+            (lhs_len, rhs_len) => lhs_len.cmp(&rhs_len).then_with(|| lhs.cmp(rhs))
+        }
+    }
+}
+
+/// Orders keys made of segments, each prefixed by its 4 byte big-endian length. Segment `n` is
+/// ordered by comparator `n`, segments beyond the last comparator bytewise, and a key which is a
+/// prefix of another sorts first. Bytes which do not make up a complete segment are ordered
+/// bytewise after all complete segments.
+pub struct CompositeComparator {
+    name: String,
+    segments: Vec<Arc<dyn Comparator>>
+}
+
+impl CompositeComparator {
+    pub fn new(segments: Vec<Arc<dyn Comparator>>) -> Self {
+        let names: Vec<&str> = segments.iter().map(|segment| segment.name()).collect();
+        Self { name: format!("pr65.composite({})", names.join(",")), segments }
+    }
+
+    /// Encodes `segments` into a key.
+    pub fn encode(segments: &[&[u8]]) -> Vec<u8> {
+        let mut ret = Vec::with_capacity(segments.iter().map(|segment| segment.len() + 4).sum());
+        for segment in segments {
+            ret.extend_from_slice(&encode_fixed32_ret(segment.len() as u32));
+            ret.extend_from_slice(segment);
+        }
+        ret
+    }
+
+    /// Splits the first complete segment off `key`.
+    fn split(key: &[u8]) -> Option<(&[u8], &[u8])> {
+        let len = decode_fixed32(key.get(0..4)?) as usize;
+        let segment = key.get(4..4 + len)?;
+        Some((segment, &key[4 + len..]))
+    }
+}
+
+impl Comparator for CompositeComparator {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn compare(&self, mut lhs: &[u8], mut rhs: &[u8]) -> Ordering {
+        for n in 0.. {
+            match (Self::split(lhs), Self::split(rhs)) {
+                (Some((lhs_segment, lhs_rest)), Some((rhs_segment, rhs_rest))) => {
+                    let ord = match self.segments.get(n) {
+                        Some(comparator) => comparator.compare(lhs_segment, rhs_segment),
+                        None => lhs_segment.cmp(rhs_segment)
+                    };
+                    if ord != Ordering::Equal {
+                        return ord
+                    }
+                    lhs = lhs_rest;
+                    rhs = rhs_rest;
+                },
+                (Some(_), None) => return if rhs.is_empty() { Ordering::Greater } else { Ordering::Less },
+                (None, Some(_)) => return if lhs.is_empty() { Ordering::Less } else { Ordering::Greater },
+                (None, None) => break
+            }
+        }
+        lhs.cmp(rhs)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cmp::Ordering;
+    use std::ops::Bound;
+    use std::sync::Arc;
+
+    use crate::Comparator;
+    use crate::comparator::{ReverseBytewiseComparator, UnsignedIntComparator, SignedIntComparator,
+                            FloatComparator, CompositeComparator};
+    use crate::partition::{InternalKey, UserKey};
+    use crate::partition::memtable::Record;
+    use crate::table::builder::ScTableBuilder;
+    use crate::table::cache::{ScTableCache, TableCacheManager};
+    use crate::table::sctable::in_bounds;
+
+    /// Checks that `keys`, given in ascending order, are ordered by `comparator`, and can be
+    /// looked up from a table built with it.
+    fn check_order(comparator: &'static dyn Comparator, keys: &[Vec<u8>]) {
+        for (i, lhs) in keys.iter().enumerate() {
+            for (j, rhs) in keys.iter().enumerate() {
+                assert_eq!(comparator.compare(lhs, rhs), i.cmp(&j), "{:?} vs {:?}", lhs, rhs);
+            }
+        }
+
+        let mut builder = ScTableBuilder::new();
+        for (i, key) in keys.iter().enumerate() {
+            builder.add_kv(i as u64 + 1, key, format!("Катюша {}", i).as_bytes());
+        }
+        let cache_manager = TableCacheManager::new(2);
+        let table = ScTableCache::from_raw(&builder.build(), cache_manager.acquire_quota()).unwrap();
+        for (i, key) in keys.iter().enumerate() {
+            let key = InternalKey::new(u64::MAX, UserKey::new_borrow(key, comparator));
+            assert_eq!(table.get(&key), Some((i as u64 + 1, Record::Value(format!("Катюша {}", i).into_bytes()))));
+        }
+
+        let mut reversed = ScTableBuilder::new();
+        for key in keys.iter().rev() {
+            reversed.add_kv(1, key, &[]);
+        }
+        let table = ScTableCache::from_raw(&reversed.build(), cache_manager.acquire_quota()).unwrap();
+        assert!(table.validate_order(comparator).is_err());
+
+        let (first, last) = (&keys[0][..], &keys[keys.len() - 1][..]);
+        for key in keys[1..keys.len() - 1].iter() {
+            assert!(in_bounds(comparator, key, Bound::Excluded(first), Bound::Excluded(last)));
+            assert!(!in_bounds(comparator, key, Bound::Unbounded, Bound::Excluded(first)));
+            assert!(!in_bounds(comparator, key, Bound::Excluded(last), Bound::Unbounded));
+        }
+        assert!(in_bounds(comparator, first, Bound::Included(first), Bound::Included(last)));
+        assert!(!in_bounds(comparator, first, Bound::Excluded(first), Bound::Included(last)));
+    }
+
+    #[test]
+    fn test_reverse_bytewise() {
+        let keys: Vec<Vec<u8>> = ["黑暗的中世纪", "歌声好像明媚的春光", "katyusha/1", "katyusha/0", "katyusha", ""].iter()
+            .map(|key| key.as_bytes().to_vec())
+            .collect();
+        check_order(&ReverseBytewiseComparator(), &keys);
+    }
+
+    #[test]
+    fn test_unsigned_int() {
+        let keys = vec![vec![], vec![0], vec![0, 0], vec![1], vec![0, 1], vec![0xff], vec![1, 0], vec![0, 1, 0],
+                        u32::MAX.to_be_bytes().to_vec(), u64::MAX.to_be_bytes().to_vec()];
+        check_order(&UnsignedIntComparator(), &keys);
+    }
+
+    #[test]
+    fn test_signed_int() {
+        let keys = vec![i64::MIN.to_be_bytes().to_vec(), i32::MIN.to_be_bytes().to_vec(), vec![0x80],
+                        (-2i64).to_be_bytes().to_vec(), vec![0xff], vec![0xff, 0xff], vec![], vec![0],
+                        1i32.to_be_bytes().to_vec(), vec![0x7f], 0x80i16.to_be_bytes().to_vec(),
+                        i64::MAX.to_be_bytes().to_vec()];
+        check_order(&SignedIntComparator(), &keys);
+    }
+
+    #[test]
+    fn test_float() {
+        let keys: Vec<Vec<u8>> = vec![vec![], vec![0xff], vec![0, 0], (-f64::NAN).to_be_bytes().to_vec()].into_iter()
+            .chain([f64::NEG_INFINITY, -1e300, -1.0, -f64::MIN_POSITIVE, -0.0, 0.0, f64::MIN_POSITIVE, 0.5,
+                    1.0, 1e300, f64::INFINITY, f64::NAN].iter().map(|f| f.to_be_bytes().to_vec()))
+            .collect();
+        check_order(&FloatComparator(), &keys);
+    }
+
+    #[test]
+    fn test_composite() {
+        let comparator = CompositeComparator::new(vec![Arc::new(ReverseBytewiseComparator()),
+                                                       Arc::new(UnsignedIntComparator())]);
+        assert_eq!(comparator.name(), "pr65.composite(pr65.reverse_bytewise,pr65.uint_be)");
+        let comparator: &'static CompositeComparator = Box::leak(Box::new(comparator));
+
+        let keys = vec![
+            CompositeComparator::encode(&[]),
+            CompositeComparator::encode(&["喀秋莎".as_bytes()]),
+            CompositeComparator::encode(&["喀秋莎".as_bytes(), &[2]]),
+            CompositeComparator::encode(&["喀秋莎".as_bytes(), &[1, 0]]),
+            CompositeComparator::encode(&["喀秋莎".as_bytes(), &[1, 0], "b".as_bytes()]),
+            CompositeComparator::encode(&["喀秋莎".as_bytes(), &[1, 0], "c".as_bytes()]),
+            CompositeComparator::encode(&["Катюша".as_bytes(), &[7]]),
+            CompositeComparator::encode(&["Катюша".as_bytes()]).into_iter().chain(vec![0, 0]).collect(),
+            CompositeComparator::encode(&["".as_bytes()]),
+        ];
+        check_order(comparator, &keys);
+        assert_eq!(comparator.compare(&keys[1], &keys[1]), Ordering::Equal);
+    }
+}
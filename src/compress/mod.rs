@@ -0,0 +1,219 @@
+use std::io::{Read, Write};
+
+use crc::crc32;
+use flate2::Compression as ZlibLevel;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+
+use crate::encode::{encode_fixed32_ret, decode_fixed32};
+use crate::error::Error;
+use crate::table::tablefmt::{TABLE_COMPRESSED_MAGIC, TABLE_COMPRESSED_TAIL_SIZE, TABLE_MAGIC_SIZE,
+                             TABLE_MAX_SIZE};
+
+/// A table codec. Codecs are chosen through `CompressionType`, and identified in table files by
+/// `id`, so only the built-in codecs can be used.
+pub(crate) trait Compression: Sync {
+    fn id(&self) -> u8;
+
+    fn compress(&self, raw: &[u8]) -> Vec<u8>;
+
+    fn decompress(&self, compressed: &[u8], raw_size: usize) -> Result<Vec<u8>, Error>;
+}
+
+/// Fast codec, suitable for upper levels which get rewritten often.
+pub(crate) struct SnappyCompression();
+
+/// Bound of the snappy expansion ratio: a 3 byte copy tag expands to at most 64 bytes.
+const SNAPPY_MAX_RATIO: usize = 32;
+
+impl Compression for SnappyCompression {
+    fn id(&self) -> u8 {
+        1
+    }
+
+    fn compress(&self, raw: &[u8]) -> Vec<u8> {
+        snap::raw::Encoder::new().compress_vec(raw).unwrap()
+    }
+
+    fn decompress(&self, compressed: &[u8], raw_size: usize) -> Result<Vec<u8>, Error> {
+        // the decoder allocates the decompressed size up front, so it must not be trusted blindly
+        if raw_size > compressed.len().saturating_mul(SNAPPY_MAX_RATIO) {
+            return Err(Error::sc_table_corrupt(
+                format!("snappy decompressed size {} impossible for {} bytes", raw_size, compressed.len()).into()))
+        }
+        match snap::raw::decompress_len(compressed) {
+            Ok(len) if len == raw_size => {},
+            _ => return Err(Error::sc_table_corrupt("incorrect snappy decompressed size".into()))
+        }
+        snap::raw::Decoder::new().decompress_vec(compressed)
+            .map_err(|e| Error::sc_table_corrupt(e.to_string().into()))
+    }
+}
+
+/// High ratio codec, suitable for the last levels which hold most of the data.
+pub(crate) struct ZlibCompression();
+
+impl Compression for ZlibCompression {
+    fn id(&self) -> u8 {
+        2
+    }
+
+    fn compress(&self, raw: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), ZlibLevel::best());
+        encoder.write_all(raw).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn decompress(&self, compressed: &[u8], raw_size: usize) -> Result<Vec<u8>, Error> {
+        // grows with the data actually decompressed, rather than the untrusted `raw_size`
+        let mut ret = Vec::new();
+        ZlibDecoder::new(compressed)
+            .take(raw_size as u64 + 1)
+            .read_to_end(&mut ret)
+            .map_err(|e| Error::sc_table_corrupt(e.to_string().into()))?;
+        if ret.len() != raw_size {
+            return Err(Error::sc_table_corrupt("incorrect zlib decompressed size".into()))
+        }
+        Ok(ret)
+    }
+}
+
+static SNAPPY: SnappyCompression = SnappyCompression();
+static ZLIB: ZlibCompression = ZlibCompression();
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CompressionType {
+    None,
+    Snappy,
+    Zlib
+}
+
+impl Default for CompressionType {
+    fn default() -> Self {
+        CompressionType::None
+    }
+}
+
+impl CompressionType {
+    pub(crate) fn codec(&self) -> Option<&'static dyn Compression> {
+        match self {
+            CompressionType::None => None,
+            CompressionType::Snappy => Some(&SNAPPY),
+            CompressionType::Zlib => Some(&ZLIB)
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        [CompressionType::Snappy, CompressionType::Zlib].iter()
+            .find(|compression| compression.codec().unwrap().id() == id)
+            .copied()
+    }
+}
+
+/// Wraps a complete table into a compressed table. The table is returned as is if it is not
+/// compressed by the codec, or the compressed table does not get smaller.
+pub(crate) fn compress_table(table: Vec<u8>, compression: CompressionType) -> Vec<u8> {
+    let codec = if let Some(codec) = compression.codec() {
+        codec
+    } else {
+        return table
+    };
+
+    let mut ret = codec.compress(&table);
+    if ret.len() + TABLE_COMPRESSED_TAIL_SIZE >= table.len() {
+        return table
+    }
+    let crc = crc32::checksum_ieee(&ret);
+    ret.extend_from_slice(&encode_fixed32_ret(table.len() as u32));
+    ret.extend_from_slice(&encode_fixed32_ret(crc));
+    ret.push(codec.id());
+    ret.extend_from_slice(TABLE_COMPRESSED_MAGIC);
+    ret
+}
+
+pub(crate) fn is_compressed_table(raw: &[u8]) -> bool {
+    raw.len() >= TABLE_COMPRESSED_TAIL_SIZE
+        && &raw[raw.len() - TABLE_MAGIC_SIZE..] == TABLE_COMPRESSED_MAGIC
+}
+
+/// Compression of a table as recorded in its tail.
+pub(crate) fn table_compression(raw: &[u8]) -> Result<CompressionType, Error> {
+    if is_compressed_table(raw) {
+        let id = raw[raw.len() - TABLE_MAGIC_SIZE - 1];
+        CompressionType::from_id(id)
+            .ok_or_else(|| Error::sc_table_corrupt(format!("unknown compression codec {}", id).into()))
+    } else {
+        Ok(CompressionType::None)
+    }
+}
+
+/// Unwraps a compressed table produced by `compress_table`.
+pub(crate) fn decompress_table(raw: &[u8]) -> Result<Vec<u8>, Error> {
+    debug_assert!(is_compressed_table(raw));
+    let tail = &raw[raw.len() - TABLE_COMPRESSED_TAIL_SIZE..];
+    let payload = &raw[..raw.len() - TABLE_COMPRESSED_TAIL_SIZE];
+    let raw_size = decode_fixed32(&tail[0..4]) as usize;
+    let crc = decode_fixed32(&tail[4..8]);
+    let id = tail[8];
+
+    if raw_size > TABLE_MAX_SIZE {
+        return Err(Error::sc_table_corrupt("too large to be a table file".into()))
+    }
+    if crc32::checksum_ieee(payload) != crc {
+        return Err(Error::sc_table_corrupt("incorrect compressed payload crc".into()))
+    }
+    let codec = CompressionType::from_id(id)
+        .ok_or_else(|| Error::sc_table_corrupt(format!("unknown compression codec {}", id).into()))?
+        .codec()
+        .unwrap();
+    codec.decompress(payload, raw_size)
+}
+
+#[cfg(test)]
+mod test {
+    use rand::{thread_rng, Rng};
+    use crate::compress::{CompressionType, compress_table, decompress_table, is_compressed_table};
+    use crate::encode::encode_fixed32;
+    use crate::table::tablefmt::{TABLE_COMPRESSED_TAIL_SIZE, TABLE_MAX_SIZE};
+
+    #[test]
+    fn test_compress_decompress() {
+        let table = "喀秋莎站在那俊俏的岸上".repeat(64).into_bytes();
+        for &compression in [CompressionType::Snappy, CompressionType::Zlib].iter() {
+            let compressed = compress_table(table.clone(), compression);
+            assert!(is_compressed_table(&compressed));
+            assert!(compressed.len() < table.len());
+            assert_eq!(decompress_table(&compressed).unwrap(), table);
+        }
+    }
+
+    #[test]
+    fn test_compress_fallback() {
+        let table: Vec<u8> = (0..256).map(|_| thread_rng().gen()).collect();
+        for &compression in [CompressionType::None, CompressionType::Snappy, CompressionType::Zlib].iter() {
+            assert_eq!(compress_table(table.clone(), compression), table);
+        }
+    }
+
+    #[test]
+    fn test_decompress_corrupt() {
+        let table = "Выходила на берег Катюша".repeat(64).into_bytes();
+        for &compression in [CompressionType::Snappy, CompressionType::Zlib].iter() {
+            let mut compressed = compress_table(table.clone(), compression);
+            compressed[0] ^= 0xFF;
+            assert!(decompress_table(&compressed).is_err());
+        }
+    }
+
+    #[test]
+    fn test_decompress_oversized() {
+        let table = "Выходила на берег Катюша".repeat(64).into_bytes();
+        for &compression in [CompressionType::Snappy, CompressionType::Zlib].iter() {
+            // the size is not covered by the payload crc
+            let mut compressed = compress_table(table.clone(), compression);
+            let size_off = compressed.len() - TABLE_COMPRESSED_TAIL_SIZE;
+            encode_fixed32(&mut compressed[size_off..size_off + 4], TABLE_MAX_SIZE as u32);
+            assert!(decompress_table(&compressed).unwrap_err().to_string().contains("size"));
+        }
+    }
+}
@@ -0,0 +1,372 @@
+use std::fmt::Write;
+use std::fs::read_to_string;
+
+use egconf::{TreeNode, Value};
+
+use crate::{Options, KeyEncoding, CompactionStyle, CompressionType};
+use crate::error::Error;
+use crate::io::IOManager;
+use crate::table::tablefmt::TABLE_DEFAULT_RESTART_INTERVAL;
+
+/// Size ratio in percent of `compaction_style = "universal"` unless configured
+const DEFAULT_UNIVERSAL_SIZE_RATIO: usize = 1;
+/// Size amplification in percent of `compaction_style = "universal"` unless configured
+const DEFAULT_UNIVERSAL_MAX_SIZE_AMPLIFICATION: usize = 200;
+
+impl Options {
+    /// Loads options from an egconf file, see `from_config_str`.
+    pub fn from_config_file(path: &str) -> Result<Self, Error> {
+        let config = read_to_string(path).map_err(|e| Error::io_error(e.to_string().into(), path.to_string()))?;
+        Self::from_config_str(&config)
+    }
+
+    /// Parses options from egconf lines of the form `knob = value`. Blank lines and lines starting
+    /// with `#` are skipped. Knobs missing from the configuration keep their defaults, unknown
+    /// knobs are rejected. Merge operators, compaction filters, clocks and comparators are not
+    /// configurable, and keep their defaults.
+    pub fn from_config_str(config: &str) -> Result<Self, Error> {
+        let mut options = Options::default();
+        let mut restart_interval = None;
+        let mut prefix_delta = None;
+        let mut universal = None;
+        let mut size_ratio = None;
+        let mut max_size_amplification = None;
+        let root = parse_config(config)?;
+        let knobs = match &root {
+            TreeNode::Branch { children, .. } => children,
+            TreeNode::Leaf { .. } => unreachable!()
+        };
+        for knob in knobs {
+            let (key, value) = match knob {
+                TreeNode::Leaf { key, value } => (key.as_str(), value),
+                TreeNode::Branch { key, .. } =>
+                    return Err(config_error(format!("unknown option section {}", key.as_deref().unwrap_or(""))))
+            };
+            match key {
+                "db_name" => options.db_name = expect_str(key, value)?.to_string(),
+                "cache_count" => options.cache_count = expect_usize(key, value)?,
+                "level0_size" => options.level0_size = expect_usize(key, value)?,
+                "level_base_size" => options.level_base_size = expect_usize(key, value)?,
+                "level_dynamic_size" => options.level_dynamic_size = expect_bool(key, value)?,
+                "size_factor" => options.size_factor = expect_usize(key, value)?,
+                "max_open_files" => options.max_open_files = expect_usize(key, value)?,
+                "table_size" => options.table_size = expect_usize(key, value)?,
+                "key_size_max" => options.key_size_max = expect_usize(key, value)?,
+                "value_size_max" => options.value_size_max = expect_usize(key, value)?,
+                "use_mmap" => options.use_mmap = expect_bool(key, value)?,
+                "key_encoding" => prefix_delta = Some(match expect_str(key, value)? {
+                    "plain" => false,
+                    "prefix_delta" => true,
+                    other => return Err(config_error(
+                        format!("key_encoding must be \"plain\" or \"prefix_delta\", got \"{}\"", other)))
+                }),
+                "restart_interval" => restart_interval = Some(expect_usize(key, value)?),
+                "compaction_style" => universal = Some(match expect_str(key, value)? {
+                    "leveled" => false,
+                    "universal" => true,
+                    other => return Err(config_error(
+                        format!("compaction_style must be \"leveled\" or \"universal\", got \"{}\"", other)))
+                }),
+                "universal_size_ratio" => size_ratio = Some(expect_usize(key, value)?),
+                "universal_max_size_amplification" => max_size_amplification = Some(expect_usize(key, value)?),
+                "compression_per_level" => options.compression_per_level = value.expect_list()
+                    .map_err(|e| config_error(format!("{}: {}", key, e)))?
+                    .iter()
+                    .map(|compression| parse_compression(expect_str(key, compression)?))
+                    .collect::<Result<_, _>>()?,
+                _ => return Err(config_error(format!("unknown option {}", key)))
+            }
+        }
+        options.key_encoding = match (prefix_delta, restart_interval) {
+            (Some(true), restart_interval) => KeyEncoding::PrefixDelta {
+                restart_interval: restart_interval.unwrap_or(TABLE_DEFAULT_RESTART_INTERVAL)
+            },
+            (_, Some(_)) =>
+                return Err(config_error("restart_interval requires key_encoding = \"prefix_delta\"".to_string())),
+            _ => KeyEncoding::Plain
+        };
+        options.compaction_style = match (universal, size_ratio, max_size_amplification) {
+            (Some(true), size_ratio, max_size_amplification) => CompactionStyle::Universal {
+                size_ratio: size_ratio.unwrap_or(DEFAULT_UNIVERSAL_SIZE_RATIO),
+                max_size_amplification: max_size_amplification.unwrap_or(DEFAULT_UNIVERSAL_MAX_SIZE_AMPLIFICATION)
+            },
+            (_, None, None) => CompactionStyle::Leveled,
+            _ => return Err(config_error(
+                "universal_size_ratio and universal_max_size_amplification require compaction_style = \"universal\""
+                    .to_string()))
+        };
+        options.validate().map_err(|e| config_error(e.to_string()))?;
+        Ok(options)
+    }
+
+    /// Formats the configurable options as egconf lines, which `from_config_str` parses back.
+    pub fn to_config_string(&self) -> String {
+        let mut ret = String::new();
+        let _ = writeln!(ret, "db_name = \"{}\"", escape(&self.db_name));
+        let _ = writeln!(ret, "cache_count = {}", self.cache_count);
+        let _ = writeln!(ret, "level0_size = {}", self.level0_size);
+        let _ = writeln!(ret, "level_base_size = {}", self.level_base_size);
+        let _ = writeln!(ret, "level_dynamic_size = {}", self.level_dynamic_size as u8);
+        let _ = writeln!(ret, "size_factor = {}", self.size_factor);
+        let _ = writeln!(ret, "max_open_files = {}", self.max_open_files);
+        let _ = writeln!(ret, "table_size = {}", self.table_size);
+        let _ = writeln!(ret, "key_size_max = {}", self.key_size_max);
+        let _ = writeln!(ret, "value_size_max = {}", self.value_size_max);
+        let _ = writeln!(ret, "use_mmap = {}", self.use_mmap as u8);
+        match self.key_encoding {
+            KeyEncoding::Plain => {
+                let _ = writeln!(ret, "key_encoding = \"plain\"");
+            },
+            KeyEncoding::PrefixDelta { restart_interval } => {
+                let _ = writeln!(ret, "key_encoding = \"prefix_delta\"");
+                let _ = writeln!(ret, "restart_interval = {}", restart_interval);
+            }
+        }
+        match self.compaction_style {
+            CompactionStyle::Leveled => {
+                let _ = writeln!(ret, "compaction_style = \"leveled\"");
+            },
+            CompactionStyle::Universal { size_ratio, max_size_amplification } => {
+                let _ = writeln!(ret, "compaction_style = \"universal\"");
+                let _ = writeln!(ret, "universal_size_ratio = {}", size_ratio);
+                let _ = writeln!(ret, "universal_max_size_amplification = {}", max_size_amplification);
+            }
+        }
+        let compressions: Vec<String> = self.compression_per_level.iter()
+            .map(|compression| format!("\"{}\"", compression_name(*compression)))
+            .collect();
+        let _ = writeln!(ret, "compression_per_level = [{}]", compressions.join(", "));
+        let _ = writeln!(ret, "# comparator = {}", self.comparator.name());
+        ret
+    }
+
+    /// Dumps the effective options into `{db_name}.options` next to the database files.
+    pub(crate) fn save_config(&self, io_manager: &IOManager) -> Result<(), Error> {
+        io_manager.acquire_quota()
+            .replace_file(format!("{}.options", self.db_name), self.to_config_string().as_bytes())
+    }
+}
+
+fn config_error(reason: String) -> Error {
+    Error::invalid_argument(format!("options config: {}", reason).into())
+}
+
+fn expect_usize(key: &str, value: &Value) -> Result<usize, Error> {
+    match value {
+        Value::Int(i) if *i >= 0 => Ok(*i as usize),
+        _ => Err(config_error(format!("{} must be a non-negative integer, got {}", key, value)))
+    }
+}
+
+fn expect_str<'v>(key: &str, value: &'v Value) -> Result<&'v str, Error> {
+    value.expect_str()
+        .map(|s| s.as_str())
+        .map_err(|_| config_error(format!("{} must be a string, got {}", key, value)))
+}
+
+fn expect_bool(key: &str, value: &Value) -> Result<bool, Error> {
+    match value {
+        Value::Int(0) => Ok(false),
+        Value::Int(1) => Ok(true),
+        Value::String(s) if s == "false" => Ok(false),
+        Value::String(s) if s == "true" => Ok(true),
+        _ => Err(config_error(format!("{} must be 0, 1, \"true\" or \"false\", got {}", key, value)))
+    }
+}
+
+fn parse_compression(name: &str) -> Result<CompressionType, Error> {
+    compression_by_name(name)
+        .ok_or_else(|| config_error(format!("unknown compression \"{}\", expected none, snappy or zlib", name)))
+}
+
+pub(crate) fn compression_by_name(name: &str) -> Option<CompressionType> {
+    match name {
+        "none" => Some(CompressionType::None),
+        "snappy" => Some(CompressionType::Snappy),
+        "zlib" => Some(CompressionType::Zlib),
+        _ => None
+    }
+}
+
+fn compression_name(compression: CompressionType) -> &'static str {
+    match compression {
+        CompressionType::None => "none",
+        CompressionType::Snappy => "snappy",
+        CompressionType::Zlib => "zlib"
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Parses egconf lines into a tree. The line parser of egconf is private to it, so this follows
+/// its grammar: dotted identifiers, integers, floats, strings with `\\`, `\"` and `\n` escapes,
+/// and lists thereof.
+fn parse_config(config: &str) -> Result<TreeNode, Error> {
+    let mut root = TreeNode::root_node();
+    for (n, line) in config.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue
+        }
+        let line_error = |reason: String| config_error(format!("line {}: {}", n + 1, reason));
+        let eq = line.find('=').ok_or_else(|| line_error("expected '='".to_string()))?;
+        let chain: Vec<String> = line[..eq].trim().split('.').map(|part| part.to_string()).collect();
+        if let Some(part) = chain.iter().find(|part| !is_identifier(part)) {
+            return Err(line_error(format!("invalid identifier \"{}\"", part)))
+        }
+        let mut parser = ValueParser { line: line[eq + 1..].trim().as_bytes(), cur: 0 };
+        let value = parser.parse_value().map_err(&line_error)?;
+        if parser.cur != parser.line.len() {
+            return Err(line_error("trailing characters after value".to_string()))
+        }
+        root.insert(&chain, value).map_err(|e| line_error(e.to_string()))?;
+    }
+    Ok(root)
+}
+
+fn is_identifier(part: &str) -> bool {
+    !part.is_empty() && part.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_?!$@".contains(&b))
+}
+
+struct ValueParser<'a> {
+    line: &'a [u8],
+    cur: usize
+}
+
+impl<'a> ValueParser<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.line.get(self.cur).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().map_or(false, |b| b.is_ascii_whitespace()) {
+            self.cur += 1;
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<Value, String> {
+        match self.peek() {
+            Some(b) if b.is_ascii_digit() => self.parse_number(),
+            Some(b'"') => self.parse_string(),
+            Some(b'[') => self.parse_list(),
+            Some(b) => Err(format!("unexpected character '{}'", b as char)),
+            None => Err("missing value".to_string())
+        }
+    }
+
+    fn parse_number(&mut self) -> Result<Value, String> {
+        let start = self.cur;
+        while self.peek().map_or(false, |b| b.is_ascii_digit() || b == b'.') {
+            self.cur += 1;
+        }
+        let number = std::str::from_utf8(&self.line[start..self.cur]).unwrap();
+        if number.contains('.') {
+            number.parse().map(Value::Float).map_err(|_| format!("invalid number {}", number))
+        } else {
+            number.parse().map(Value::Int).map_err(|_| format!("integer {} out of range", number))
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<Value, String> {
+        self.cur += 1;
+        let mut buffer = Vec::new();
+        loop {
+            match self.peek() {
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    buffer.push(match self.line.get(self.cur + 1) {
+                        Some(b'\\') => b'\\',
+                        Some(b'"') => b'"',
+                        Some(b'n') => b'\n',
+                        _ => return Err("invalid escape sequence in string".to_string())
+                    });
+                    self.cur += 2;
+                },
+                Some(b) => {
+                    buffer.push(b);
+                    self.cur += 1;
+                },
+                None => return Err("unclosed string".to_string())
+            }
+        }
+        self.cur += 1;
+        String::from_utf8(buffer).map(Value::String).map_err(|e| e.to_string())
+    }
+
+    fn parse_list(&mut self) -> Result<Value, String> {
+        self.cur += 1;
+        let mut values = Vec::new();
+        loop {
+            self.skip_whitespace();
+            if self.peek() == Some(b']') {
+                break
+            }
+            values.push(self.parse_value()?);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.cur += 1,
+                Some(b']') => break,
+                _ => return Err("missing ',' as value separator".to_string())
+            }
+        }
+        self.cur += 1;
+        Ok(Value::List(values))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Options, KeyEncoding, CompactionStyle, CompressionType};
+
+    #[test]
+    fn test_from_config_str() {
+        let options = Options::from_config_str(r#"
+            # Катюша
+            db_name = "喀秋莎"
+            cache_count = 8
+            table_size = 65536
+            value_size_max = 16384
+            use_mmap = "true"
+            key_encoding = "prefix_delta"
+            compaction_style = "universal"
+            universal_size_ratio = 10
+            compression_per_level = ["none", "snappy", "zlib"]
+        "#).unwrap();
+        let defaults = Options::default();
+        assert_eq!(options.db_name, "喀秋莎");
+        assert_eq!(options.cache_count, 8);
+        assert_eq!(options.table_size, 65536);
+        assert!(options.use_mmap);
+        assert_eq!(options.key_encoding, KeyEncoding::PrefixDelta { restart_interval: 16 });
+        assert_eq!(options.compaction_style,
+                   CompactionStyle::Universal { size_ratio: 10, max_size_amplification: 200 });
+        assert_eq!(options.compression_per_level,
+                   vec![CompressionType::None, CompressionType::Snappy, CompressionType::Zlib]);
+        assert_eq!(options.level0_size, defaults.level0_size);
+        assert_eq!(options.value_size_max, 16384);
+        assert_eq!(options.key_size_max, defaults.key_size_max);
+
+        let reloaded = Options::from_config_str(&options.to_config_string()).unwrap();
+        assert_eq!(reloaded.to_config_string(), options.to_config_string());
+        assert_eq!(reloaded.db_name, "喀秋莎");
+    }
+
+    #[test]
+    fn test_from_config_str_errors() {
+        let reason = |config: &str| Options::from_config_str(config).err().unwrap().to_string();
+        assert!(reason("cache_count = \"many\"").contains("cache_count must be a non-negative integer"));
+        assert!(reason("cache_count = 4\ncache_count = 8").contains("line 2"));
+        assert!(reason("katyusha = 1").contains("unknown option katyusha"));
+        assert!(reason("storage.katyusha = 1").contains("unknown option section storage"));
+        assert!(reason("table_size 4096").contains("line 1: expected '='"));
+        assert!(reason("db_name = \"Катюша").contains("unclosed string"));
+        assert!(reason("compression_per_level = [\"lz4\"]").contains("unknown compression \"lz4\""));
+        assert!(reason("restart_interval = 4").contains("requires key_encoding"));
+        assert!(reason("universal_size_ratio = 4").contains("require compaction_style"));
+        assert!(reason("use_mmap = 2").contains("use_mmap must be"));
+        assert!(reason("table_size = 99999999999999999999").contains("out of range"));
+        assert!(reason("size_factor = 1").contains("size_factor must be at least 2"));
+    }
+}
@@ -0,0 +1,271 @@
+use crate::error::Error;
+
+/// Escapes a 0x00 byte within an encoded byte string
+const BYTES_ESCAPE: u8 = 0xff;
+/// Terminates an encoded byte string, sorting it before all of its extensions
+const BYTES_TERMINATOR: u8 = 0x01;
+
+/// A value with an order preserving encoding: encodings compare bytewise (as by
+/// `DefaultComparator`) in the same order as the values. Encodings are self-delimiting and no
+/// encoding is a prefix of another, so tuples are encoded by concatenating their fields.
+///
+/// Integers are stored big-endian with the sign bit flipped, floats in the total order of
+/// `total_cmp`, byte strings with 0x00 escaped as `0x00 0xff` and terminated by `0x00 0x01`.
+pub trait MemComparable: Sized {
+    fn encode_to(&self, buf: &mut Vec<u8>);
+
+    /// Decodes a value off the front of `input`, advancing `input` past it.
+    fn decode_from(input: &mut &[u8]) -> Result<Self, Error>;
+}
+
+pub fn encode_key<T: MemComparable>(value: &T) -> Vec<u8> {
+    let mut ret = Vec::new();
+    value.encode_to(&mut ret);
+    ret
+}
+
+/// Decodes a key made of exactly one `T`.
+pub fn decode_key<T: MemComparable>(mut key: &[u8]) -> Result<T, Error> {
+    let value = T::decode_from(&mut key)?;
+    if !key.is_empty() {
+        return Err(Error::invalid_argument(format!("{} trailing bytes after decoded key", key.len()).into()))
+    }
+    Ok(value)
+}
+
+fn take<'a>(input: &mut &'a [u8], n: usize) -> Result<&'a [u8], Error> {
+    if input.len() < n {
+        return Err(Error::invalid_argument(
+            format!("key truncated, expected {} more bytes, got {}", n, input.len()).into()))
+    }
+    let (ret, rest) = input.split_at(n);
+    *input = rest;
+    Ok(ret)
+}
+
+macro_rules! impl_unsigned {
+    ($($t:ty),*) => {$(
+        impl MemComparable for $t {
+            fn encode_to(&self, buf: &mut Vec<u8>) {
+                buf.extend_from_slice(&self.to_be_bytes());
+            }
+
+            fn decode_from(input: &mut &[u8]) -> Result<Self, Error> {
+                let mut bytes = [0u8; std::mem::size_of::<$t>()];
+                bytes.copy_from_slice(take(input, std::mem::size_of::<$t>())?);
+                Ok(<$t>::from_be_bytes(bytes))
+            }
+        }
+    )*}
+}
+
+macro_rules! impl_signed {
+    ($($t:ty => $u:ty),*) => {$(
+        impl MemComparable for $t {
+            fn encode_to(&self, buf: &mut Vec<u8>) {
+                ((*self as $u) ^ (1 << (<$u>::BITS - 1))).encode_to(buf)
+            }
+
+            fn decode_from(input: &mut &[u8]) -> Result<Self, Error> {
+                Ok((<$u>::decode_from(input)? ^ (1 << (<$u>::BITS - 1))) as $t)
+            }
+        }
+    )*}
+}
+
+macro_rules! impl_float {
+    ($($t:ty => $u:ty),*) => {$(
+        impl MemComparable for $t {
+            fn encode_to(&self, buf: &mut Vec<u8>) {
+                let bits = self.to_bits();
+                let sign = 1 << (<$u>::BITS - 1);
+                (if bits & sign != 0 { !bits } else { bits | sign }).encode_to(buf)
+            }
+
+            fn decode_from(input: &mut &[u8]) -> Result<Self, Error> {
+                let bits = <$u>::decode_from(input)?;
+                let sign = 1 << (<$u>::BITS - 1);
+                Ok(<$t>::from_bits(if bits & sign != 0 { bits & !sign } else { !bits }))
+            }
+        }
+    )*}
+}
+
+impl_unsigned!(u8, u16, u32, u64, u128);
+impl_signed!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128);
+impl_float!(f32 => u32, f64 => u64);
+
+impl MemComparable for bool {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        buf.push(*self as u8)
+    }
+
+    fn decode_from(input: &mut &[u8]) -> Result<Self, Error> {
+        match take(input, 1)?[0] {
+            0 => Ok(false),
+            1 => Ok(true),
+            b => Err(Error::invalid_argument(format!("invalid boolean byte {:#04x}", b).into()))
+        }
+    }
+}
+
+pub fn encode_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    for &b in bytes {
+        buf.push(b);
+        if b == 0 {
+            buf.push(BYTES_ESCAPE);
+        }
+    }
+    buf.extend_from_slice(&[0, BYTES_TERMINATOR]);
+}
+
+pub fn decode_bytes(input: &mut &[u8]) -> Result<Vec<u8>, Error> {
+    let mut ret = Vec::new();
+    loop {
+        let n = input.iter().position(|&b| b == 0)
+            .ok_or_else(|| Error::invalid_argument("unterminated byte string in key".into()))?;
+        ret.extend_from_slice(take(input, n)?);
+        match take(input, 2)?[1] {
+            BYTES_ESCAPE => ret.push(0),
+            BYTES_TERMINATOR => return Ok(ret),
+            b => return Err(Error::invalid_argument(format!("invalid escape byte {:#04x} in key", b).into()))
+        }
+    }
+}
+
+impl MemComparable for Vec<u8> {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        encode_bytes(buf, self)
+    }
+
+    fn decode_from(input: &mut &[u8]) -> Result<Self, Error> {
+        decode_bytes(input)
+    }
+}
+
+impl MemComparable for String {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        encode_bytes(buf, self.as_bytes())
+    }
+
+    fn decode_from(input: &mut &[u8]) -> Result<Self, Error> {
+        String::from_utf8(decode_bytes(input)?).map_err(|e| Error::invalid_argument(e.to_string().into()))
+    }
+}
+
+macro_rules! impl_tuple {
+    ($($name:ident),*) => {
+        impl<$($name: MemComparable),*> MemComparable for ($($name,)*) {
+            #[allow(non_snake_case)]
+            fn encode_to(&self, buf: &mut Vec<u8>) {
+                let ($($name,)*) = self;
+                $($name.encode_to(buf);)*
+            }
+
+            fn decode_from(input: &mut &[u8]) -> Result<Self, Error> {
+                Ok(($($name::decode_from(input)?,)*))
+            }
+        }
+    }
+}
+
+impl_tuple!(A);
+impl_tuple!(A, B);
+impl_tuple!(A, B, C);
+impl_tuple!(A, B, C, D);
+impl_tuple!(A, B, C, D, E);
+
+/// Encodes the wrapped value such that it sorts in descending order, by inverting all bits of
+/// its encoding.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Descending<T>(pub T);
+
+impl<T: MemComparable> MemComparable for Descending<T> {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        let start = buf.len();
+        self.0.encode_to(buf);
+        for b in buf[start..].iter_mut() {
+            *b = !*b;
+        }
+    }
+
+    fn decode_from(input: &mut &[u8]) -> Result<Self, Error> {
+        let inverted: Vec<u8> = input.iter().map(|b| !b).collect();
+        let mut rest = inverted.as_slice();
+        let value = T::decode_from(&mut rest)?;
+        *input = &input[inverted.len() - rest.len()..];
+        Ok(Descending(value))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fmt::Debug;
+    use rand::{thread_rng, Rng};
+    use crate::encode::memcmp::{MemComparable, Descending, encode_key, decode_key};
+
+    /// Checks that `values`, given in ascending order, encode in the same order and round trip.
+    fn check_order<T: MemComparable + Debug + PartialEq>(values: &[T]) {
+        let encoded: Vec<Vec<u8>> = values.iter().map(encode_key).collect();
+        for (i, pair) in encoded.windows(2).enumerate() {
+            assert!(pair[0] < pair[1], "{:?} should encode before {:?}", values[i], values[i + 1]);
+        }
+        for (value, key) in values.iter().zip(encoded.iter()) {
+            assert_eq!(&decode_key::<T>(key).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn test_integers() {
+        check_order(&[0u8, 1, 0x7f, 0x80, 0xff]);
+        check_order(&[i64::MIN, -0x100, -2, -1, 0, 1, 0xff, i64::MAX]);
+        check_order(&[i8::MIN, -1, 0, i8::MAX]);
+        check_order(&[i128::MIN, -1, 0, i128::MAX]);
+
+        let mut values: Vec<i32> = (0..1024).map(|_| thread_rng().gen()).collect();
+        values.sort();
+        values.dedup();
+        check_order(&values);
+    }
+
+    #[test]
+    fn test_floats() {
+        check_order(&[f64::NEG_INFINITY, -1e300, -1.0, -f64::MIN_POSITIVE, -0.0, 0.0, f64::MIN_POSITIVE, 0.5,
+                      1.0, 1e300, f64::INFINITY]);
+        check_order(&[f32::NEG_INFINITY, -1.5, 0.0, 1.5, f32::INFINITY]);
+        assert!(decode_key::<f64>(&encode_key(&f64::NAN)).unwrap().is_nan());
+    }
+
+    #[test]
+    fn test_bytes() {
+        check_order(&[vec![], vec![0], vec![0, 0], vec![0, 1], vec![0, 0xff], vec![1], vec![1, 0], vec![0xff]]);
+        check_order(&["".to_string(), "Катюша".to_string(), "喀秋莎".to_string(), "喀秋莎站在那俊俏的岸上".to_string()]);
+        check_order(&[false, true]);
+
+        assert!(decode_key::<Vec<u8>>(&[1, 2, 0]).is_err());
+        assert!(decode_key::<Vec<u8>>(&[1, 2, 0, 2]).is_err());
+        assert!(decode_key::<Vec<u8>>(&[1, 0, 1, 0]).is_err());
+        assert!(decode_key::<bool>(&[2]).is_err());
+        assert!(decode_key::<u32>(&[0, 0, 1]).is_err());
+    }
+
+    #[test]
+    fn test_tuples() {
+        check_order(&[
+            ("Катюша".to_string(), 2u32, false),
+            ("Катюша".to_string(), 10u32, false),
+            ("Катюша".to_string(), 10u32, true),
+            ("Катюша\0".to_string(), 0u32, false),
+            ("Катюшаa".to_string(), 0u32, false),
+        ]);
+        check_order(&[
+            (vec![1u8], Descending("歌声".to_string()), -1i64),
+            (vec![1u8], Descending("".to_string()), -1i64),
+            (vec![1u8, 0], Descending("歌声好像明媚的春光".to_string()), 0i64),
+            (vec![1u8, 0], Descending("歌声".to_string()), 0i64),
+        ]);
+        check_order(&[Descending(10u64), Descending(2u64), Descending(0u64)]);
+        check_order(&[Descending((1i32, vec![1u8])), Descending((1, vec![0])), Descending((1, vec![])),
+                      Descending((0, vec![0xff]))]);
+    }
+}
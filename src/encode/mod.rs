@@ -1,5 +1,8 @@
 use std::u32;
 
+pub mod memcmp;
+pub mod varint;
+
 pub fn decode_fixed32(s: &[u8]) -> u32 {
     debug_assert_eq!(s.len(), 4);
     unsafe {
@@ -0,0 +1,167 @@
+//! LEB128 varints, and zigzag mapping of signed integers to be stored as varints.
+
+/// Maximum encoded size of a varint32
+pub const VARINT32_MAX_SIZE: usize = 5;
+/// Maximum encoded size of a varint64
+pub const VARINT64_MAX_SIZE: usize = 10;
+
+/// Encodes `num` as LEB128 into the front of `s`, returns the count of bytes written. `s` must
+/// hold at least `varint32_len(num)` bytes.
+pub fn encode_varint32(s: &mut [u8], num: u32) -> usize {
+    encode_varint64(s, num as u64)
+}
+
+pub fn encode_varint64(s: &mut [u8], mut num: u64) -> usize {
+    let mut len = 0;
+    while num >= 0x80 {
+        s[len] = (num & 0x7F) as u8 | 0x80;
+        num >>= 7;
+        len += 1;
+    }
+    s[len] = num as u8;
+    len + 1
+}
+
+pub fn put_varint32(dest: &mut Vec<u8>, num: u32) {
+    put_varint64(dest, num as u64)
+}
+
+pub fn put_varint64(dest: &mut Vec<u8>, num: u64) {
+    let mut buffer = [0u8; VARINT64_MAX_SIZE];
+    let len = encode_varint64(&mut buffer, num);
+    dest.extend_from_slice(&buffer[..len])
+}
+
+/// Decodes a varint off the front of `s`, returns it along with its encoded size. Returns `None`
+/// if the varint is truncated, or does not fit into 32 bits.
+pub fn decode_varint32(s: &[u8]) -> Option<(u32, usize)> {
+    decode_varint(s, 32).map(|(num, len)| (num as u32, len))
+}
+
+/// Like `decode_varint32`, for 64 bit varints.
+pub fn decode_varint64(s: &[u8]) -> Option<(u64, usize)> {
+    decode_varint(s, 64)
+}
+
+fn decode_varint(s: &[u8], bits: u32) -> Option<(u64, usize)> {
+    let mut ret = 0u64;
+    for (n, &byte) in s.iter().enumerate() {
+        let shift = 7 * n as u32;
+        let payload = (byte & 0x7F) as u64;
+        if shift >= bits || (bits - shift < 7 && payload >> (bits - shift) != 0) {
+            return None
+        }
+        ret |= payload << shift;
+        if byte & 0x80 == 0 {
+            return Some((ret, n + 1))
+        }
+    }
+    None
+}
+
+pub fn varint32_len(num: u32) -> usize {
+    varint64_len(num as u64)
+}
+
+pub fn varint64_len(mut num: u64) -> usize {
+    let mut len = 1;
+    while num >= 0x80 {
+        num >>= 7;
+        len += 1;
+    }
+    len
+}
+
+/// Maps signed integers to unsigned ones, such that numbers of small magnitude get small varints.
+pub fn encode_zigzag32(num: i32) -> u32 {
+    ((num << 1) ^ (num >> 31)) as u32
+}
+
+pub fn decode_zigzag32(num: u32) -> i32 {
+    ((num >> 1) as i32) ^ -((num & 1) as i32)
+}
+
+pub fn encode_zigzag64(num: i64) -> u64 {
+    ((num << 1) ^ (num >> 63)) as u64
+}
+
+pub fn decode_zigzag64(num: u64) -> i64 {
+    ((num >> 1) as i64) ^ -((num & 1) as i64)
+}
+
+#[cfg(test)]
+mod test {
+    use rand::{thread_rng, Rng};
+    use crate::encode::varint::{encode_varint32, decode_varint32, put_varint32, varint32_len,
+                                encode_varint64, decode_varint64, put_varint64, varint64_len,
+                                encode_zigzag32, decode_zigzag32, encode_zigzag64, decode_zigzag64,
+                                VARINT32_MAX_SIZE, VARINT64_MAX_SIZE};
+
+    #[test]
+    fn test_varint32() {
+        let mut numbers = vec![0, 1, 0x7F, 0x80, 0x3FFF, 0x4000, u32::MAX];
+        numbers.extend((1..1024).map(|_| thread_rng().gen::<u32>() >> thread_rng().gen_range(0, 32)));
+        let mut stream = Vec::new();
+        for &number in numbers.iter() {
+            let mut buffer = [0u8; VARINT32_MAX_SIZE];
+            let len = encode_varint32(&mut buffer, number);
+            assert_eq!(len, varint32_len(number));
+            assert_eq!(decode_varint32(&buffer), Some((number, len)));
+            assert_eq!(decode_varint32(&buffer[..len - 1]), None);
+            put_varint32(&mut stream, number);
+        }
+        let mut pos = 0;
+        for &number in numbers.iter() {
+            let (decoded, len) = decode_varint32(&stream[pos..]).unwrap();
+            assert_eq!(decoded, number);
+            pos += len;
+        }
+        assert_eq!(pos, stream.len());
+
+        assert_eq!(decode_varint32(&[0xFF, 0xFF, 0xFF, 0xFF, 0x0F]), Some((u32::MAX, 5)));
+        assert_eq!(decode_varint32(&[0xFF, 0xFF, 0xFF, 0xFF, 0x1F]), None);
+        assert_eq!(decode_varint32(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x00]), None);
+    }
+
+    #[test]
+    fn test_varint64() {
+        let mut numbers = vec![0, 1, 0x7F, 0x80, u32::MAX as u64 + 1, u64::MAX];
+        numbers.extend((1..1024).map(|_| thread_rng().gen::<u64>() >> thread_rng().gen_range(0, 64)));
+        let mut stream = Vec::new();
+        for &number in numbers.iter() {
+            let mut buffer = [0u8; VARINT64_MAX_SIZE];
+            let len = encode_varint64(&mut buffer, number);
+            assert_eq!(len, varint64_len(number));
+            assert_eq!(decode_varint64(&buffer), Some((number, len)));
+            put_varint64(&mut stream, number);
+        }
+        let mut pos = 0;
+        for &number in numbers.iter() {
+            let (decoded, len) = decode_varint64(&stream[pos..]).unwrap();
+            assert_eq!(decoded, number);
+            pos += len;
+        }
+        assert_eq!(pos, stream.len());
+
+        let mut overflow = [0xFFu8; VARINT64_MAX_SIZE];
+        overflow[VARINT64_MAX_SIZE - 1] = 0x01;
+        assert_eq!(decode_varint64(&overflow), Some((u64::MAX, VARINT64_MAX_SIZE)));
+        overflow[VARINT64_MAX_SIZE - 1] = 0x02;
+        assert_eq!(decode_varint64(&overflow), None);
+        assert_eq!(decode_varint64(&[0x80; 11]), None);
+    }
+
+    #[test]
+    fn test_zigzag() {
+        for &(number, encoded) in [(0, 0), (-1, 1), (1, 2), (-2, 3), (i32::MAX, u32::MAX - 1), (i32::MIN, u32::MAX)].iter() {
+            assert_eq!(encode_zigzag32(number), encoded);
+            assert_eq!(decode_zigzag32(encoded), number);
+        }
+        for _ in 1..1024 {
+            let number = thread_rng().gen::<i64>();
+            assert_eq!(decode_zigzag64(encode_zigzag64(number)), number);
+        }
+        assert_eq!(encode_zigzag64(i64::MIN), u64::MAX);
+        assert_eq!(encode_zigzag64(-64), 127);
+    }
+}
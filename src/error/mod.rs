@@ -4,7 +4,16 @@ use std::fmt::{Debug, Display, Formatter};
 pub enum Error {
     ScTableCorrupt { reason: ErrorStr },
     ScSplitCorrupt { reason: ErrorStr },
+    UnsupportedVersion { version: u32 },
     IOError { reason: ErrorStr, file: String },
+    InvalidArgument { reason: ErrorStr },
+    Conflict { reason: ErrorStr },
+    LockTimeout { reason: ErrorStr },
+    Deadlock { reason: ErrorStr },
+    MergeFailed { reason: ErrorStr },
+    ManifestCorrupt { reason: ErrorStr },
+    ComparatorMismatch { reason: ErrorStr },
+    RecordTooLarge { reason: ErrorStr },
     RequiresExplode
 }
 
@@ -35,18 +44,77 @@ impl Error {
         Error::ScSplitCorrupt { reason }
     }
 
+    pub(crate) fn unsupported_version(version: u32) -> Self {
+        Error::UnsupportedVersion { version }
+    }
+
     pub(crate) fn io_error(reason: ErrorStr, file: String) -> Self {
         Error::IOError { reason, file }
     }
 
+    pub(crate) fn invalid_argument(reason: ErrorStr) -> Self {
+        Error::InvalidArgument { reason }
+    }
+
+    pub(crate) fn conflict(reason: ErrorStr) -> Self {
+        Error::Conflict { reason }
+    }
+
+    pub(crate) fn lock_timeout(reason: ErrorStr) -> Self {
+        Error::LockTimeout { reason }
+    }
+
+    pub(crate) fn deadlock(reason: ErrorStr) -> Self {
+        Error::Deadlock { reason }
+    }
+
+    pub(crate) fn merge_failed(reason: ErrorStr) -> Self {
+        Error::MergeFailed { reason }
+    }
+
+    pub(crate) fn manifest_corrupt(reason: ErrorStr) -> Self {
+        Error::ManifestCorrupt { reason }
+    }
+
+    pub(crate) fn comparator_mismatch(reason: ErrorStr) -> Self {
+        Error::ComparatorMismatch { reason }
+    }
+
+    pub(crate) fn record_too_large(reason: ErrorStr) -> Self {
+        Error::RecordTooLarge { reason }
+    }
+
     pub(crate) fn requires_explode() -> Self {
         Error::RequiresExplode
     }
 }
 
+impl Display for ErrorStr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            ErrorStr::Owned(s) => write!(f, "{}", s),
+            ErrorStr::StaticBorrow(s) => write!(f, "{}", s)
+        }
+    }
+}
+
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
-        unimplemented!()
+        match self {
+            Error::ScTableCorrupt { reason } => write!(f, "corrupt table: {}", reason),
+            Error::ScSplitCorrupt { reason } => write!(f, "corrupt split: {}", reason),
+            Error::UnsupportedVersion { version } => write!(f, "unsupported table format version {}", version),
+            Error::IOError { reason, file } => write!(f, "I/O error on {}: {}", file, reason),
+            Error::InvalidArgument { reason } => write!(f, "invalid argument: {}", reason),
+            Error::Conflict { reason } => write!(f, "transaction conflict: {}", reason),
+            Error::LockTimeout { reason } => write!(f, "lock timeout: {}", reason),
+            Error::Deadlock { reason } => write!(f, "deadlock: {}", reason),
+            Error::MergeFailed { reason } => write!(f, "merge failed: {}", reason),
+            Error::ManifestCorrupt { reason } => write!(f, "corrupt manifest: {}", reason),
+            Error::ComparatorMismatch { reason } => write!(f, "comparator mismatch: {}", reason),
+            Error::RecordTooLarge { reason } => write!(f, "record too large: {}", reason),
+            Error::RequiresExplode => write!(f, "partition requires explode")
+        }
     }
 }
 
@@ -0,0 +1,430 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, VecDeque};
+use std::ops::Bound;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{self, AtomicU32, AtomicU64};
+
+use crate::{Options, MutableOptions};
+use crate::batch::BatchOp;
+use crate::error::Error;
+use crate::io::IOManager;
+use crate::table::cache::TableCacheManager;
+use crate::table::tablefmt::TABLE_MAX_SEQ;
+use crate::partition::{ArcPartition, Partition, InternalKey, UserKey};
+use crate::partition::memtable::{Record, RangeTombstone};
+
+/// Snapshots held by readers. Flushes keep every version of a key some live snapshot may read.
+pub(crate) struct SnapshotList {
+    /// Seq of the latest write visible to readers
+    visible_seq: AtomicU64,
+    /// Count of live snapshots by seq
+    live: Mutex<BTreeMap<u64, usize>>
+}
+
+/// A snapshot registered in a `SnapshotList` until dropped.
+pub(crate) struct Snapshot<'a> {
+    snapshots: &'a SnapshotList,
+    seq: u64
+}
+
+impl SnapshotList {
+    pub(crate) fn new() -> Self {
+        Self { visible_seq: AtomicU64::new(0), live: Mutex::new(BTreeMap::new()) }
+    }
+
+    /// Makes writes up to `seq` visible to snapshots acquired afterwards.
+    pub(crate) fn publish(&self, seq: u64) {
+        self.visible_seq.store(seq, atomic::Ordering::SeqCst);
+    }
+
+    pub(crate) fn acquire(&self) -> Snapshot<'_> {
+        let mut live = self.live.lock().unwrap();
+        let seq = self.visible_seq.load(atomic::Ordering::SeqCst);
+        *live.entry(seq).or_insert(0) += 1;
+        Snapshot { snapshots: self, seq }
+    }
+
+    /// Seq of the oldest snapshot which is live, or may still be acquired.
+    pub(crate) fn oldest(&self) -> u64 {
+        let live = self.live.lock().unwrap();
+        let visible_seq = self.visible_seq.load(atomic::Ordering::SeqCst);
+        live.keys().next().map_or(visible_seq, |&seq| seq.min(visible_seq))
+    }
+
+    fn release(&self, seq: u64) {
+        let mut live = self.live.lock().unwrap();
+        let count = live.get_mut(&seq).unwrap();
+        *count -= 1;
+        if *count == 0 {
+            live.remove(&seq);
+        }
+    }
+}
+
+impl<'a> Snapshot<'a> {
+    pub(crate) fn seq(&self) -> u64 {
+        self.seq
+    }
+}
+
+impl<'a> Drop for Snapshot<'a> {
+    fn drop(&mut self) {
+        self.snapshots.release(self.seq)
+    }
+}
+
+/// State shared by all column families of a database.
+pub(crate) struct SharedState {
+    seq: AtomicU64,
+    pub(crate) snapshots: SnapshotList,
+    write_lock: Mutex<()>,
+    next_partition_id: AtomicU32,
+    pub(crate) cache_manager: TableCacheManager,
+    pub(crate) io_manager: IOManager
+}
+
+impl SharedState {
+    pub(crate) fn new(options: &Options) -> Self {
+        Self {
+            seq: AtomicU64::new(0),
+            snapshots: SnapshotList::new(),
+            write_lock: Mutex::new(()),
+            next_partition_id: AtomicU32::new(0),
+            cache_manager: TableCacheManager::new(options.cache_count),
+            io_manager: IOManager::new(options.max_open_files, options.use_mmap)
+        }
+    }
+
+    /// Runs `f` with the first of `count` consecutive seqs reserved for it. Writes are
+    /// serialized, and readers see none of them until all of them got applied, nor ever if `f`
+    /// fails. `count` may be 0, for checks which need to exclude concurrent writes. Seqs beyond
+    /// `TABLE_MAX_SEQ` are never handed out, as tables use the bits above it as flags.
+    pub(crate) fn commit<F>(&self, count: usize, f: F) -> Result<(), Error>
+        where F: FnOnce(u64) -> Result<(), Error> {
+        let _guard = self.write_lock.lock().unwrap();
+        let first_seq = self.seq.load(atomic::Ordering::SeqCst) + 1;
+        let last_seq = (first_seq - 1).saturating_add(count as u64);
+        if last_seq > TABLE_MAX_SEQ {
+            return Err(Error::invalid_argument(
+                format!("{} writes exceed the largest seq {}", count, TABLE_MAX_SEQ).into()))
+        }
+        self.seq.store(last_seq, atomic::Ordering::SeqCst);
+        let ret = f(first_seq);
+        if ret.is_ok() {
+            self.snapshots.publish(last_seq);
+        }
+        ret
+    }
+}
+
+/// Owns the column families of a database besides the default one. Column families are never
+/// removed and never move out of their `Arc`, so references handed out stay valid as long as the
+/// set.
+pub(crate) struct ColumnFamilySet<'a> {
+    families: Vec<Arc<ColumnFamily<'a>>>
+}
+
+impl<'a> ColumnFamilySet<'a> {
+    pub(crate) fn new() -> Self {
+        Self { families: Vec::new() }
+    }
+
+    pub(crate) fn insert(&mut self, family: ColumnFamily<'a>) -> &'a ColumnFamily<'a> {
+        self.families.push(Arc::new(family));
+        Self::extend(self.families.last().unwrap())
+    }
+
+    pub(crate) fn find(&self, name: &str) -> Option<&'a ColumnFamily<'a>> {
+        self.families.iter()
+            .find(|family| family.name() == name)
+            .map(Self::extend)
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &ColumnFamily<'a>> {
+        self.families.iter().map(|family| &**family)
+    }
+
+    /// Lends `family` for as long as the database holding the set is borrowed.
+    fn extend(family: &Arc<ColumnFamily<'a>>) -> &'a ColumnFamily<'a> {
+        unsafe { &*Arc::as_ptr(family) }
+    }
+}
+
+/// Seq, start and end of a range dropped from a column family.
+type DroppedRange = (u64, Vec<u8>, Vec<u8>);
+
+/// A named keyspace with its own partitions, options and comparator. `cache_count`,
+/// `max_open_files` and `use_mmap` are database wide, and taken from the options the database
+/// was opened with.
+pub struct ColumnFamily<'a> {
+    name: String,
+    options: Options,
+    /// Current values of the options which can change while the database is open
+    mutable_options: RwLock<MutableOptions>,
+    /// Partitions sorted by key range, created on first write
+    partitions: RwLock<VecDeque<ArcPartition<'a>>>,
+    /// Seqs and ranges of `drop_range` calls, whose partitions may be gone along with their writes
+    dropped_ranges: RwLock<Vec<DroppedRange>>
+}
+
+impl<'a> ColumnFamily<'a> {
+    pub(crate) fn new(name: impl ToString, options: Options) -> Self {
+        Self {
+            name: name.to_string(),
+            mutable_options: RwLock::new(MutableOptions::from(&options)),
+            options,
+            partitions: RwLock::new(VecDeque::new()),
+            dropped_ranges: RwLock::new(Vec::new())
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The options this column family was created with, see `mutable_options` for the current
+    /// values of options changed by `ScottDB::set_options`.
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    pub fn mutable_options(&self) -> MutableOptions {
+        self.mutable_options.read().unwrap().clone()
+    }
+
+    /// Applies `changes` if valid, after dumping the resulting options through `dump_io_manager`
+    /// if given.
+    pub(crate) fn set_options(&self,
+                              changes: &[(&str, &str)],
+                              dump_io_manager: Option<&IOManager>) -> Result<(), Error> {
+        let mut mutable_options = self.mutable_options.write().unwrap();
+        let changed = mutable_options.with_changes(changes)?;
+        changed.validate()?;
+        if let Some(io_manager) = dump_io_manager {
+            let mut options = self.options.clone();
+            changed.apply_to(&mut options);
+            options.save_config(io_manager)?;
+        }
+        *mutable_options = changed;
+        Ok(())
+    }
+
+    pub(crate) fn get(&self, shared: &SharedState, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let snapshot = shared.snapshots.acquire();
+        self.get_at(key, snapshot.seq())
+    }
+
+    pub(crate) fn get_at(&self, key: &[u8], snapshot_seq: u64) -> Result<Option<Vec<u8>>, Error> {
+        let partitions = self.partitions.read().unwrap();
+        match self.find_partition(&partitions, key) {
+            Some(partition) => partition.get(key, snapshot_seq),
+            None => Ok(None)
+        }
+    }
+
+    pub(crate) fn scan(&self,
+                       shared: &SharedState,
+                       lower: Bound<&[u8]>,
+                       upper: Bound<&[u8]>) -> Result<Vec<(Vec<u8>, Vec<u8>)>, Error> {
+        let snapshot = shared.snapshots.acquire();
+        let partitions = self.partitions.read().unwrap();
+        let mut kv_pairs = Vec::new();
+        for partition in partitions.iter() {
+            kv_pairs.extend(partition.scan(lower, upper, snapshot.seq())?);
+        }
+        Ok(kv_pairs)
+    }
+
+    /// Deletes all keys in `[start, end)` at `seq`, unlinking partitions and tables lying entirely
+    /// within the range right away. The drop must have been recorded in the manifest, if any.
+    pub(crate) fn drop_range(&self, seq: u64, start: &[u8], end: &[u8]) -> Result<(), Error> {
+        self.dropped_ranges.write().unwrap().push((seq, start.to_vec(), end.to_vec()));
+        let dropped_partitions = {
+            let mut partitions = self.partitions.write().unwrap();
+            let (dropped, kept): (VecDeque<_>, VecDeque<_>) = partitions.drain(..)
+                .partition(|partition| partition.bounds()
+                    .map_or(false, |(lower, upper)| self.compare(start, &lower) != Ordering::Greater
+                        && self.compare(&upper, end) == Ordering::Less));
+            *partitions = kept;
+            dropped
+        };
+        for partition in dropped_partitions {
+            partition.drop_all_tables()?;
+        }
+
+        self.delete_range(seq, start, end)?;
+        let partitions = self.partitions.read().unwrap();
+        for (i, partition) in partitions.iter().enumerate() {
+            if self.partition_overlaps(&partitions, i, start, end) {
+                partition.drop_tables(start, end)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn delete_range(&self, seq: u64, start: &[u8], end: &[u8]) -> Result<(), Error> {
+        let partitions = self.partitions.read().unwrap();
+        for (i, partition) in partitions.iter().enumerate() {
+            if self.partition_overlaps(&partitions, i, start, end) {
+                partition.write_range_deletion(
+                    RangeTombstone::new(seq,
+                                        UserKey::new_owned(start.to_vec(), &*self.options.comparator),
+                                        UserKey::new_owned(end.to_vec(), &*self.options.comparator)))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&'a self, shared: &'a SharedState, seq: u64, key: &[u8], record: Record) -> Result<(), Error> {
+        let partition = self.route(shared, key);
+        partition.write(InternalKey::new(seq, UserKey::new_owned(key.to_vec(), &*self.options.comparator)), record)
+    }
+
+    /// Finds the partition `key` belongs to, creating the first partition if there is none.
+    fn route(&'a self, shared: &'a SharedState, key: &[u8]) -> ArcPartition<'a> {
+        if let Some(partition) = self.find_partition(&self.partitions.read().unwrap(), key) {
+            return partition.clone()
+        }
+        let mut partitions = self.partitions.write().unwrap();
+        if partitions.is_empty() {
+            let partition_id = shared.next_partition_id.fetch_add(1, atomic::Ordering::SeqCst);
+            partitions.push_back(ArcPartition::new(
+                Partition::new(&self.options, &self.mutable_options, partition_id, &shared.seq, &shared.snapshots,
+                               &shared.cache_manager, &shared.io_manager)));
+        }
+        self.find_partition(&partitions, key).unwrap().clone()
+    }
+
+    /// A partition owns keys above the upper bound of its predecessor, up to its own upper bound.
+    /// The last partition owns all keys above.
+    fn find_partition<'p>(&self,
+                          partitions: &'p VecDeque<ArcPartition<'a>>,
+                          key: &[u8]) -> Option<&'p ArcPartition<'a>> {
+        partitions.iter()
+            .find(|partition| partition.bounds()
+                .map_or(false, |(_, upper)| self.compare(key, &upper) != Ordering::Greater))
+            .or(partitions.back())
+    }
+
+    fn partition_overlaps(&self,
+                          partitions: &VecDeque<ArcPartition<'a>>,
+                          n: usize,
+                          start: &[u8],
+                          end: &[u8]) -> bool {
+        let above_start = n + 1 == partitions.len() || partitions[n].bounds()
+            .map_or(false, |(_, upper)| self.compare(start, &upper) != Ordering::Greater);
+        let below_end = n == 0 || partitions[n - 1].bounds()
+            .map_or(true, |(_, upper)| self.compare(&upper, end) == Ordering::Less);
+        above_start && below_end
+    }
+
+    pub(crate) fn partition_count(&self) -> usize {
+        self.partitions.read().unwrap().len()
+    }
+
+    pub(crate) fn compare(&self, lhs: &[u8], rhs: &[u8]) -> Ordering {
+        self.options.comparator.compare(lhs, rhs)
+    }
+
+    pub(crate) fn validate(&self, op: &BatchOp) -> Result<(), Error> {
+        match op {
+            BatchOp::Put(key, value) | BatchOp::Merge(key, value) | BatchOp::PutWithTtl(key, value, _) => {
+                self.check_key_size(key)?;
+                self.check_value_size(value)?;
+            },
+            BatchOp::Delete(key) => self.check_key_size(key)?,
+            BatchOp::DeleteRange(start, end) => {
+                self.check_key_size(start)?;
+                self.check_key_size(end)?;
+            }
+        }
+        match op {
+            BatchOp::DeleteRange(start, end) if self.compare(start, end) != Ordering::Less =>
+                Err(Error::invalid_argument("range deletion start must be less than end".into())),
+            BatchOp::Merge(_, _) if self.options.merge_operator.is_none() =>
+                Err(Error::invalid_argument(format!("column family {} has no merge operator", self.name).into())),
+            _ => Ok(())
+        }
+    }
+
+    fn check_key_size(&self, key: &[u8]) -> Result<(), Error> {
+        let key_size_max = self.mutable_options.read().unwrap().key_size_max;
+        if key.len() > key_size_max {
+            return Err(Error::record_too_large(
+                format!("key of {} bytes exceeds key_size_max {} of column family {}",
+                        key.len(), key_size_max, self.name).into()))
+        }
+        Ok(())
+    }
+
+    fn check_value_size(&self, value: &[u8]) -> Result<(), Error> {
+        let value_size_max = self.mutable_options.read().unwrap().value_size_max;
+        if value.len() > value_size_max {
+            return Err(Error::record_too_large(
+                format!("value of {} bytes exceeds value_size_max {} of column family {}",
+                        value.len(), value_size_max, self.name).into()))
+        }
+        Ok(())
+    }
+
+    /// Seq of the latest write touching `key`, including dropped ranges, or 0 if there is none.
+    pub(crate) fn latest_seq(&self, key: &[u8]) -> Result<u64, Error> {
+        let dropped_seq = self.dropped_ranges.read().unwrap().iter()
+            .filter(|(_, start, end)| self.compare(start, key) != Ordering::Greater
+                && self.compare(key, end) == Ordering::Less)
+            .map(|(seq, _, _)| *seq)
+            .max()
+            .unwrap_or(0);
+        let partitions = self.partitions.read().unwrap();
+        let seq = match self.find_partition(&partitions, key) {
+            Some(partition) => partition.latest_seq(key)?,
+            None => 0
+        };
+        Ok(seq.max(dropped_seq))
+    }
+
+    pub(crate) fn apply(&'a self, shared: &'a SharedState, seq: u64, op: &BatchOp) -> Result<(), Error> {
+        match op {
+            BatchOp::Put(key, value) => self.write(shared, seq, key, Record::Value(value.clone())),
+            BatchOp::Delete(key) => self.write(shared, seq, key, Record::Deletion),
+            BatchOp::DeleteRange(start, end) => self.delete_range(seq, start, end),
+            BatchOp::Merge(key, operand) => self.write(shared, seq, key, Record::Merge(operand.clone())),
+            BatchOp::PutWithTtl(key, value, ttl) => {
+                let expire_at = self.options.clock.now().saturating_add(ttl.as_millis() as u64);
+                self.write(shared, seq, key, Record::Expiring(value.clone(), expire_at))
+            }
+        }
+    }
+
+    /// Removes what `apply` wrote of `op` at `seq`.
+    pub(crate) fn revert(&self, seq: u64, op: &BatchOp) {
+        let partitions = self.partitions.read().unwrap();
+        match op {
+            BatchOp::Put(key, _) | BatchOp::Delete(key) | BatchOp::Merge(key, _) | BatchOp::PutWithTtl(key, _, _) =>
+                if let Some(partition) = self.find_partition(&partitions, key) {
+                    partition.revert_write(seq, key);
+                },
+            BatchOp::DeleteRange(_, _) => for partition in partitions.iter() {
+                partition.revert_range_deletion(seq);
+            }
+        }
+    }
+
+    /// Flushes the memtables of all partitions, writes must be excluded meanwhile.
+    pub(crate) fn flush(&self) -> Result<(), Error> {
+        for partition in self.partitions.read().unwrap().iter() {
+            partition.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Rewrites all tables of older format versions into the current version, returns the count
+    /// of rewritten tables.
+    pub(crate) fn upgrade_tables(&self) -> Result<usize, Error> {
+        let mut upgraded_count = 0;
+        for partition in self.partitions.read().unwrap().iter() {
+            upgraded_count += partition.upgrade_tables()?;
+        }
+        Ok(upgraded_count)
+    }
+}
@@ -1,17 +1,39 @@
 use std::sync::atomic::AtomicUsize;
-use std::fs::File;
+use std::fs::{File, rename, remove_file};
 use std::io::{Read, Write};
+use std::ptr::NonNull;
 use std_semaphore::Semaphore;
+use memmap::Mmap;
 
 use crate::error;
 
 pub(crate) struct IOManager {
     open_files: AtomicUsize,
-    sem: Semaphore
+    sem: Semaphore,
+    use_mmap: bool
 }
 
 pub(crate) struct FileQuota<'a>(&'a IOManager);
 
+/// A memory mapped file, which keeps the file quota it was mapped with until dropped.
+/// Warning: make sure all `MappedFile`s are dropped before the `IOManager` drops.
+pub(crate) struct MappedFile {
+    mmap: Mmap,
+    io_manager: NonNull<IOManager>
+}
+
+impl MappedFile {
+    pub(crate) fn data(&self) -> &[u8] {
+        &self.mmap
+    }
+}
+
+impl Drop for MappedFile {
+    fn drop(&mut self) {
+        unsafe { self.io_manager.as_ref().on_quota_released() }
+    }
+}
+
 impl<'a> FileQuota<'a> {
     pub(crate) fn read_file(self, file_name: String) -> Result<Vec<u8>, error::Error> {
         self.read_file_impl(&file_name).or_else(
@@ -31,6 +53,37 @@ impl<'a> FileQuota<'a> {
         )
     }
 
+    pub(crate) fn map_file(self, file_name: String) -> Result<MappedFile, error::Error> {
+        let mmap = Self::map_file_impl(&file_name).or_else(
+            |e| {
+                Err(error::Error::io_error(e.to_string().into(),
+                                           file_name))
+            }
+        )?;
+        let io_manager = NonNull::from(self.0);
+        std::mem::forget(self);
+        Ok(MappedFile { mmap, io_manager })
+    }
+
+    /// Replaces the content of a file atomically, by writing a temporary file and renaming it.
+    pub(crate) fn replace_file(self, file_name: String, data: &[u8]) -> Result<(), error::Error> {
+        self.replace_file_impl(&file_name, data).or_else(
+            |e| {
+                Err(error::Error::io_error(e.to_string().into(),
+                                           file_name))
+            }
+        )
+    }
+
+    pub(crate) fn remove_file(self, file_name: String) -> Result<(), error::Error> {
+        remove_file(&file_name).or_else(
+            |e| {
+                Err(error::Error::io_error(e.to_string().into(),
+                                           file_name))
+            }
+        )
+    }
+
     fn read_file_impl(self, file_name: &String) -> Result<Vec<u8>, std::io::Error> {
         let mut v = Vec::new();
         File::with_options()
@@ -41,6 +94,14 @@ impl<'a> FileQuota<'a> {
         Ok(v)
     }
 
+    fn map_file_impl(file_name: &String) -> Result<Mmap, std::io::Error> {
+        let file = File::with_options()
+            .read(true)
+            .write(false)
+            .open(file_name)?;
+        unsafe { Mmap::map(&file) }
+    }
+
     fn write_file_impl(self, file_name: &String, data: &[u8]) -> Result<(), std::io::Error> {
         File::with_options()
             .write(true)
@@ -50,6 +111,18 @@ impl<'a> FileQuota<'a> {
             .write_all(data)?;
         Ok(())
     }
+
+    fn replace_file_impl(self, file_name: &String, data: &[u8]) -> Result<(), std::io::Error> {
+        let temp_file_name = format!("{}.tmp", file_name);
+        let mut file = File::with_options()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temp_file_name)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        rename(temp_file_name, file_name)
+    }
 }
 
 impl<'a> Drop for FileQuota<'a> {
@@ -60,8 +133,16 @@ impl<'a> Drop for FileQuota<'a> {
 }
 
 impl IOManager {
-    pub fn new(max_open_files: usize) -> Self {
-        Self { open_files: AtomicUsize::new(0), sem: Semaphore::new(max_open_files as isize) }
+    pub fn new(max_open_files: usize, use_mmap: bool) -> Self {
+        Self {
+            open_files: AtomicUsize::new(0),
+            sem: Semaphore::new(max_open_files as isize),
+            use_mmap
+        }
+    }
+
+    pub fn use_mmap(&self) -> bool {
+        self.use_mmap
     }
 
     pub fn acquire_quota(&self) -> FileQuota {
@@ -3,39 +3,135 @@
 #![feature(map_first_last)]
 
 use std::cmp::Ordering;
-use std::marker::PhantomData;
-use std::collections::VecDeque;
-use std::sync::atomic::AtomicU64;
+use std::sync::{Arc, Mutex, RwLock};
+use std::ops::Bound;
+use std::time::Duration;
+use std::vec;
 
 mod encode;
 mod error;
 mod table;
 mod partition;
 mod io;
+mod compress;
+mod family;
+mod batch;
+mod transaction;
+mod merge;
+mod compaction;
+mod clock;
+mod manifest;
+mod comparator;
+mod typed;
+mod config;
+mod options;
 
 pub use table::tablefmt;
+pub use encode::memcmp;
+pub use encode::varint;
+pub use table::upgrade::upgrade_table_file;
+pub use error::Error;
+pub use compress::CompressionType;
+pub use family::ColumnFamily;
+pub use batch::WriteBatch;
+pub use transaction::{OptimisticTransaction, TransactionDB, TransactionOptions, PessimisticTransaction};
+pub use merge::MergeOperator;
+pub use compaction::{CompactionFilter, CompactionContext, FilterDecision};
+pub use clock::{Clock, SystemClock, ManualClock};
+pub use comparator::{ReverseBytewiseComparator, UnsignedIntComparator, SignedIntComparator, FloatComparator,
+                     CompositeComparator};
+pub use typed::{TypedDb, TypedIterator, KeyCodec, ValueCodec};
+pub use options::{OptionsBuilder, MutableOptions};
 
-pub trait Comparator {
-    fn compare(lhs: &[u8], rhs: &[u8]) -> Ordering;
+pub const DEFAULT_COLUMN_FAMILY: &str = "default";
+
+/// Orders user keys. Comparators are shared as trait objects, so they may carry configuration.
+/// `name` gets recorded in the manifest, a database can only be reopened with a comparator of the
+/// same name.
+pub trait Comparator: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn compare(&self, lhs: &[u8], rhs: &[u8]) -> Ordering;
 }
 
+/// Orders keys bytewise.
 pub struct DefaultComparator();
 
 impl Comparator for DefaultComparator {
-    fn compare(lhs: &[u8], rhs: &[u8]) -> Ordering {
+    fn name(&self) -> &str {
+        "pr65.bytewise"
+    }
+
+    fn compare(&self, lhs: &[u8], rhs: &[u8]) -> Ordering {
         lhs.cmp(rhs)
     }
 }
 
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum KeyEncoding {
+    Plain,
+    PrefixDelta { restart_interval: usize }
+}
+
+impl Default for KeyEncoding {
+    fn default() -> Self {
+        KeyEncoding::Plain
+    }
+}
+
+/// How a partition compacts its tables.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CompactionStyle {
+    /// Keeps the levels below level 0 sorted runs of growing target sizes, for low read and
+    /// space amplification
+    Leveled,
+    /// Merges sorted runs of similar size, for low write amplification. Runs are merged once
+    /// the size of a run is within `size_ratio` percent of the newer runs merged with it, or all
+    /// runs are merged once the newer runs total `max_size_amplification` percent of the oldest.
+    Universal { size_ratio: usize, max_size_amplification: usize }
+}
+
+impl Default for CompactionStyle {
+    fn default() -> Self {
+        CompactionStyle::Leveled
+    }
+}
+
+#[derive(Clone)]
 pub struct Options {
     pub db_name: String,
     pub cache_count: usize,
+    /// Count of level 0 tables which triggers compacting them
     pub level0_size: usize,
+    /// Target size of level 1 in bytes, each deeper level targets `size_factor` times the size
+    /// of the one above
+    pub level_base_size: usize,
+    /// Derive level target sizes backwards from the size of the bottom level instead, which
+    /// keeps the levels above it empty until its data grows
+    pub level_dynamic_size: bool,
     pub size_factor: usize,
     pub max_open_files: usize,
     pub table_size: usize,
     pub key_size_max: usize,
     pub value_size_max: usize,
+    pub key_encoding: KeyEncoding,
+    /// Leveled or universal compaction, can be chosen for each column family. With universal
+    /// compaction `level0_size` is the count of sorted runs which triggers compaction.
+    pub compaction_style: CompactionStyle,
+    /// Compression of each level, the last one applies to all deeper levels
+    pub compression_per_level: Vec<CompressionType>,
+    /// Memory map table files instead of reading them. A cached table keeps its mapping, thus
+    /// consumes one of `max_open_files` until evicted from cache. Requires `max_open_files` above
+    /// `cache_count`, so that mappings never take up all of the file quota.
+    pub use_mmap: bool,
+    /// Combines values written by `ScottDB::merge`, merges are rejected without one
+    pub merge_operator: Option<Arc<dyn MergeOperator>>,
+    /// Drops or rewrites values during compaction, see `CompactionFilter`
+    pub compaction_filter: Option<Arc<dyn CompactionFilter>>,
+    /// Time source for keys put with a ttl
+    pub clock: Arc<dyn Clock>,
+    /// Orders keys, must be the same each time a database or column family is opened
+    pub comparator: Arc<dyn Comparator>,
 }
 
 impl Options {
@@ -51,52 +147,537 @@ impl Options {
             db_name: db_name.to_string(),
             cache_count,
             level0_size,
+            level_base_size: level0_size.saturating_mul(size_factor).saturating_mul(table_size),
+            level_dynamic_size: false,
             size_factor,
             max_open_files,
             table_size,
             key_size_max,
             value_size_max,
+            key_encoding: KeyEncoding::default(),
+            compaction_style: CompactionStyle::default(),
+            compression_per_level: Vec::new(),
+            use_mmap: false,
+            merge_operator: None,
+            compaction_filter: None,
+            clock: Arc::new(SystemClock()),
+            comparator: Arc::new(DefaultComparator()),
         }
     }
+}
 
-    fn level_size(&self, level: usize) -> usize {
-        self.level0_size * self.size_factor.pow(level as u32)
+impl Default for Options {
+    fn default() -> Self {
+        Self::new("pr65", 64, 4, 10, 256, 4 << 20, 4096, 1 << 20)
     }
 }
 
-use crate::io::IOManager;
-use crate::table::cache::TableCacheManager;
-use crate::partition::ArcPartition;
+use crate::family::{SharedState, ColumnFamilySet};
+use crate::manifest::{Manifest, DroppedRange};
 
-pub struct ScottDB<'a, Comp: 'static + Comparator> {
-    phantom: PhantomData<Comp>,
-
-    options: Options,
-    seq: AtomicU64,
-    partitions: VecDeque<ArcPartition<'a, Comp>>,
-    cache_manager: TableCacheManager,
-    io_manager: IOManager,
+pub struct ScottDB<'a> {
+    default_family: ColumnFamily<'a>,
+    families: RwLock<ColumnFamilySet<'a>>,
+    shared: SharedState,
+    manifest: Option<Mutex<Manifest>>
 }
 
-impl<'a, Comp: 'static + Comparator> ScottDB<'a, Comp> {
-    pub fn new(options: Options) -> Self {
-        let cache_count = options.cache_count;
-        let max_open_files = options.max_open_files;
-        Self {
-            phantom: PhantomData,
-            options,
-            seq: AtomicU64::new(0),
-            partitions: VecDeque::new(),
-            cache_manager: TableCacheManager::new(cache_count),
-            io_manager: IOManager::new(max_open_files),
+impl<'a> ScottDB<'a> {
+    /// Creates a database without a manifest, comparators are not checked nor recorded.
+    pub fn new(options: Options) -> Result<Self, Error> {
+        options.validate()?;
+        Ok(Self {
+            shared: SharedState::new(&options),
+            default_family: ColumnFamily::new(DEFAULT_COLUMN_FAMILY, options),
+            families: RwLock::new(ColumnFamilySet::new()),
+            manifest: None
+        })
+    }
+
+    /// Opens the database named by the options, recording the comparator of each column family
+    /// in its manifest. Fails with `Error::ComparatorMismatch` if a column family was created
+    /// with another comparator. The effective options are dumped into `{db_name}.options`.
+    pub fn open(options: Options) -> Result<Self, Error> {
+        options.validate()?;
+        let shared = SharedState::new(&options);
+        let mut manifest = Manifest::load(&shared.io_manager, &options.db_name)?;
+        manifest.check_comparator(&shared.io_manager, DEFAULT_COLUMN_FAMILY, &*options.comparator)?;
+        options.save_config(&shared.io_manager)?;
+        Ok(Self {
+            shared,
+            default_family: ColumnFamily::new(DEFAULT_COLUMN_FAMILY, options),
+            families: RwLock::new(ColumnFamilySet::new()),
+            manifest: Some(Mutex::new(manifest))
+        })
+    }
+
+    pub fn default_column_family(&self) -> &ColumnFamily<'a> {
+        &self.default_family
+    }
+
+    pub fn create_column_family(&'a self, name: &str, options: Options) -> Result<&'a ColumnFamily<'a>, Error> {
+        options.validate()?;
+        let mut families = self.families.write().unwrap();
+        if name == DEFAULT_COLUMN_FAMILY || families.iter().any(|family| family.name() == name) {
+            return Err(Error::invalid_argument(format!("column family {} already exists", name).into()))
+        }
+        if let Some(manifest) = &self.manifest {
+            manifest.lock().unwrap().check_comparator(&self.shared.io_manager, name, &*options.comparator)?;
+        }
+        Ok(families.insert(ColumnFamily::new(name, options)))
+    }
+
+    /// Looks up a column family by name.
+    pub fn column_family(&'a self, name: &str) -> Option<&'a ColumnFamily<'a>> {
+        self.families.read().unwrap().find(name)
+    }
+
+    /// Changes options of the default column family while the database is open, see
+    /// `set_options_cf`.
+    pub fn set_options(&self, changes: &[(&str, &str)]) -> Result<(), Error> {
+        self.set_options_cf(&self.default_family, changes)
+    }
+
+    /// Changes options of `cf` given as `(option, value)` pairs, such as `("table_size", "8388608")`.
+    /// Either all changes apply or none does. Only options in `MutableOptions` can be changed,
+    /// comparator, name and database wide options are rejected. Changes to the default column
+    /// family of an opened database are dumped into `{db_name}.options` as well.
+    pub fn set_options_cf(&self, cf: &ColumnFamily<'a>, changes: &[(&str, &str)]) -> Result<(), Error> {
+        let dump_io_manager = match &self.manifest {
+            Some(_) if std::ptr::eq(cf, &self.default_family) => Some(&self.shared.io_manager),
+            _ => None
+        };
+        cf.set_options(changes, dump_io_manager)
+    }
+
+    /// Begins a transaction which reads from a snapshot, and detects conflicting writes on commit.
+    pub fn begin_optimistic(&'a self) -> OptimisticTransaction<'a> {
+        OptimisticTransaction::new(self)
+    }
+
+    pub fn put(&'a self, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        self.put_cf(&self.default_family, key, value)
+    }
+
+    /// Puts a value which reads as absent once `ttl` elapsed. Flushes and compactions turn it into
+    /// a deletion once expired, which compaction into the bottom level removes.
+    pub fn put_with_ttl(&'a self, key: &[u8], value: &[u8], ttl: Duration) -> Result<(), Error> {
+        self.put_with_ttl_cf(&self.default_family, key, value, ttl)
+    }
+
+    pub fn delete(&'a self, key: &[u8]) -> Result<(), Error> {
+        self.delete_cf(&self.default_family, key)
+    }
+
+    /// Deletes all keys in `[start, end)`.
+    pub fn delete_range(&'a self, start: &[u8], end: &[u8]) -> Result<(), Error> {
+        self.delete_range_cf(&self.default_family, start, end)
+    }
+
+    /// Deletes all keys in `[start, end)` like `delete_range`, and unlinks partitions and tables
+    /// lying entirely within the range right away. The drop gets recorded in the manifest first.
+    pub fn drop_range(&'a self, start: &[u8], end: &[u8]) -> Result<(), Error> {
+        self.drop_range_cf(&self.default_family, start, end)
+    }
+
+    /// Merges `operand` into the value of `key` with the merge operator of the options.
+    pub fn merge(&'a self, key: &[u8], operand: &[u8]) -> Result<(), Error> {
+        self.merge_cf(&self.default_family, key, operand)
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        self.default_family.get(&self.shared, key)
+    }
+
+    /// Iterates over all key value pairs within the given bounds in key order.
+    pub fn scan(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Result<ScottIterator, Error> {
+        self.scan_cf(&self.default_family, lower, upper)
+    }
+
+    pub fn put_cf(&'a self, cf: &'a ColumnFamily<'a>, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        let mut batch = WriteBatch::new();
+        batch.put(cf, key, value);
+        self.write(batch)
+    }
+
+    pub fn put_with_ttl_cf(&'a self,
+                           cf: &'a ColumnFamily<'a>,
+                           key: &[u8],
+                           value: &[u8],
+                           ttl: Duration) -> Result<(), Error> {
+        let mut batch = WriteBatch::new();
+        batch.put_with_ttl(cf, key, value, ttl);
+        self.write(batch)
+    }
+
+    pub fn delete_cf(&'a self, cf: &'a ColumnFamily<'a>, key: &[u8]) -> Result<(), Error> {
+        let mut batch = WriteBatch::new();
+        batch.delete(cf, key);
+        self.write(batch)
+    }
+
+    pub fn delete_range_cf(&'a self, cf: &'a ColumnFamily<'a>, start: &[u8], end: &[u8]) -> Result<(), Error> {
+        let mut batch = WriteBatch::new();
+        batch.delete_range(cf, start, end);
+        self.write(batch)
+    }
+
+    pub fn merge_cf(&'a self, cf: &'a ColumnFamily<'a>, key: &[u8], operand: &[u8]) -> Result<(), Error> {
+        let mut batch = WriteBatch::new();
+        batch.merge(cf, key, operand);
+        self.write(batch)
+    }
+
+    pub fn drop_range_cf(&'a self, cf: &'a ColumnFamily<'a>, start: &[u8], end: &[u8]) -> Result<(), Error> {
+        if cf.compare(start, end) != Ordering::Less {
+            return Err(Error::invalid_argument("dropped range start must be less than end".into()))
+        }
+        self.shared.commit(1, |seq| {
+            if let Some(manifest) = &self.manifest {
+                let dropped_range = DroppedRange { family: cf.name().to_string(), seq, start: start.to_vec(),
+                                                   end: end.to_vec() };
+                manifest.lock().unwrap().record_drop(&self.shared.io_manager, dropped_range)?;
+            }
+            cf.drop_range(seq, start, end)
+        })
+    }
+
+    pub fn get_cf(&self, cf: &ColumnFamily<'a>, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        cf.get(&self.shared, key)
+    }
+
+    pub fn scan_cf(&self,
+                   cf: &ColumnFamily<'a>,
+                   lower: Bound<&[u8]>,
+                   upper: Bound<&[u8]>) -> Result<ScottIterator, Error> {
+        Ok(ScottIterator { inner: cf.scan(&self.shared, lower, upper)?.into_iter() })
+    }
+
+    /// Applies all writes of `batch`, readers see either none or all of them.
+    pub fn write(&'a self, batch: WriteBatch<'a>) -> Result<(), Error> {
+        if batch.is_empty() {
+            return Ok(())
         }
+        batch.validate()?;
+        self.shared.commit(batch.len(), |first_seq| batch.apply(&self.shared, first_seq))
+    }
+
+    /// Flushes the memtables of the default column family, see `flush_cf`.
+    pub fn flush(&self) -> Result<(), Error> {
+        self.flush_cf(&self.default_family)
+    }
+
+    /// Flushes the memtables of `cf` into tables of level 0, and runs the compactions that
+    /// triggers. Writes wait until the flush completes.
+    pub fn flush_cf(&self, cf: &ColumnFamily<'a>) -> Result<(), Error> {
+        self.shared.commit(0, |_| cf.flush())
+    }
+
+    /// Rewrites tables written by older versions of Pr65 into the current table format, returns the
+    /// count of rewritten tables. The call blocks until all tables are rewritten, but holds no lock
+    /// while rewriting, so other threads can keep using the database meanwhile.
+    pub fn upgrade_tables(&self) -> Result<usize, Error> {
+        let mut upgraded_count = self.default_family.upgrade_tables()?;
+        for family in self.families.read().unwrap().iter() {
+            upgraded_count += family.upgrade_tables()?;
+        }
+        Ok(upgraded_count)
+    }
+}
+
+pub struct ScottIterator {
+    inner: vec::IntoIter<(Vec<u8>, Vec<u8>)>
+}
+
+impl Iterator for ScottIterator {
+    type Item = (Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next()
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::ops::Bound;
+    use std::convert::TryInto;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use crate::{ScottDB, Options, WriteBatch, MergeOperator, ManualClock, Error, ReverseBytewiseComparator,
+                CompactionStyle};
+    use crate::table::sctable::ScTableFile;
+    use crate::table::tablefmt::TABLE_MAX_SEQ;
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn test_delete_range() {
+        let db = ScottDB::new(Options::new("test_delete_range", 4, 4, 10, 16, 4096, 64, 256)).unwrap();
+        for i in 0..16 {
+            db.put(format!("katyusha/{:02}", i).as_bytes(), "Выходила на берег Катюша".as_bytes()).unwrap();
+        }
+        db.delete("katyusha/00".as_bytes()).unwrap();
+        db.delete_range("katyusha/04".as_bytes(), "katyusha/12".as_bytes()).unwrap();
+        db.put("katyusha/08".as_bytes(), "歌声好像明媚的春光".as_bytes()).unwrap();
+        assert!(db.delete_range("katyusha/12".as_bytes(), "katyusha/04".as_bytes()).is_err());
+
+        assert_eq!(db.get("katyusha/00".as_bytes()).unwrap(), None);
+        assert_eq!(db.get("katyusha/03".as_bytes()).unwrap().unwrap(), "Выходила на берег Катюша".as_bytes());
+        assert_eq!(db.get("katyusha/04".as_bytes()).unwrap(), None);
+        assert_eq!(db.get("katyusha/08".as_bytes()).unwrap().unwrap(), "歌声好像明媚的春光".as_bytes());
+        assert_eq!(db.get("katyusha/11".as_bytes()).unwrap(), None);
+        assert_eq!(db.get("katyusha/12".as_bytes()).unwrap().unwrap(), "Выходила на берег Катюша".as_bytes());
+
+        let keys: Vec<Vec<u8>> = db.scan(Bound::Included("katyusha/01".as_bytes()), Bound::Unbounded).unwrap()
+            .map(|(key, _)| key)
+            .collect();
+        let expected: Vec<Vec<u8>> = [1, 2, 3, 8, 12, 13, 14, 15].iter()
+            .map(|i| format!("katyusha/{:02}", i).into_bytes())
+            .collect();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn test_drop_range() {
+        let db = ScottDB::new(Options::new("test_drop_range", 4, 4, 10, 16, 4096, 64, 256)).unwrap();
+        for i in 0..8 {
+            db.put(format!("katyusha/{:02}", i).as_bytes(), "Расцветали яблони и груши".as_bytes()).unwrap();
+        }
+        db.drop_range("katyusha/02".as_bytes(), "katyusha/04".as_bytes()).unwrap();
+        assert!(db.get("katyusha/01".as_bytes()).unwrap().is_some());
+        assert_eq!(db.get("katyusha/02".as_bytes()).unwrap(), None);
+        assert!(db.get("katyusha/04".as_bytes()).unwrap().is_some());
+
+        db.drop_range("katyusha".as_bytes(), "katyusha/99".as_bytes()).unwrap();
+        assert_eq!(db.default_family.partition_count(), 0);
+        assert_eq!(db.scan(Bound::Unbounded, Bound::Unbounded).unwrap().count(), 0);
+
+        db.put("katyusha/00".as_bytes(), "歌声好像明媚的春光".as_bytes()).unwrap();
+        assert_eq!(db.get("katyusha/00".as_bytes()).unwrap().unwrap(), "歌声好像明媚的春光".as_bytes());
+    }
+
+    #[test]
+    fn test_drop_range_recorded() {
+        let options = || Options::new("test_drop_range_recorded", 4, 4, 10, 16, 4096, 64, 256);
+        {
+            let db = ScottDB::open(options()).unwrap();
+            db.put("katyusha/01".as_bytes(), "Расцветали яблони и груши".as_bytes()).unwrap();
+            let manifest_size = std::fs::metadata("test_drop_range_recorded.manifest").unwrap().len();
+            db.drop_range("katyusha".as_bytes(), "katyusha/99".as_bytes()).unwrap();
+            assert!(std::fs::metadata("test_drop_range_recorded.manifest").unwrap().len() > manifest_size);
+        }
+        // the manifest still reads with the drop recorded
+        ScottDB::open(options()).unwrap();
+        std::fs::remove_file("test_drop_range_recorded.manifest").unwrap();
+        std::fs::remove_file("test_drop_range_recorded.options").unwrap();
+    }
+
+    #[test]
+    fn test_column_families() {
+        let db = ScottDB::new(Options::new("test_column_families", 4, 4, 10, 16, 4096, 64, 256)).unwrap();
+        let mut options = Options::new("test_column_families", 4, 4, 10, 16, 2048, 64, 256);
+        options.comparator = Arc::new(ReverseBytewiseComparator());
+        let reversed = db.create_column_family("reversed", options).unwrap();
+        assert!(db.create_column_family("reversed", Options::new("", 4, 4, 10, 16, 2048, 64, 256)).is_err());
+        assert!(db.column_family("forward").is_none());
+        assert_eq!(db.column_family("reversed").unwrap().options().comparator.name(), "pr65.reverse_bytewise");
+
+        let mut batch = WriteBatch::new();
+        for i in 0..4 {
+            let key = format!("katyusha/{}", i);
+            batch.put(db.default_column_family(), key.as_bytes(), "Выходила на берег Катюша".as_bytes());
+            batch.put(reversed, key.as_bytes(), "喀秋莎站在那俊俏的岸上".as_bytes());
+        }
+        batch.delete(reversed, "katyusha/0".as_bytes());
+        batch.put(reversed, "katyusha/0".as_bytes(), "歌声好像明媚的春光".as_bytes());
+        db.write(batch).unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch.put(reversed, "katyusha/9".as_bytes(), "".as_bytes());
+        batch.delete_range(reversed, "katyusha/0".as_bytes(), "katyusha/3".as_bytes());
+        assert!(db.write(batch).is_err());
+        assert_eq!(db.get_cf(reversed, "katyusha/9".as_bytes()).unwrap(), None);
+
+        assert_eq!(db.get("katyusha/0".as_bytes()).unwrap().unwrap(), "Выходила на берег Катюша".as_bytes());
+        assert_eq!(db.get_cf(reversed, "katyusha/0".as_bytes()).unwrap().unwrap(), "歌声好像明媚的春光".as_bytes());
+        db.delete_range_cf(reversed, "katyusha/3".as_bytes(), "katyusha/1".as_bytes()).unwrap();
+        let keys: Vec<Vec<u8>> = db.scan_cf(reversed, Bound::Unbounded, Bound::Unbounded).unwrap()
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(keys, vec![b"katyusha/1".to_vec(), b"katyusha/0".to_vec()]);
+        assert_eq!(db.scan(Bound::Unbounded, Bound::Unbounded).unwrap().count(), 4);
+    }
+
+    #[test]
+    fn test_comparator_mismatch() {
+        let _ = std::fs::remove_file("test_comparator_mismatch.manifest");
+        let options = || Options::new("test_comparator_mismatch", 4, 4, 10, 16, 4096, 64, 256);
+        let reversed = || {
+            let mut options = options();
+            options.comparator = Arc::new(ReverseBytewiseComparator());
+            options
+        };
+        {
+            let db = ScottDB::open(options()).unwrap();
+            db.create_column_family("reversed", reversed()).unwrap();
+        }
+
+        let db = ScottDB::open(options()).unwrap();
+        assert!(matches!(db.create_column_family("reversed", options()), Err(Error::ComparatorMismatch { .. })));
+        db.create_column_family("reversed", reversed()).unwrap();
+        assert!(matches!(ScottDB::open(reversed()), Err(Error::ComparatorMismatch { .. })));
+        assert!(Options::from_config_file("test_comparator_mismatch.options").is_ok());
+        std::fs::remove_file("test_comparator_mismatch.manifest").unwrap();
+        std::fs::remove_file("test_comparator_mismatch.options").unwrap();
+    }
+
+    struct CounterOperator();
+
+    impl MergeOperator for CounterOperator {
+        fn name(&self) -> &str {
+            "counter"
+        }
+
+        fn full_merge(&self, _key: &[u8], existing: Option<&[u8]>, operands: &[&[u8]]) -> Option<Vec<u8>> {
+            let mut counter = existing.map_or(Some(0), |value| value.try_into().ok().map(u64::from_be_bytes))?;
+            for operand in operands {
+                counter += u64::from_be_bytes((*operand).try_into().ok()?);
+            }
+            Some(counter.to_be_bytes().to_vec())
+        }
+    }
+
+    #[test]
+    fn test_merge() {
+        let db = ScottDB::new(Options::new("test_merge", 4, 4, 10, 16, 4096, 64, 256)).unwrap();
+        assert!(db.merge("katyusha/0".as_bytes(), &1u64.to_be_bytes()).is_err());
+
+        let mut options = Options::new("test_merge", 4, 4, 10, 16, 4096, 64, 256);
+        options.merge_operator = Some(Arc::new(CounterOperator()));
+        let db = ScottDB::new(options).unwrap();
+        db.merge("katyusha/0".as_bytes(), &1u64.to_be_bytes()).unwrap();
+        db.merge("katyusha/0".as_bytes(), &2u64.to_be_bytes()).unwrap();
+        db.put("katyusha/1".as_bytes(), &10u64.to_be_bytes()).unwrap();
+        db.merge("katyusha/1".as_bytes(), &5u64.to_be_bytes()).unwrap();
+        db.delete_range("katyusha/0".as_bytes(), "katyusha/1".as_bytes()).unwrap();
+        db.merge("katyusha/0".as_bytes(), &4u64.to_be_bytes()).unwrap();
+        assert_eq!(db.get("katyusha/0".as_bytes()).unwrap().unwrap(), 4u64.to_be_bytes());
+        assert_eq!(db.get("katyusha/1".as_bytes()).unwrap().unwrap(), 15u64.to_be_bytes());
+
+        let scanned: Vec<(Vec<u8>, Vec<u8>)> = db.scan(Bound::Unbounded, Bound::Unbounded).unwrap().collect();
+        assert_eq!(scanned, vec![(b"katyusha/0".to_vec(), 4u64.to_be_bytes().to_vec()),
+                                 (b"katyusha/1".to_vec(), 15u64.to_be_bytes().to_vec())]);
+
+        db.merge("katyusha/1".as_bytes(), "Катюша".as_bytes()).unwrap();
+        assert!(db.get("katyusha/1".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_ttl() {
+        let clock = Arc::new(ManualClock::new(1_000_000));
+        let mut options = Options::new("test_ttl", 4, 4, 10, 16, 4096, 64, 256);
+        options.clock = clock.clone();
+        let db = ScottDB::new(options).unwrap();
+        db.put("katyusha/0".as_bytes(), "Выходила на берег Катюша".as_bytes()).unwrap();
+        db.put_with_ttl("katyusha/0".as_bytes(), "喀秋莎站在那俊俏的岸上".as_bytes(), Duration::from_secs(10)).unwrap();
+        db.put_with_ttl("katyusha/1".as_bytes(), "歌声好像明媚的春光".as_bytes(), Duration::from_secs(60)).unwrap();
+
+        clock.advance(Duration::from_secs(9));
+        assert_eq!(db.get("katyusha/0".as_bytes()).unwrap().unwrap(), "喀秋莎站在那俊俏的岸上".as_bytes());
+        assert_eq!(db.scan(Bound::Unbounded, Bound::Unbounded).unwrap().count(), 2);
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(db.get("katyusha/0".as_bytes()).unwrap(), None);
+        let keys: Vec<Vec<u8>> = db.scan(Bound::Unbounded, Bound::Unbounded).unwrap()
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(keys, vec![b"katyusha/1".to_vec()]);
+
+        clock.advance(Duration::from_secs(50));
+        assert_eq!(db.get("katyusha/1".as_bytes()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_universal_compaction() {
+        let mut options = Options::new("test_universal_compaction", 4, 4, 10, 16, 4096, 64, 256);
+        options.compaction_style = CompactionStyle::Universal { size_ratio: 1, max_size_amplification: 200 };
+        let db = ScottDB::new(options).unwrap();
+        let lyrics = ["正当梨花开遍了天涯", "河上飘着柔软的轻纱", "喀秋莎站在那俊俏的岸上", "歌声好像明媚的春光"];
+        for (n, lyric) in lyrics.iter().enumerate() {
+            db.put(format!("katyusha/{}", n).as_bytes(), lyric.as_bytes()).unwrap();
+            db.put("katyusha/latest".as_bytes(), lyric.as_bytes()).unwrap();
+            if n == 3 {
+                db.delete("katyusha/0".as_bytes()).unwrap();
+            }
+            db.flush().unwrap();
+        }
+
+        // the fourth run got all runs of similar size merged into a single one below level 0
+        for file_number in 1..=4 {
+            assert!(!std::path::Path::new(&ScTableFile::new(0, 0, file_number).file_name()).exists());
+        }
+        assert!(std::path::Path::new(&ScTableFile::new(0, 1, 1).file_name()).exists());
+        assert_eq!(db.get("katyusha/0".as_bytes()).unwrap(), None);
+        assert_eq!(db.get("katyusha/2".as_bytes()).unwrap().unwrap(), "喀秋莎站在那俊俏的岸上".as_bytes());
+        assert_eq!(db.get("katyusha/latest".as_bytes()).unwrap().unwrap(), "歌声好像明媚的春光".as_bytes());
+        assert_eq!(db.scan(Bound::Unbounded, Bound::Unbounded).unwrap().count(), 4);
+
+        std::fs::remove_file(ScTableFile::new(0, 1, 1).file_name()).unwrap();
+    }
+
+    #[test]
+    fn test_record_size_limits() {
+        let mut options = Options::new("test_record_size_limits", 4, 4, 10, 16, 4096, 16, 64);
+        options.merge_operator = Some(Arc::new(CounterOperator()));
+        let db = ScottDB::new(options).unwrap();
+        let too_large = |ret: Result<(), Error>| matches!(ret, Err(Error::RecordTooLarge { .. }));
+        assert!(too_large(db.put("喀秋莎站在那俊俏的岸上".as_bytes(), "".as_bytes())));
+        assert!(too_large(db.put("喀秋莎".as_bytes(),
+                                 "Выходила на берег Катюша, на высокий берег на крутой".as_bytes())));
+        assert!(too_large(db.merge("喀秋莎".as_bytes(), &[0; 65])));
+        assert!(too_large(db.delete_range("喀秋莎".as_bytes(), "喀秋莎站在那俊俏的岸上".as_bytes())));
+        db.put("喀秋莎".as_bytes(), "Выходила на берег Катюша".as_bytes()).unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch.delete(db.default_column_family(), "喀秋莎".as_bytes());
+        batch.put(db.default_column_family(), "喀秋莎".as_bytes(), &[0; 65]);
+        assert!(too_large(db.write(batch)));
+        assert_eq!(db.get("喀秋莎".as_bytes()).unwrap().unwrap(), "Выходила на берег Катюша".as_bytes());
+    }
+
+    #[test]
+    fn test_write_batch_failing_part_way() {
+        let db = ScottDB::new(Options::new("test_write_batch_failing_part_way", 4, 4, 10, 16, 4096, 64, 256)).unwrap();
+        db.put("喀秋莎".as_bytes(), "Выходила на берег Катюша".as_bytes()).unwrap();
+
+        // bypasses validation, so the last write only fails when applied
+        let mut batch = WriteBatch::new();
+        batch.delete(db.default_column_family(), "喀秋莎".as_bytes());
+        batch.put(db.default_column_family(), "河上飘着柔软的轻纱".as_bytes(), "Поплыли туманы над рекой".as_bytes());
+        batch.delete_range(db.default_column_family(), "河".as_bytes(), "洳".as_bytes());
+        batch.put(db.default_column_family(), "正当梨花开遍了天涯".as_bytes(), &[0; 8192]);
+        let ret = db.shared.commit(batch.len(), |first_seq| batch.apply(&db.shared, first_seq));
+        assert!(matches!(ret, Err(Error::RecordTooLarge { .. })));
+
+        // later writes make newer seqs visible, the failed batch must stay absent
+        db.put("河边".as_bytes(), "歌声好像明媚的春光".as_bytes()).unwrap();
+        assert_eq!(db.get("喀秋莎".as_bytes()).unwrap().unwrap(), "Выходила на берег Катюша".as_bytes());
+        assert_eq!(db.get("河上飘着柔软的轻纱".as_bytes()).unwrap(), None);
+        assert_eq!(db.get("河边".as_bytes()).unwrap().unwrap(), "歌声好像明媚的春光".as_bytes());
+        assert_eq!(db.scan(Bound::Unbounded, Bound::Unbounded).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_seq_exhausted() {
+        let db = ScottDB::new(Options::new("test_seq_exhausted", 4, 4, 10, 16, 4096, 64, 256)).unwrap();
+        db.put("喀秋莎".as_bytes(), "Выходила на берег Катюша".as_bytes()).unwrap();
+        db.shared.commit(TABLE_MAX_SEQ as usize - 1, |_| Ok(())).unwrap();
+        let ret = db.put("河上飘着柔软的轻纱".as_bytes(), "Поплыли туманы над рекой".as_bytes());
+        assert!(matches!(ret, Err(Error::InvalidArgument { .. })));
+        assert_eq!(db.get("喀秋莎".as_bytes()).unwrap().unwrap(), "Выходила на берег Катюша".as_bytes());
+        assert_eq!(db.get("河上飘着柔软的轻纱".as_bytes()).unwrap(), None);
+    }
 }
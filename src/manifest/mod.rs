@@ -0,0 +1,243 @@
+use std::path::Path;
+
+use crc::crc32;
+
+use crate::Comparator;
+use crate::encode::{decode_fixed32, encode_fixed32_ret, decode_fixed64, encode_fixed64_ret};
+use crate::encode::varint::{put_varint32, decode_varint32};
+use crate::error::Error;
+use crate::io::IOManager;
+
+const MANIFEST_MAGIC: u32 = 0x7072364d;
+
+/// Persistent metadata of a database. It records the comparator each column family was created
+/// with, so that a database cannot be reopened with keys ordered differently, and the ranges
+/// dropped by `ScottDB::drop_range`.
+///
+/// Layout: MAGIC | varint FAMILY COUNT
+///         | (varint NAME SIZE | NAME | varint COMPARATOR SIZE | COMPARATOR)*
+///         | [varint DROP COUNT | (varint NAME SIZE | NAME | 8byte SEQ | varint START SIZE | START
+///                                | varint END SIZE | END)*] | CRC
+///
+/// The dropped ranges section is left out while there are none, as in manifests written before
+/// drops were recorded.
+pub(crate) struct Manifest {
+    db_name: String,
+    /// Column family names along with the names of their comparators
+    families: Vec<(String, String)>,
+    dropped_ranges: Vec<DroppedRange>
+}
+
+/// Column families along with their comparators, and dropped ranges of a manifest file.
+type DecodedManifest = (Vec<(String, String)>, Vec<DroppedRange>);
+
+/// Keys in `[start, end)` of a column family dropped at `seq`. Recorded before the tables holding
+/// them get unlinked.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct DroppedRange {
+    pub(crate) family: String,
+    pub(crate) seq: u64,
+    pub(crate) start: Vec<u8>,
+    pub(crate) end: Vec<u8>
+}
+
+impl Manifest {
+    /// Loads the manifest of `db_name`, or starts an empty one if the database is new.
+    pub(crate) fn load(io_manager: &IOManager, db_name: &str) -> Result<Self, Error> {
+        let file_name = Self::file_name(db_name);
+        if !Path::new(&file_name).exists() {
+            return Ok(Self { db_name: db_name.to_string(), families: Vec::new(), dropped_ranges: Vec::new() })
+        }
+        let raw = io_manager.acquire_quota().read_file(file_name)?;
+        let (families, dropped_ranges) = Self::decode(&raw)?;
+        Ok(Self { db_name: db_name.to_string(), families, dropped_ranges })
+    }
+
+    /// Checks `comparator` against the one recorded for `family`, records it if there is none. The
+    /// manifest is unchanged if it cannot be written.
+    pub(crate) fn check_comparator(&mut self,
+                                   io_manager: &IOManager,
+                                   family: &str,
+                                   comparator: &dyn Comparator) -> Result<(), Error> {
+        match self.families.iter().find(|(name, _)| name == family) {
+            Some((_, recorded)) if recorded == comparator.name() => Ok(()),
+            Some((_, recorded)) => Err(Error::comparator_mismatch(
+                format!("column family {} was created with comparator {}, opened with {}",
+                        family, recorded, comparator.name()).into())),
+            None => {
+                let mut families = self.families.clone();
+                families.push((family.to_string(), comparator.name().to_string()));
+                io_manager.acquire_quota().replace_file(Self::file_name(&self.db_name),
+                                                        &Self::encode(&families, &self.dropped_ranges))?;
+                self.families = families;
+                Ok(())
+            }
+        }
+    }
+
+    /// Records a dropped range, the manifest is unchanged if it cannot be written.
+    pub(crate) fn record_drop(&mut self, io_manager: &IOManager, dropped_range: DroppedRange) -> Result<(), Error> {
+        let mut dropped_ranges = self.dropped_ranges.clone();
+        dropped_ranges.push(dropped_range);
+        io_manager.acquire_quota().replace_file(Self::file_name(&self.db_name),
+                                                &Self::encode(&self.families, &dropped_ranges))?;
+        self.dropped_ranges = dropped_ranges;
+        Ok(())
+    }
+
+    fn file_name(db_name: &str) -> String {
+        format!("{}.manifest", db_name)
+    }
+
+    fn encode(families: &[(String, String)], dropped_ranges: &[DroppedRange]) -> Vec<u8> {
+        let mut ret = Vec::new();
+        ret.extend_from_slice(&encode_fixed32_ret(MANIFEST_MAGIC));
+        put_varint32(&mut ret, families.len() as u32);
+        for (family, comparator) in families.iter() {
+            for s in [family, comparator].iter() {
+                put_varint32(&mut ret, s.len() as u32);
+                ret.extend_from_slice(s.as_bytes());
+            }
+        }
+        if !dropped_ranges.is_empty() {
+            put_varint32(&mut ret, dropped_ranges.len() as u32);
+            for dropped_range in dropped_ranges.iter() {
+                put_varint32(&mut ret, dropped_range.family.len() as u32);
+                ret.extend_from_slice(dropped_range.family.as_bytes());
+                ret.extend_from_slice(&encode_fixed64_ret(dropped_range.seq));
+                for key in [&dropped_range.start, &dropped_range.end].iter() {
+                    put_varint32(&mut ret, key.len() as u32);
+                    ret.extend_from_slice(key);
+                }
+            }
+        }
+        ret.extend_from_slice(&encode_fixed32_ret(crc32::checksum_ieee(&ret)));
+        ret
+    }
+
+    fn decode(raw: &[u8]) -> Result<DecodedManifest, Error> {
+        if raw.len() < 9 {
+            return Err(Error::manifest_corrupt("manifest too short".into()))
+        }
+        let (body, crc) = raw.split_at(raw.len() - 4);
+        if crc32::checksum_ieee(body) != decode_fixed32(crc) {
+            return Err(Error::manifest_corrupt("incorrect checksum".into()))
+        }
+        if decode_fixed32(&body[0..4]) != MANIFEST_MAGIC {
+            return Err(Error::manifest_corrupt("incorrect magic number".into()))
+        }
+
+        let mut off = 4;
+        let read_varint = |off: &mut usize| -> Result<usize, Error> {
+            let (num, len) = decode_varint32(&body[*off..])
+                .ok_or_else(|| Error::manifest_corrupt(format!("malformed varint at offset {}", *off).into()))?;
+            *off += len;
+            Ok(num as usize)
+        };
+        let read_bytes = |off: &mut usize, size: usize| -> Result<&[u8], Error> {
+            let bytes = body.get(*off..*off + size)
+                .ok_or_else(|| Error::manifest_corrupt("unexpected end of manifest".into()))?;
+            *off += size;
+            Ok(bytes)
+        };
+        let read_str = |off: &mut usize| -> Result<String, Error> {
+            let size = read_varint(off)?;
+            String::from_utf8(read_bytes(off, size)?.to_vec())
+                .map_err(|e| Error::manifest_corrupt(e.to_string().into()))
+        };
+
+        let count = read_varint(&mut off)?;
+        let mut families = Vec::with_capacity(count.min(body.len()));
+        for _ in 0..count {
+            families.push((read_str(&mut off)?, read_str(&mut off)?));
+        }
+        let mut dropped_ranges = Vec::new();
+        if off != body.len() {
+            let count = read_varint(&mut off)?;
+            for _ in 0..count {
+                let family = read_str(&mut off)?;
+                let seq = decode_fixed64(read_bytes(&mut off, 8)?);
+                let start_size = read_varint(&mut off)?;
+                let start = read_bytes(&mut off, start_size)?.to_vec();
+                let end_size = read_varint(&mut off)?;
+                let end = read_bytes(&mut off, end_size)?.to_vec();
+                dropped_ranges.push(DroppedRange { family, seq, start, end });
+            }
+        }
+        if off != body.len() {
+            return Err(Error::manifest_corrupt("trailing bytes after dropped ranges".into()))
+        }
+        Ok((families, dropped_ranges))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crc::crc32;
+    use crate::manifest::{Manifest, DroppedRange, MANIFEST_MAGIC};
+    use crate::encode::encode_fixed32_ret;
+    use crate::io::IOManager;
+    use crate::error::Error;
+    use crate::{DefaultComparator, ReverseBytewiseComparator};
+
+    #[test]
+    fn test_manifest_codec() {
+        let manifest = Manifest {
+            db_name: "test_manifest_codec".to_string(),
+            families: vec![("default".to_string(), "pr65.bytewise".to_string()),
+                           ("喀秋莎".to_string(), "Катюша".to_string())],
+            dropped_ranges: Vec::new()
+        };
+        let mut raw = Manifest::encode(&manifest.families, &manifest.dropped_ranges);
+        assert_eq!(Manifest::decode(&raw).unwrap(), (manifest.families.clone(), Vec::new()));
+
+        assert!(Manifest::decode(&raw[..raw.len() - 1]).is_err());
+        raw[6] ^= 1;
+        assert!(Manifest::decode(&raw).is_err());
+    }
+
+    #[test]
+    fn test_record_drop() {
+        let io_manager = IOManager::new(1, false);
+        let mut manifest = Manifest::load(&io_manager, "test_record_drop").unwrap();
+        let dropped_range = DroppedRange { family: "default".to_string(), seq: 2, start: "喀".as_bytes().to_vec(),
+                                           end: "喀秋莎站".as_bytes().to_vec() };
+        manifest.record_drop(&io_manager, dropped_range.clone()).unwrap();
+        assert_eq!(Manifest::load(&io_manager, "test_record_drop").unwrap().dropped_ranges, vec![dropped_range]);
+        std::fs::remove_file("test_record_drop.manifest").unwrap();
+    }
+
+    #[test]
+    fn test_check_comparator_write_failed() {
+        let io_manager = IOManager::new(1, false);
+        let mut manifest = Manifest::load(&io_manager, "test_check_comparator_write_failed/喀秋莎").unwrap();
+        assert!(manifest.check_comparator(&io_manager, "default", &DefaultComparator()).is_err());
+        assert!(manifest.families.is_empty());
+        let ret = manifest.check_comparator(&io_manager, "default", &ReverseBytewiseComparator());
+        assert!(matches!(ret, Err(Error::IOError { .. })));
+        assert!(manifest.families.is_empty());
+    }
+
+    #[test]
+    fn test_manifest_dropped_ranges() {
+        let families = vec![("default".to_string(), "pr65.bytewise".to_string())];
+        let dropped_ranges = vec![
+            DroppedRange { family: "default".to_string(), seq: 7, start: "喀".as_bytes().to_vec(),
+                           end: "喀秋莎站".as_bytes().to_vec() },
+            DroppedRange { family: "Катюша".to_string(), seq: 0x40490fd0, start: Vec::new(), end: vec![0xFF] }
+        ];
+        let raw = Manifest::encode(&families, &dropped_ranges);
+        assert_eq!(Manifest::decode(&raw).unwrap(), (families.clone(), dropped_ranges.clone()));
+        // manifests without dropped ranges keep the former layout
+        let mut raw = encode_fixed32_ret(MANIFEST_MAGIC).to_vec();
+        raw.push(1);
+        for s in ["default", "pr65.bytewise"].iter() {
+            raw.push(s.len() as u8);
+            raw.extend_from_slice(s.as_bytes());
+        }
+        let crc = crc32::checksum_ieee(&raw);
+        raw.extend_from_slice(&encode_fixed32_ret(crc));
+        assert_eq!(Manifest::encode(&families, &[]), raw);
+        assert_eq!(Manifest::decode(&raw).unwrap(), (families, Vec::new()));
+    }
+}
@@ -0,0 +1,32 @@
+use crate::error::Error;
+
+/// Combines merge operands written by `ScottDB::merge` into a value. Operands are combined
+/// lazily on reads, and collapsed when memtables get flushed, as far as no live snapshot reads
+/// them. Compaction of table levels does not collapse operands yet.
+pub trait MergeOperator: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Applies `operands`, oldest first, upon the `existing` value of `key`. Returns `None` if
+    /// the operands can not be applied, which fails the read.
+    fn full_merge(&self, key: &[u8], existing: Option<&[u8]>, operands: &[&[u8]]) -> Option<Vec<u8>>;
+}
+
+/// Applies merge operands collected newest first upon `base`. Without operands `base` is
+/// returned as is.
+pub(crate) fn merge_operands(merge_operator: Option<&dyn MergeOperator>,
+                             key: &[u8],
+                             base: Option<Vec<u8>>,
+                             operands: &[impl AsRef<[u8]>]) -> Result<Option<Vec<u8>>, Error> {
+    if operands.is_empty() {
+        return Ok(base)
+    }
+    let merge_operator = merge_operator.ok_or_else(
+        || Error::merge_failed(format!("{} has merge operands but no merge operator is set",
+                                       String::from_utf8_lossy(key)).into()))?;
+    let operands: Vec<&[u8]> = operands.iter().rev().map(|operand| operand.as_ref()).collect();
+    merge_operator.full_merge(key, base.as_deref(), &operands)
+        .map(Some)
+        .ok_or_else(|| Error::merge_failed(
+            format!("{} failed merging {} operands of {}", merge_operator.name(), operands.len(),
+                    String::from_utf8_lossy(key)).into()))
+}
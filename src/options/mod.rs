@@ -0,0 +1,390 @@
+use std::sync::Arc;
+
+use crate::{Options, KeyEncoding, CompactionStyle, CompressionType, MergeOperator, CompactionFilter, Clock, Comparator};
+use crate::config::compression_by_name;
+use crate::error::Error;
+use crate::table::tablefmt::{TABLE_MIN_SIZE, TABLE_MAX_DATA_SIZE, TABLE_EMPTY_SIZE, TABLE_CATALOG_ITEM_SIZE,
+                             TABLE_DEFAULT_RESTART_INTERVAL};
+
+/// Builds options upon the defaults of `Options::default`, checking them on `build`.
+pub struct OptionsBuilder {
+    options: Options
+}
+
+impl OptionsBuilder {
+    pub fn new(db_name: impl ToString) -> Self {
+        Self { options: Options { db_name: db_name.to_string(), ..Options::default() } }
+    }
+
+    pub fn cache_count(mut self, cache_count: usize) -> Self {
+        self.options.cache_count = cache_count;
+        self
+    }
+
+    pub fn level0_size(mut self, level0_size: usize) -> Self {
+        self.options.level0_size = level0_size;
+        self
+    }
+
+    pub fn level_base_size(mut self, level_base_size: usize) -> Self {
+        self.options.level_base_size = level_base_size;
+        self
+    }
+
+    pub fn level_dynamic_size(mut self, level_dynamic_size: bool) -> Self {
+        self.options.level_dynamic_size = level_dynamic_size;
+        self
+    }
+
+    pub fn size_factor(mut self, size_factor: usize) -> Self {
+        self.options.size_factor = size_factor;
+        self
+    }
+
+    pub fn max_open_files(mut self, max_open_files: usize) -> Self {
+        self.options.max_open_files = max_open_files;
+        self
+    }
+
+    pub fn table_size(mut self, table_size: usize) -> Self {
+        self.options.table_size = table_size;
+        self
+    }
+
+    pub fn key_size_max(mut self, key_size_max: usize) -> Self {
+        self.options.key_size_max = key_size_max;
+        self
+    }
+
+    pub fn value_size_max(mut self, value_size_max: usize) -> Self {
+        self.options.value_size_max = value_size_max;
+        self
+    }
+
+    pub fn key_encoding(mut self, key_encoding: KeyEncoding) -> Self {
+        self.options.key_encoding = key_encoding;
+        self
+    }
+
+    pub fn compaction_style(mut self, compaction_style: CompactionStyle) -> Self {
+        self.options.compaction_style = compaction_style;
+        self
+    }
+
+    pub fn compression_per_level(mut self, compression_per_level: Vec<CompressionType>) -> Self {
+        self.options.compression_per_level = compression_per_level;
+        self
+    }
+
+    pub fn use_mmap(mut self, use_mmap: bool) -> Self {
+        self.options.use_mmap = use_mmap;
+        self
+    }
+
+    pub fn merge_operator(mut self, merge_operator: Arc<dyn MergeOperator>) -> Self {
+        self.options.merge_operator = Some(merge_operator);
+        self
+    }
+
+    pub fn compaction_filter(mut self, compaction_filter: Arc<dyn CompactionFilter>) -> Self {
+        self.options.compaction_filter = Some(compaction_filter);
+        self
+    }
+
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.options.clock = clock;
+        self
+    }
+
+    pub fn comparator(mut self, comparator: Arc<dyn Comparator>) -> Self {
+        self.options.comparator = comparator;
+        self
+    }
+
+    pub fn build(self) -> Result<Options, Error> {
+        self.options.validate()?;
+        Ok(self.options)
+    }
+}
+
+impl Options {
+    pub fn builder(db_name: impl ToString) -> OptionsBuilder {
+        OptionsBuilder::new(db_name)
+    }
+
+    /// Rejects settings the database cannot work with.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.db_name.is_empty() {
+            return invalid("db_name must not be empty".to_string())
+        }
+        if self.cache_count == 0 {
+            return invalid("cache_count must be at least 1".to_string())
+        }
+        if self.max_open_files == 0 {
+            return invalid("max_open_files must be at least 1".to_string())
+        }
+        if self.use_mmap && self.max_open_files <= self.cache_count {
+            return invalid("use_mmap needs max_open_files above cache_count, as cached tables stay mapped".to_string())
+        }
+        if let CompactionStyle::Universal { max_size_amplification: 0, .. } = self.compaction_style {
+            return invalid("universal max_size_amplification must be at least 1".to_string())
+        }
+        MutableOptions::from(self).validate()
+    }
+}
+
+/// Options which `ScottDB::set_options` can change while the database is open. Flushes and
+/// compactions started after a change use the new values.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MutableOptions {
+    pub level0_size: usize,
+    pub level_base_size: usize,
+    pub level_dynamic_size: bool,
+    pub size_factor: usize,
+    pub table_size: usize,
+    pub key_size_max: usize,
+    pub value_size_max: usize,
+    pub key_encoding: KeyEncoding,
+    pub compression_per_level: Vec<CompressionType>
+}
+
+impl From<&Options> for MutableOptions {
+    fn from(options: &Options) -> Self {
+        Self {
+            level0_size: options.level0_size,
+            level_base_size: options.level_base_size,
+            level_dynamic_size: options.level_dynamic_size,
+            size_factor: options.size_factor,
+            table_size: options.table_size,
+            key_size_max: options.key_size_max,
+            value_size_max: options.value_size_max,
+            key_encoding: options.key_encoding,
+            compression_per_level: options.compression_per_level.clone()
+        }
+    }
+}
+
+impl MutableOptions {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.level0_size == 0 {
+            return invalid("level0_size must be at least 1".to_string())
+        }
+        if self.level_base_size == 0 {
+            return invalid("level_base_size must be at least 1".to_string())
+        }
+        if self.size_factor < 2 {
+            return invalid(format!("size_factor must be at least 2, got {}", self.size_factor))
+        }
+        if self.table_size < TABLE_MIN_SIZE || self.table_size > TABLE_MAX_DATA_SIZE {
+            return invalid(format!("table_size must be within [{}, {}], got {}",
+                                   TABLE_MIN_SIZE, TABLE_MAX_DATA_SIZE, self.table_size))
+        }
+        if self.key_size_max == 0 {
+            return invalid("key_size_max must be at least 1".to_string())
+        }
+        // An expiring value carries 8 more bytes of expiry time
+        let record_size_max = (TABLE_EMPTY_SIZE + TABLE_CATALOG_ITEM_SIZE + 8)
+            .saturating_add(self.key_size_max)
+            .saturating_add(self.value_size_max);
+        if record_size_max > self.table_size {
+            return invalid(format!("a key of key_size_max {} and value of value_size_max {} do not fit into \
+                                    table_size {}", self.key_size_max, self.value_size_max, self.table_size))
+        }
+        if let KeyEncoding::PrefixDelta { restart_interval: 0 } = self.key_encoding {
+            return invalid("prefix delta restart_interval must be at least 1".to_string())
+        }
+        Ok(())
+    }
+
+    /// Applies `changes` of the form `(option, value)`, with values written as in configuration
+    /// files but unquoted, and `compression_per_level` separated by commas.
+    pub(crate) fn with_changes(&self, changes: &[(&str, &str)]) -> Result<Self, Error> {
+        let mut ret = self.clone();
+        let mut prefix_delta = None;
+        let mut restart_interval = None;
+        for &(option, value) in changes {
+            match option {
+                "level0_size" => ret.level0_size = parse_usize(option, value)?,
+                "level_base_size" => ret.level_base_size = parse_usize(option, value)?,
+                "level_dynamic_size" => ret.level_dynamic_size = match value {
+                    "true" | "1" => true,
+                    "false" | "0" => false,
+                    _ => return invalid(format!("level_dynamic_size must be true or false, got {}", value))
+                },
+                "size_factor" => ret.size_factor = parse_usize(option, value)?,
+                "table_size" => ret.table_size = parse_usize(option, value)?,
+                "key_size_max" => ret.key_size_max = parse_usize(option, value)?,
+                "value_size_max" => ret.value_size_max = parse_usize(option, value)?,
+                "key_encoding" => prefix_delta = Some(match value {
+                    "plain" => false,
+                    "prefix_delta" => true,
+                    _ => return invalid(format!("key_encoding must be plain or prefix_delta, got {}", value))
+                }),
+                "restart_interval" => restart_interval = Some(parse_usize(option, value)?),
+                "compression_per_level" => ret.compression_per_level = value.split(',')
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty())
+                    .map(|name| compression_by_name(name).ok_or_else(|| Error::invalid_argument(
+                        format!("unknown compression {}, expected none, snappy or zlib", name).into())))
+                    .collect::<Result<_, _>>()?,
+                "db_name" | "cache_count" | "max_open_files" | "use_mmap" | "merge_operator" | "compaction_filter"
+                | "clock" | "comparator" | "compaction_style" =>
+                    return invalid(format!("option {} cannot be changed while the database is open", option)),
+                _ => return invalid(format!("unknown option {}", option))
+            }
+        }
+        let current_restart_interval = match self.key_encoding {
+            KeyEncoding::PrefixDelta { restart_interval } => Some(restart_interval),
+            KeyEncoding::Plain => None
+        };
+        ret.key_encoding = match (prefix_delta.unwrap_or(current_restart_interval.is_some()), restart_interval) {
+            (true, restart_interval) => KeyEncoding::PrefixDelta {
+                restart_interval: restart_interval.or(current_restart_interval)
+                    .unwrap_or(TABLE_DEFAULT_RESTART_INTERVAL)
+            },
+            (false, Some(_)) => return invalid("restart_interval requires key_encoding prefix_delta".to_string()),
+            (false, None) => KeyEncoding::Plain
+        };
+        Ok(ret)
+    }
+
+    /// Overrides the mutable options of `options` with these values.
+    pub(crate) fn apply_to(&self, options: &mut Options) {
+        options.level0_size = self.level0_size;
+        options.level_base_size = self.level_base_size;
+        options.level_dynamic_size = self.level_dynamic_size;
+        options.size_factor = self.size_factor;
+        options.table_size = self.table_size;
+        options.key_size_max = self.key_size_max;
+        options.value_size_max = self.value_size_max;
+        options.key_encoding = self.key_encoding;
+        options.compression_per_level = self.compression_per_level.clone();
+    }
+
+    /// Target size in bytes of `level`, from level 1 on.
+    pub(crate) fn level_size(&self, level: usize) -> usize {
+        debug_assert!(level > 0);
+        self.size_factor.saturating_pow(level as u32 - 1).saturating_mul(self.level_base_size)
+    }
+
+    pub(crate) fn compression(&self, level: usize) -> CompressionType {
+        self.compression_per_level.get(level)
+            .or(self.compression_per_level.last())
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+fn invalid<T>(reason: String) -> Result<T, Error> {
+    Err(Error::invalid_argument(reason.into()))
+}
+
+fn parse_usize(option: &str, value: &str) -> Result<usize, Error> {
+    value.parse().map_err(|_| Error::invalid_argument(
+        format!("{} must be a non-negative integer, got {}", option, value).into()))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use crate::{Options, KeyEncoding, CompactionStyle, CompressionType, ScottDB, ReverseBytewiseComparator, Comparator,
+                Error};
+
+    #[test]
+    fn test_builder() {
+        let options = Options::builder("test_builder")
+            .cache_count(8)
+            .table_size(65536)
+            .value_size_max(16384)
+            .key_encoding(KeyEncoding::PrefixDelta { restart_interval: 4 })
+            .comparator(Arc::new(ReverseBytewiseComparator()))
+            .build()
+            .unwrap();
+        assert_eq!(options.db_name, "test_builder");
+        assert_eq!(options.cache_count, 8);
+        assert_eq!(options.table_size, 65536);
+        assert_eq!(options.size_factor, Options::default().size_factor);
+        assert_eq!(options.comparator.name(), "pr65.reverse_bytewise");
+    }
+
+    #[test]
+    fn test_validate() {
+        let reason = |options: Options| options.validate().err().unwrap().to_string();
+        assert!(reason(Options::builder("").options).contains("db_name"));
+        assert!(reason(Options::builder("test_validate").cache_count(0).options).contains("cache_count"));
+        assert!(reason(Options::builder("test_validate").size_factor(1).options).contains("size_factor"));
+        assert!(reason(Options::builder("test_validate").table_size(8).options).contains("table_size"));
+        assert!(reason(Options::builder("test_validate").table_size(4096).key_size_max(1024).value_size_max(4096)
+            .options).contains("do not fit"));
+        assert!(reason(Options::builder("test_validate").key_encoding(KeyEncoding::PrefixDelta { restart_interval: 0 })
+            .options).contains("restart_interval"));
+        assert!(Options::builder("test_validate").max_open_files(0).build().is_err());
+        assert!(reason(Options::builder("test_validate").cache_count(16).max_open_files(16).use_mmap(true).options)
+            .contains("use_mmap"));
+        assert!(Options::builder("test_validate")
+            .compaction_style(CompactionStyle::Universal { size_ratio: 1, max_size_amplification: 0 })
+            .build()
+            .is_err());
+        assert!(Options::builder("test_validate")
+            .compaction_style(CompactionStyle::Universal { size_ratio: 1, max_size_amplification: 200 })
+            .build()
+            .is_ok());
+        assert!(Options::default().validate().is_ok());
+
+        assert!(ScottDB::new(Options::new("test_validate", 0, 4, 10, 16, 4096, 64, 256)).is_err());
+        let db = ScottDB::new(Options::new("test_validate", 4, 4, 10, 16, 4096, 64, 256)).unwrap();
+        assert!(db.create_column_family("katyusha", Options::new("test_validate", 4, 4, 1, 16, 4096, 64, 256))
+            .is_err());
+    }
+
+    #[test]
+    fn test_set_options() {
+        let db = ScottDB::new(Options::new("test_set_options", 4, 4, 10, 16, 4096, 64, 256)).unwrap();
+        db.put("喀秋莎".as_bytes(), "Выходила на берег Катюша".as_bytes()).unwrap();
+        db.set_options(&[("table_size", "8192"), ("value_size_max", "1024"), ("key_encoding", "prefix_delta"),
+                         ("compression_per_level", "none, snappy")]).unwrap();
+        let mutable_options = db.default_column_family().mutable_options();
+        assert_eq!(mutable_options.table_size, 8192);
+        assert_eq!(mutable_options.key_encoding, KeyEncoding::PrefixDelta { restart_interval: 16 });
+        assert_eq!(mutable_options.compression_per_level, vec![CompressionType::None, CompressionType::Snappy]);
+        assert_eq!(db.default_column_family().options().table_size, 4096);
+        db.put("喀秋莎".as_bytes(), &[0; 1024]).unwrap();
+
+        let reason = |changes: &[(&str, &str)]| db.set_options(changes).err().unwrap().to_string();
+        assert!(reason(&[("comparator", "pr65.reverse_bytewise")]).contains("cannot be changed"));
+        assert!(reason(&[("db_name", "katyusha")]).contains("cannot be changed"));
+        assert!(reason(&[("level0_size", "8"), ("katyusha", "1")]).contains("unknown option"));
+        assert!(reason(&[("level0_size", "8"), ("size_factor", "1")]).contains("size_factor"));
+        assert!(reason(&[("table_size", "-1")]).contains("non-negative"));
+        assert!(reason(&[("key_encoding", "plain"), ("restart_interval", "4")]).contains("restart_interval"));
+        assert_eq!(db.default_column_family().mutable_options(), mutable_options);
+
+        let value_size_max = usize::MAX.to_string();
+        assert!(matches!(db.set_options(&[("value_size_max", &value_size_max)]), Err(Error::InvalidArgument { .. })));
+        assert!(reason(&[("key_size_max", &value_size_max)]).contains("do not fit"));
+        assert_eq!(db.default_column_family().mutable_options(), mutable_options);
+        db.put("喀秋莎".as_bytes(), "Выходила на берег Катюша".as_bytes()).unwrap();
+
+        db.set_options(&[("value_size_max", "16")]).unwrap();
+        assert!(matches!(db.put("喀秋莎".as_bytes(), "Выходила на берег Катюша".as_bytes()),
+                         Err(Error::RecordTooLarge { .. })));
+    }
+
+    #[test]
+    fn test_set_options_dumped() {
+        let db = ScottDB::open(Options::new("test_set_options_dumped", 4, 4, 10, 16, 4096, 64, 256)).unwrap();
+        db.set_options(&[("table_size", "8192"), ("compression_per_level", "snappy")]).unwrap();
+        let dumped = Options::from_config_file("test_set_options_dumped.options").unwrap();
+        assert_eq!(dumped.table_size, 8192);
+        assert_eq!(dumped.compression_per_level, vec![CompressionType::Snappy]);
+        assert_eq!(dumped.value_size_max, 256);
+
+        let katyusha = db.create_column_family("katyusha", Options::new("test_set_options_dumped", 4, 4, 10, 16,
+                                                                        4096, 64, 256)).unwrap();
+        db.set_options_cf(katyusha, &[("table_size", "16384")]).unwrap();
+        assert_eq!(Options::from_config_file("test_set_options_dumped.options").unwrap().table_size, 8192);
+        std::fs::remove_file("test_set_options_dumped.manifest").unwrap();
+        std::fs::remove_file("test_set_options_dumped.options").unwrap();
+    }
+}
@@ -1,31 +1,339 @@
+use crate::MutableOptions;
 use crate::table::Table;
-use crate::Comparator;
-use crate::table::sctable::ScTable;
+use crate::table::sctable::{ScTable, ScTableFile};
 
-pub struct Level<Comp: 'static + Comparator> {
-    tables: Vec<Box<dyn Table<Comp>>>,
+pub struct Level {
+    tables: Vec<Box<dyn Table>>,
+    /// File size of each table, in the order of `tables`
+    table_sizes: Vec<usize>,
     file_id: u64
 }
 
-impl<Comp: 'static + Comparator> Level<Comp> {
+impl Level {
     pub(crate) fn new() -> Self {
         Self {
             tables: Vec::new(),
+            table_sizes: Vec::new(),
             file_id: 1
         }
     }
 
-    pub(crate) fn add_file(&mut self, table_file: ScTable<Comp>) {
+    pub(crate) fn add_file(&mut self, table_file: ScTable, file_size: usize) {
         self.tables.push(Box::new(table_file));
+        self.table_sizes.push(file_size);
+    }
+
+    /// Tables of this level, in the order they were added.
+    pub(crate) fn tables(&self) -> &[Box<dyn Table>] {
+        &self.tables
+    }
+
+    /// Removes the tables matching `pred`, returns the files of removed tables.
+    pub(crate) fn remove_tables<F>(&mut self, pred: F) -> Vec<ScTableFile>
+        where F: Fn(&dyn Table) -> bool {
+        let (removed, kept): (Vec<_>, Vec<_>) = self.tables.drain(..)
+            .zip(self.table_sizes.drain(..))
+            .partition(|(table, _)| pred(table.as_ref()));
+        (self.tables, self.table_sizes) = kept.into_iter().unzip();
+        removed.iter().map(|(table, _)| table.file()).collect()
     }
 
     pub(crate) fn table_count(&self) -> usize {
         self.tables.len()
     }
 
+    /// Total file size of the tables of this level.
+    pub(crate) fn size(&self) -> usize {
+        self.table_sizes.iter().sum()
+    }
+
+    pub(crate) fn table_files(&self) -> Vec<ScTableFile> {
+        let mut ret: Vec<ScTableFile> = self.tables.iter().map(|table| table.file()).collect();
+        ret.sort();
+        ret.dedup();
+        ret
+    }
+
     pub(crate) fn level_next_file_id(&mut self) -> u64 {
         let ret = self.file_id;
         self.file_id += 1;
         ret
     }
 }
+
+/// Target sizes of the levels below level 0, and the level which level 0 compacts into.
+pub(crate) struct LevelTargets {
+    /// Level 0 compacts into this level, the levels in between are kept empty
+    base_level: usize,
+    /// Target size in bytes of each level, 0 for level 0 and the levels above the base level
+    sizes: Vec<usize>
+}
+
+impl LevelTargets {
+    /// Targets grow by `size_factor` from `level_base_size` at level 1 on. With
+    /// `level_dynamic_size`, targets are instead derived backwards from the size of the bottom
+    /// level, and level 0 compacts into the topmost level whose target is at least
+    /// `level_base_size`, so that small partitions get few levels. The bottom level always
+    /// targets its static size, overflowing it pushes the bottom level down.
+    pub(crate) fn new(levels: &[Level], options: &MutableOptions) -> Self {
+        let mut sizes: Vec<usize> = (0..levels.len())
+            .map(|n| if n == 0 { 0 } else { options.level_size(n) })
+            .collect();
+        let bottom_level = (1..levels.len()).rev().find(|&n| levels[n].size() > 0);
+        let bottom_level = match bottom_level {
+            Some(bottom_level) if options.level_dynamic_size => bottom_level,
+            _ => return Self { base_level: 1, sizes }
+        };
+        let bottom_size = levels[bottom_level].size();
+        let mut base_level = bottom_level;
+        for n in (1..bottom_level).rev() {
+            let size = bottom_size / options.size_factor.saturating_pow((bottom_level - n) as u32);
+            if size < options.level_base_size {
+                break
+            }
+            sizes[n] = size;
+            base_level = n;
+        }
+        for size in sizes[1..base_level].iter_mut() {
+            *size = 0;
+        }
+        Self { base_level, sizes }
+    }
+
+    pub(crate) fn base_level(&self) -> usize {
+        self.base_level
+    }
+
+    pub(crate) fn size(&self, level: usize) -> usize {
+        self.sizes[level]
+    }
+}
+
+/// Compaction score of each level, a level scoring at least 1 needs compaction. Tables of level 0
+/// overlap and each of them costs a read, so level 0 is scored by table count against
+/// `level0_size`. Deeper levels are scored by size against their target size, data left above
+/// the base level scores infinitely.
+pub(crate) fn compaction_scores(levels: &[Level], options: &MutableOptions) -> Vec<f64> {
+    let targets = LevelTargets::new(levels, options);
+    levels.iter()
+        .enumerate()
+        .map(|(n, level)| match (n, targets.size(n)) {
+            (0, _) => level.table_count() as f64 / options.level0_size as f64,
+            (_, 0) if level.size() == 0 => 0.0,
+            (_, 0) => f64::INFINITY,
+            (_, target) => level.size() as f64 / target as f64
+        })
+        .collect()
+}
+
+/// Picks the level scoring highest to compact next, if any level needs compaction. Returns the
+/// input level along with the level to compact into.
+pub(crate) fn pick_compaction(levels: &[Level], options: &MutableOptions) -> Option<(usize, usize)> {
+    let input_level = compaction_scores(levels, options).into_iter()
+        .enumerate()
+        .filter(|&(_, score)| score >= 1.0)
+        .max_by(|(_, lhs), (_, rhs)| lhs.total_cmp(rhs))
+        .map(|(n, _)| n)?;
+    match input_level {
+        0 => Some((0, LevelTargets::new(levels, options).base_level())),
+        _ => Some((input_level, input_level + 1))
+    }
+}
+
+/// Sorted runs picked by universal compaction. Picked runs are always the newest ones: the
+/// newest `level0_tables` tables of level 0, and once those are all of level 0, all tables of
+/// the levels from 1 to `last_input_level`.
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) struct UniversalCompaction {
+    pub(crate) level0_tables: usize,
+    pub(crate) last_input_level: usize,
+    pub(crate) output_level: usize
+}
+
+/// Picks sorted runs to merge with universal compaction, once there are at least `level0_size`
+/// runs. Each table of level 0 is a run, and so is each deeper level. All runs are merged if the
+/// newer runs total `max_size_amplification` percent of the oldest one. Otherwise, starting
+/// from the newest run, runs are merged as long as the next run is at most `size_ratio` percent
+/// larger than the runs picked before it. If that picks a single run, just enough of the newest
+/// runs are merged to get below `level0_size` runs.
+pub(crate) fn pick_universal_compaction(levels: &[Level],
+                                        level0_size: usize,
+                                        size_ratio: usize,
+                                        max_size_amplification: usize) -> Option<UniversalCompaction> {
+    // (level, size) of each run, newest first
+    let mut runs: Vec<(usize, usize)> = levels.first()
+        .map_or(Vec::new(), |level0| level0.table_sizes.iter().rev().map(|&size| (0, size)).collect());
+    runs.extend(levels.iter()
+        .enumerate()
+        .skip(1)
+        .filter(|(_, level)| level.size() > 0)
+        .map(|(n, level)| (n, level.size())));
+    if runs.len() < level0_size.max(2) {
+        return None
+    }
+
+    let oldest_size = runs.last().unwrap().1;
+    let newer_size: usize = runs[..runs.len() - 1].iter().map(|&(_, size)| size).sum();
+    let count = if newer_size.saturating_mul(100) >= oldest_size.saturating_mul(max_size_amplification) {
+        runs.len()
+    } else {
+        let mut picked_size = runs[0].1;
+        let mut count = 1;
+        while count < runs.len()
+            && runs[count].1.saturating_mul(100) <= picked_size.saturating_mul(100 + size_ratio) {
+            picked_size += runs[count].1;
+            count += 1;
+        }
+        if count > 1 { count } else { (runs.len() + 2).saturating_sub(level0_size).max(2) }
+    };
+
+    let level0_count = levels.first().map_or(0, |level0| level0.table_count());
+    let last_input_level = runs[count - 1].0;
+    let output_level = if last_input_level > 0 {
+        last_input_level
+    } else if count < level0_count {
+        // the output is newer than the tables of level 0 left behind
+        0
+    } else {
+        // level 0 only, the output goes right above the shallowest run below level 0, or to the
+        // bottom if there is none
+        match runs.get(count) {
+            Some(&(n, _)) => n - 1,
+            None => (levels.len() - 1).max(1)
+        }
+    };
+    Some(UniversalCompaction { level0_tables: count.min(level0_count), last_input_level, output_level })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Options, MutableOptions, DefaultComparator};
+    use crate::partition::UserKey;
+    use crate::partition::level::{Level, LevelTargets, UniversalCompaction, compaction_scores, pick_compaction,
+                                  pick_universal_compaction};
+    use crate::table::sctable::{ScTable, ScTableFile};
+
+    fn add_table(level: &mut Level, n: usize, file_size: usize) {
+        static COMPARATOR: DefaultComparator = DefaultComparator();
+        let file_number = level.level_next_file_id();
+        let key = |key: &str| UserKey::new_owned(key.as_bytes().to_vec(), &COMPARATOR);
+        level.add_file(ScTable::new(ScTableFile::new(41041, n as u32, file_number), key("喀秋莎"), key("Катюша")),
+                       file_size);
+    }
+
+    #[test]
+    fn test_compaction_scores() {
+        let options = MutableOptions::from(&Options::new("test_compaction_scores", 4, 4, 10, 16, 4096, 64, 256));
+        assert_eq!(options.level_size(1), 4 * 10 * 4096);
+        assert_eq!(options.level_size(2), 4 * 10 * 10 * 4096);
+
+        let mut levels = vec![Level::new(), Level::new(), Level::new()];
+        for _ in 0..3 {
+            add_table(&mut levels[0], 0, 4096);
+        }
+        // a few small tables do not fill level 1, however many there are
+        for _ in 0..50 {
+            add_table(&mut levels[1], 1, 512);
+        }
+        add_table(&mut levels[2], 2, 4096);
+        assert_eq!(compaction_scores(&levels, &options), vec![0.75, 50.0 * 512.0 / 163840.0, 4096.0 / 1638400.0]);
+        assert_eq!(pick_compaction(&levels, &options), None);
+
+        add_table(&mut levels[0], 0, 64);
+        assert_eq!(pick_compaction(&levels, &options), Some((0, 1)));
+        for _ in 0..300 {
+            add_table(&mut levels[1], 1, 512);
+        }
+        assert_eq!(pick_compaction(&levels, &options), Some((1, 2)));
+        assert_eq!(levels[1].size(), 350 * 512);
+        levels[1].remove_tables(|_| true);
+        assert_eq!(levels[1].size(), 0);
+        assert_eq!(pick_compaction(&levels, &options), Some((0, 1)));
+    }
+
+    #[test]
+    fn test_dynamic_level_targets() {
+        let mut options =
+            MutableOptions::from(&Options::new("test_dynamic_level_targets", 4, 4, 10, 16, 4096, 64, 256));
+        options.level_base_size = 10000;
+        options.level_dynamic_size = true;
+
+        // without data below level 0, level 0 compacts into level 1
+        let mut levels = vec![Level::new(), Level::new(), Level::new(), Level::new(), Level::new()];
+        add_table(&mut levels[0], 0, 4096);
+        assert_eq!(LevelTargets::new(&levels, &options).base_level(), 1);
+
+        // a small partition keeps all its data in the bottom level
+        add_table(&mut levels[3], 3, 90000);
+        let targets = LevelTargets::new(&levels, &options);
+        assert_eq!(targets.base_level(), 3);
+        assert_eq!((1..5).map(|n| targets.size(n)).collect::<Vec<_>>(), vec![0, 0, 10000 * 100, 10000 * 1000]);
+
+        // targets grow along with the bottom level
+        add_table(&mut levels[3], 3, 810000);
+        let targets = LevelTargets::new(&levels, &options);
+        assert_eq!(targets.base_level(), 2);
+        assert_eq!((1..5).map(|n| targets.size(n)).collect::<Vec<_>>(), vec![0, 90000, 10000 * 100, 10000 * 1000]);
+
+        for _ in 0..3 {
+            add_table(&mut levels[0], 0, 4096);
+        }
+        assert_eq!(pick_compaction(&levels, &options), Some((0, 2)));
+        add_table(&mut levels[2], 2, 200000);
+        assert_eq!(pick_compaction(&levels, &options), Some((2, 3)));
+        levels[2].remove_tables(|_| true);
+
+        // data left above the base level, as by switching to dynamic level sizes, moves down first
+        add_table(&mut levels[1], 1, 100);
+        assert_eq!(compaction_scores(&levels, &options)[1], f64::INFINITY);
+        assert_eq!(pick_compaction(&levels, &options), Some((1, 2)));
+
+        options.level_dynamic_size = false;
+        assert_eq!(LevelTargets::new(&levels, &options).base_level(), 1);
+        assert_eq!(pick_compaction(&levels, &options), Some((0, 1)));
+
+        // once level 1 would reach level_base_size, the bottom level overflows and moves down
+        options.level_dynamic_size = true;
+        levels[1].remove_tables(|_| true);
+        add_table(&mut levels[3], 3, 200000);
+        assert_eq!(LevelTargets::new(&levels, &options).base_level(), 1);
+        assert_eq!(pick_compaction(&levels, &options), Some((3, 4)));
+    }
+
+    #[test]
+    fn test_universal_compaction() {
+        let pick = |levels: &[Level]| pick_universal_compaction(levels, 4, 10, 200);
+        let picked = |level0_tables: usize, last_input_level: usize, output_level: usize|
+            Some(UniversalCompaction { level0_tables, last_input_level, output_level });
+
+        // too few runs
+        let mut levels = vec![Level::new()];
+        for size in [8000, 3000, 1000] {
+            add_table(&mut levels[0], 0, size);
+        }
+        assert_eq!(pick(&levels), None);
+
+        // runs of similar size merge, newest first
+        add_table(&mut levels[0], 0, 1000);
+        assert_eq!(pick(&levels), picked(2, 0, 0));
+        add_table(&mut levels[0], 0, 1000);
+        assert_eq!(pick(&levels), picked(4, 0, 0));
+
+        // all runs merge when the newer ones grow too large compared to the oldest one, into the
+        // bottom level
+        levels.push(Level::new());
+        levels.push(Level::new());
+        assert_eq!(pick_universal_compaction(&levels, 4, 0, 50), picked(5, 0, 2));
+
+        // otherwise, the newest runs merge until few enough remain
+        levels[0] = Level::new();
+        for size in [8000, 4000, 2000, 1000] {
+            add_table(&mut levels[0], 0, size);
+        }
+        add_table(&mut levels[2], 2, 100000);
+        assert_eq!(pick(&levels), picked(3, 0, 0));
+        assert_eq!(pick_universal_compaction(&levels, 3, 10, 200), picked(4, 0, 1));
+        assert_eq!(pick_universal_compaction(&levels, 2, 10, 200), picked(4, 2, 2));
+        assert_eq!(pick_universal_compaction(&levels, 4, 10, 10), picked(4, 2, 2));
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::BTreeMap;
+use std::collections::btree_map::Iter;
+use std::cmp::Ordering;
+
+use crate::merge::{MergeOperator, merge_operands};
+use crate::partition::{InternalKey, UserKey};
+use crate::table::tablefmt::{TABLE_CATALOG_ITEM_SIZE, TABLE_EMPTY_SIZE, TABLE_RANGE_DELETION_ITEM_SIZE};
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) enum Record {
+    Value(Vec<u8>),
+    Deletion,
+    /// Merge operand, combined with older records of the key by the merge operator on reads
+    Merge(Vec<u8>),
+    /// Value which expires at the given clock time, in milliseconds since the Unix epoch
+    Expiring(Vec<u8>, u64)
+}
+
+impl Record {
+    pub(crate) fn size(&self) -> usize {
+        match self {
+            Record::Value(value) | Record::Merge(value) => value.len(),
+            Record::Expiring(value, _) => value.len() + 8,
+            Record::Deletion => 0
+        }
+    }
+}
+
+/// Deletes all versions of keys in `[start, end)` written before `seq`.
+pub(crate) struct RangeTombstone {
+    pub(crate) seq: u64,
+    pub(crate) start: UserKey,
+    pub(crate) end: UserKey
+}
+
+impl RangeTombstone {
+    pub(crate) fn new(seq: u64, start: UserKey, end: UserKey) -> Self {
+        Self { seq, start, end }
+    }
+
+    pub(crate) fn covers(&self, key: &UserKey) -> bool {
+        &self.start <= key && key < &self.end
+    }
+
+    /// Whether this tombstone deletes the record of `key` written at `seq`, as seen at `snapshot_seq`.
+    pub(crate) fn deletes(&self, key: &UserKey, seq: u64, snapshot_seq: u64) -> bool {
+        self.seq > seq && self.seq <= snapshot_seq && self.covers(key)
+    }
+
+    pub(crate) fn size(&self) -> usize {
+        self.start.key().len() + self.end.key().len() + TABLE_RANGE_DELETION_ITEM_SIZE
+    }
+}
+
+impl Clone for RangeTombstone {
+    fn clone(&self) -> Self {
+        Self { seq: self.seq, start: self.start.clone(), end: self.end.clone() }
+    }
+}
+
+pub(crate) struct MemTable {
+    entries: BTreeMap<InternalKey, Record>,
+    range_tombstones: Vec<RangeTombstone>,
+    data_size: usize
+}
+
+impl MemTable {
+    pub(crate) fn new() -> Self {
+        Self { entries: BTreeMap::new(), range_tombstones: Vec::new(), data_size: 0 }
+    }
+
+    pub(crate) fn put(&mut self, key: InternalKey, record: Record) {
+        let key_size = key.user_key.key().len();
+        self.data_size += key_size + record.size();
+        if let Some(replaced) = self.entries.insert(key, record) {
+            self.data_size -= key_size + replaced.size();
+        }
+    }
+
+    pub(crate) fn add_range_tombstone(&mut self, range_tombstone: RangeTombstone) {
+        self.data_size += range_tombstone.size() - TABLE_RANGE_DELETION_ITEM_SIZE;
+        self.range_tombstones.push(range_tombstone);
+    }
+
+    pub(crate) fn remove(&mut self, key: &InternalKey) {
+        if let Some(removed) = self.entries.remove(key) {
+            self.data_size -= key.user_key.key().len() + removed.size();
+        }
+    }
+
+    pub(crate) fn remove_range_tombstones(&mut self, seq: u64) {
+        let data_size = &mut self.data_size;
+        self.range_tombstones.retain(|range_tombstone| {
+            if range_tombstone.seq == seq {
+                *data_size -= range_tombstone.size() - TABLE_RANGE_DELETION_ITEM_SIZE;
+            }
+            range_tombstone.seq != seq
+        });
+    }
+
+    /// Returns the latest record of `key` visible at the seq of `key`.
+    pub(crate) fn get(&self, key: &InternalKey) -> Option<(u64, &Record)> {
+        self.entries.range(key..).next()
+            .filter(|(k, _)| k.user_key == key.user_key)
+            .map(|(k, record)| (k.seq, record))
+    }
+
+    /// Returns the seq of the latest range tombstone covering `key` visible at the seq of `key`,
+    /// or 0 if there is none.
+    pub(crate) fn range_tombstone_seq(&self, key: &InternalKey) -> u64 {
+        self.range_tombstones.iter()
+            .filter(|tombstone| tombstone.seq <= key.seq && tombstone.covers(&key.user_key))
+            .map(|tombstone| tombstone.seq)
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub(crate) fn iter(&self) -> Iter<'_, InternalKey, Record> {
+        self.entries.iter()
+    }
+
+    /// Records to be flushed into a table, sorted like internal keys. Records covered by a range
+    /// tombstone of this memtable are left out, unless a snapshot at `oldest_snapshot` or later may
+    /// still read them. Runs of merge operands upon a base record of this memtable get collapsed
+    /// into a single value at the seq of the newest operand, leaving out operands newer than
+    /// `oldest_snapshot`. Operands upon an expiring value are kept, as the value may expire later.
+    pub(crate) fn flush_records(&self,
+                                merge_operator: Option<&dyn MergeOperator>,
+                                oldest_snapshot: u64) -> Vec<(&InternalKey, Record)> {
+        let mut records = Vec::new();
+        // Pending run of merge operands of a single key, newest first
+        let mut operands: Vec<(&InternalKey, &[u8])> = Vec::new();
+        for (key, record) in self.entries.iter() {
+            if operands.first().is_some_and(|(k, _)| k.user_key != key.user_key) {
+                records.extend(operands.drain(..).map(|(k, operand)| (k, Record::Merge(operand.to_vec()))));
+            }
+            let covered = self.range_tombstones.iter()
+                .any(|range_tombstone| range_tombstone.deletes(&key.user_key, key.seq, oldest_snapshot));
+            match record {
+                Record::Merge(operand) if !covered => {
+                    operands.push((key, operand));
+                    continue
+                },
+                _ => {}
+            }
+            // Operands a live snapshot may read without the newer ones are kept
+            let newer = operands.iter().take_while(|(k, _)| k.seq > oldest_snapshot).count();
+            records.extend(operands.drain(..newer).map(|(k, operand)| (k, Record::Merge(operand.to_vec()))));
+            if !operands.is_empty() {
+                let base = match record {
+                    Record::Value(value) if !covered => Some(Some(value.clone())),
+                    Record::Expiring(_, _) if !covered => None,
+                    _ => Some(None)
+                };
+                let run: Vec<&[u8]> = operands.iter().map(|(_, operand)| *operand).collect();
+                match base.map(|base| merge_operands(merge_operator, key.user_key.key(), base, &run)) {
+                    Some(Ok(Some(value))) => records.push((operands[0].0, Record::Value(value))),
+                    _ => records.extend(operands.iter().map(|(k, operand)| (*k, Record::Merge(operand.to_vec()))))
+                }
+                operands.clear();
+            }
+            if !covered {
+                records.push((key, record.clone()));
+            }
+        }
+        records.extend(operands.drain(..).map(|(k, operand)| (k, Record::Merge(operand.to_vec()))));
+        records
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.entries.is_empty() && self.range_tombstones.is_empty()
+    }
+
+    pub(crate) fn range_tombstones(&self) -> &[RangeTombstone] {
+        &self.range_tombstones
+    }
+
+    /// Size of the table this memtable would be flushed into
+    pub(crate) fn size(&self) -> usize {
+        self.data_size
+            + self.entries.len() * TABLE_CATALOG_ITEM_SIZE
+            + self.range_tombstones.len() * TABLE_RANGE_DELETION_ITEM_SIZE
+            + TABLE_EMPTY_SIZE
+    }
+
+    /// Smallest and largest user key touched by this memtable, including range tombstones.
+    pub(crate) fn bounds(&self) -> Option<(UserKey, UserKey)> {
+        let mut lower = self.entries.keys().next().map(|k| &k.user_key);
+        let mut upper = self.entries.keys().next_back().map(|k| &k.user_key);
+        for tombstone in self.range_tombstones.iter() {
+            if lower.map_or(true, |lower| tombstone.start.cmp(lower) == Ordering::Less) {
+                lower = Some(&tombstone.start);
+            }
+            if upper.map_or(true, |upper| tombstone.end.cmp(upper) == Ordering::Greater) {
+                upper = Some(&tombstone.end);
+            }
+        }
+        Some((lower?.clone(), upper?.clone()))
+    }
+}
@@ -1,48 +1,63 @@
-use std::collections::BTreeMap;
-use std::sync::{Mutex, atomic::AtomicU64, Condvar, Arc};
-use std::marker::PhantomData;
+use std::sync::{Mutex, MutexGuard, RwLock, atomic::AtomicU64, Condvar, Arc};
 use std::cmp::Ordering;
 use std::ptr::NonNull;
+use std::ops::Bound;
+use std::iter::once;
 
-use crate::{Comparator, Options, DefaultComparator};
-use crate::table::tablefmt::{TABLE_CATALOG_ITEM_SIZE, TABLE_MIN_SIZE};
+use crate::{Comparator, Options, MutableOptions, CompactionStyle};
+use crate::table::tablefmt::{TABLE_CATALOG_ITEM_SIZE, TABLE_EMPTY_SIZE};
 use crate::table::builder::ScTableBuilder;
+use crate::table::Table;
 use crate::table::cache::TableCacheManager;
 use crate::io::IOManager;
 use crate::error::Error;
-use crate::partition::level::Level;
-use crate::table::sctable::{ScTable, ScTableFile};
+use crate::family::SnapshotList;
+use crate::partition::level::{Level, UniversalCompaction, pick_compaction, pick_universal_compaction};
+use crate::table::sctable::{ScTable, ScTableFile, in_bounds};
+use crate::table::upgrade::upgrade_table;
+use crate::partition::memtable::{MemTable, Record, RangeTombstone};
+use crate::merge::{MergeOperator, merge_operands};
+use crate::compaction::{CompactionContext, filter_record};
 use std::sync::atomic::AtomicBool;
 
 mod level;
+pub(crate) mod memtable;
 
-pub(crate) enum UserKey<Comp: Comparator> {
-    Owned(Vec<u8>, PhantomData<Comp>),
-    Borrow(NonNull<[u8]>)
+/// A user key along with the comparator ordering it. Keys never outlive the options holding
+/// their comparator.
+pub(crate) enum UserKey {
+    Owned(Vec<u8>, NonNull<dyn Comparator>),
+    Borrow(NonNull<[u8]>, NonNull<dyn Comparator>)
 }
 
-impl<Comp: Comparator> Clone for UserKey<Comp> {
+impl Clone for UserKey {
     fn clone(&self) -> Self {
         match self {
-            UserKey::Owned(data, _) => UserKey::Owned(data.clone(), PhantomData),
-            UserKey::Borrow(ptr) => UserKey::Borrow(ptr.clone())
+            UserKey::Owned(data, comparator) => UserKey::Owned(data.clone(), *comparator),
+            UserKey::Borrow(ptr, comparator) => UserKey::Borrow(*ptr, *comparator)
         }
     }
 }
 
-impl<Comp: Comparator> UserKey<Comp> {
-    pub(crate) fn new_owned(vec: Vec<u8>) -> Self {
-        UserKey::Owned(vec, PhantomData)
+impl UserKey {
+    pub(crate) fn new_owned(vec: Vec<u8>, comparator: &(dyn Comparator + 'static)) -> Self {
+        UserKey::Owned(vec, NonNull::from(comparator))
     }
 
-    pub(crate) fn new_borrow(slice: &[u8]) -> Self {
-        UserKey::Borrow(unsafe { NonNull::new_unchecked(slice as *const [u8] as _) })
+    pub(crate) fn new_borrow(slice: &[u8], comparator: &(dyn Comparator + 'static)) -> Self {
+        UserKey::Borrow(unsafe { NonNull::new_unchecked(slice as *const [u8] as _) }, NonNull::from(comparator))
     }
 
-    fn key(&self) -> &[u8] {
+    pub(crate) fn key(&self) -> &[u8] {
         match self {
             UserKey::Owned(k, _) => k.as_slice(),
-            UserKey::Borrow(b) => unsafe { b.as_ref() }
+            UserKey::Borrow(b, _) => unsafe { b.as_ref() }
+        }
+    }
+
+    pub(crate) fn comparator(&self) -> &(dyn Comparator + 'static) {
+        match self {
+            UserKey::Owned(_, comparator) | UserKey::Borrow(_, comparator) => unsafe { comparator.as_ref() }
         }
     }
 
@@ -55,68 +70,68 @@ impl<Comp: Comparator> UserKey<Comp> {
     }
 }
 
-impl<Comp: Comparator> Ord for UserKey<Comp> {
+impl Ord for UserKey {
     fn cmp(&self, other: &Self) -> Ordering {
-        Comp::compare(&self.key(), &other.key())
+        self.comparator().compare(self.key(), other.key())
     }
 }
 
-impl<Comp: Comparator> PartialOrd for UserKey<Comp> {
+impl PartialOrd for UserKey {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         Some(self.cmp(other))
     }
 }
 
-impl<Comp: Comparator> PartialEq for UserKey<Comp> {
+impl PartialEq for UserKey {
     fn eq(&self, other: &Self) -> bool {
         self.cmp(other) == Ordering::Equal
     }
 }
 
-impl<Comp: Comparator> Eq for UserKey<Comp> {}
-
-type DefaultUserKey = UserKey<DefaultComparator>;
+impl Eq for UserKey {}
 
-pub(crate) struct InternalKey<Comp: Comparator> {
-    seq: u64,
-    pub(crate) user_key: UserKey<Comp>
+pub(crate) struct InternalKey {
+    pub(crate) seq: u64,
+    pub(crate) user_key: UserKey
 }
 
-impl<Comp: Comparator> InternalKey<Comp> {
-    pub(crate) fn new(seq: u64, user_key: UserKey<Comp>) -> Self {
+impl InternalKey {
+    pub(crate) fn new(seq: u64, user_key: UserKey) -> Self {
         Self { seq, user_key }
     }
 }
 
-impl<Comp: Comparator> Ord for InternalKey<Comp> {
+/// Internal keys are ordered by user key, and then by descending sequence number, so the latest
+/// version of a user key comes first. Memtables are flushed in this order, which makes tables sorted
+/// the way `ScTableCache::get` binary searches them and `ScTableCache::validate_order` checks. The
+/// former order, by sequence number first, flushed tables whose items could not be searched by key.
+impl Ord for InternalKey {
     fn cmp(&self, other: &Self) -> Ordering {
-        let ord =  self.seq.cmp(&other.seq);
+        let ord = self.user_key.cmp(&other.user_key);
         if ord == Ordering::Equal {
-            self.user_key.cmp(&other.user_key)
+            other.seq.cmp(&self.seq)
         } else {
             ord
         }
     }
 }
 
-impl<Comp: Comparator> PartialOrd for InternalKey<Comp> {
+impl PartialOrd for InternalKey {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         Some(self.cmp(other))
     }
 }
 
-impl<Comp: Comparator> PartialEq for InternalKey<Comp> {
+impl PartialEq for InternalKey {
     fn eq(&self, other: &Self) -> bool {
         self.cmp(other) == Ordering::Equal
     }
 }
 
-impl<Comp: Comparator> Eq for InternalKey<Comp> {}
+impl Eq for InternalKey {}
 
-type MemTable<Comp> = BTreeMap<InternalKey<Comp>, Vec<u8>>;
-
-pub(crate) struct Partition<'a, Comp: 'static + Comparator> {
-    data: Mutex<PartitionData<'a, Comp>>,
+pub(crate) struct Partition<'a> {
+    data: Mutex<PartitionData>,
     condvar: Condvar,
 
     under_explode: AtomicBool,
@@ -124,26 +139,32 @@ pub(crate) struct Partition<'a, Comp: 'static + Comparator> {
     partition_id: u32,
 
     seq: &'a AtomicU64,
+    snapshots: &'a SnapshotList,
     cache_manager: &'a TableCacheManager,
     io_manager: &'a IOManager,
-    options: &'a Options
+    options: &'a Options,
+    mutable_options: &'a RwLock<MutableOptions>
 }
 
-impl<'a, Comp: 'static + Comparator> Partition<'a, Comp> {
-    fn new(options: &'a Options,
+impl<'a> Partition<'a> {
+    pub(crate) fn new(options: &'a Options,
+           mutable_options: &'a RwLock<MutableOptions>,
            partition_id: u32,
            seq: &'a AtomicU64,
+           snapshots: &'a SnapshotList,
            cache_manager: &'a TableCacheManager,
            io_manager: &'a IOManager) -> Self {
         Self {
-            data: Mutex::new(PartitionData::new(options)),
+            data: Mutex::new(PartitionData::new()),
             condvar: Condvar::new(),
             under_explode: AtomicBool::new(false),
             partition_id,
             seq,
+            snapshots,
             cache_manager,
             io_manager,
-            options
+            options,
+            mutable_options
         }
     }
 
@@ -167,7 +188,7 @@ impl<'a, Comp: 'static + Comparator> Partition<'a, Comp> {
     }
 }
 
-impl<'a, Comp: Comparator> PartialOrd for Partition<'a, Comp> {
+impl<'a> PartialOrd for Partition<'a> {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         let g1 = self.data.lock().unwrap();
         let g2 = other.data.lock().unwrap();
@@ -184,43 +205,127 @@ impl<'a, Comp: Comparator> PartialOrd for Partition<'a, Comp> {
     }
 }
 
-impl<'a, Comp: Comparator> Ord for Partition<'a, Comp> {
+impl<'a> Ord for Partition<'a> {
     fn cmp(&self, other: &Self) -> Ordering {
         self.partial_cmp(other).unwrap()
     }
 }
 
-impl<'a, Comp: Comparator> PartialEq for Partition<'a, Comp> {
+impl<'a> PartialEq for Partition<'a> {
     fn eq(&self, other: &Self) -> bool {
         debug_assert!(Self::debug_never_eq_sanity_check(self, other));
         false
     }
 }
 
-impl<'a, Comp: Comparator> Eq for Partition<'a, Comp> {}
+impl<'a> Eq for Partition<'a> {}
 
-fn kv_pair_size<Comp>(key: &InternalKey<Comp>, value: &[u8]) -> usize
-    where Comp: Comparator {
-    key.user_key.key().len() + value.len() + TABLE_CATALOG_ITEM_SIZE
+fn kv_pair_size(key: &InternalKey, record: &Record) -> usize {
+    key.user_key.key().len() + record.size() + TABLE_CATALOG_ITEM_SIZE
 }
 
-#[derive(Clone, Ord, PartialOrd, Eq, PartialEq)]
-pub(crate) struct ArcPartition<'a, Comp: 'static + Comparator>(Arc<Partition<'a, Comp>>);
+fn visible_value(seq: u64, record: Record, range_deletion_seq: u64, now: u64) -> Option<Vec<u8>> {
+    match record {
+        Record::Value(value) if seq > range_deletion_seq => Some(value),
+        Record::Expiring(value, expire_at) if seq > range_deletion_seq && expire_at > now => Some(value),
+        _ => None
+    }
+}
 
-impl<'a, Comp: 'static + Comparator> ArcPartition<'a, Comp> {
-    pub(crate) fn new(partition: Partition<'a, Comp>) -> Self {
+/// Resolves the value of a key visible at `snapshot_seq` from all its records, sorted newest
+/// first. Values expired at `now` are absent.
+fn resolve_value(records: &[(InternalKey, Record)],
+                 range_tombstones: &[RangeTombstone],
+                 snapshot_seq: u64,
+                 now: u64,
+                 merge_operator: Option<&dyn MergeOperator>) -> Result<Option<Vec<u8>>, Error> {
+    let mut operands = Vec::new();
+    let mut base = None;
+    for (key, record) in records.iter().filter(|(key, _)| key.seq <= snapshot_seq) {
+        if range_tombstones.iter()
+            .any(|range_tombstone| range_tombstone.deletes(&key.user_key, key.seq, snapshot_seq)) {
+            break
+        }
+        match record {
+            Record::Merge(operand) => operands.push(operand.as_slice()),
+            Record::Value(value) => {
+                base = Some(value.clone());
+                break
+            },
+            Record::Expiring(value, expire_at) => {
+                base = (*expire_at > now).then(|| value.clone());
+                break
+            },
+            Record::Deletion => break
+        }
+    }
+    merge_operands(merge_operator, records[0].0.user_key.key(), base, &operands)
+}
+
+#[derive(Ord, PartialOrd, Eq, PartialEq)]
+pub(crate) struct ArcPartition<'a>(Arc<Partition<'a>>);
+
+impl<'a> Clone for ArcPartition<'a> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<'a> ArcPartition<'a> {
+    pub(crate) fn new(partition: Partition<'a>) -> Self {
         Self(Arc::new(partition))
     }
 
-    pub(crate) fn write(&self, key: InternalKey<Comp>, value: Vec<u8>) -> Result<(), Error> {
+    pub(crate) fn write(&self, key: InternalKey, record: Record) -> Result<(), Error> {
+        let (mut data, table_size) = self.make_room(kv_pair_size(&key, &record))?;
+        data.memtable_put(key, record, table_size);
+        Ok(())
+    }
+
+    pub(crate) fn write_range_deletion(&self, range_tombstone: RangeTombstone) -> Result<(), Error> {
+        let (mut data, table_size) = self.make_room(range_tombstone.size())?;
+        data.memtable_add_range_tombstone(range_tombstone, table_size);
+        Ok(())
+    }
+
+    /// Removes the record of `user_key` written at `seq` from the memtables, for a batch failing
+    /// part way.
+    pub(crate) fn revert_write(&self, seq: u64, user_key: &[u8]) {
+        let partition = &self.0;
+        let mut data = partition.data.lock().unwrap();
+        let data = &mut *data;
+        let key = InternalKey::new(seq, UserKey::new_borrow(user_key, &*partition.options.comparator));
+        for memtable in once(&mut data.mem_table).chain(data.imm_table.iter_mut()) {
+            memtable.remove(&key);
+        }
+    }
+
+    /// Removes range tombstones written at `seq` from the memtables, for a batch failing part way.
+    pub(crate) fn revert_range_deletion(&self, seq: u64) {
+        let mut data = self.0.data.lock().unwrap();
+        let data = &mut *data;
+        for memtable in once(&mut data.mem_table).chain(data.imm_table.iter_mut()) {
+            memtable.remove_range_tombstones(seq);
+        }
+    }
+
+    /// Waits until the memtable has room for `size` more bytes, returns it along with the
+    /// `table_size` it made room under.
+    fn make_room(&self, size: usize) -> Result<(MutexGuard<'_, PartitionData>, usize), Error> {
         let partition = &self.0;
+        // A record not fitting into an empty table would wait for room forever
+        let table_size = partition.mutable_options.read().unwrap().table_size;
+        if TABLE_EMPTY_SIZE + size > table_size {
+            return Err(Error::record_too_large(
+                format!("record of {} bytes does not fit into table_size {}", size, table_size).into()))
+        }
         let mut data = partition.data.lock().unwrap();
         data.background_error()?;
         loop {
             if false /* TODO add proper condition here */ {
                 return Err(Error::requires_explode())
             }
-            if data.memtable_size() + kv_pair_size(&key, &value) <= partition.options.table_size {
+            if data.memtable_size() + size <= table_size {
                 break;
             } else if data.has_imm() {
                 data = partition.condvar.wait(data).unwrap();
@@ -230,11 +335,110 @@ impl<'a, Comp: 'static + Comparator> ArcPartition<'a, Comp> {
                 break;
             }
         }
-        data.memtable_put(key, value);
-        Ok(())
+        Ok((data, table_size))
+    }
+
+    /// Returns the latest value of `user_key` visible at `snapshot_seq`.
+    pub(crate) fn get(&self, user_key: &[u8], snapshot_seq: u64) -> Result<Option<Vec<u8>>, Error> {
+        let now = self.0.options.clock.now();
+        let mut snapshot_seq = snapshot_seq;
+        let mut operands = Vec::new();
+        let base = loop {
+            match self.lookup(user_key, snapshot_seq)? {
+                (Some((seq, Record::Merge(operand))), range_deletion_seq) if seq > range_deletion_seq => {
+                    operands.push(operand);
+                    snapshot_seq = seq - 1;
+                },
+                (record, range_deletion_seq) =>
+                    break record.and_then(|(seq, record)| visible_value(seq, record, range_deletion_seq, now))
+            }
+        };
+        merge_operands(self.0.options.merge_operator.as_deref(), user_key, base, &operands)
+    }
+
+    /// Returns the seq of the latest write touching `user_key`, including range deletions, or 0
+    /// if there is none.
+    pub(crate) fn latest_seq(&self, user_key: &[u8]) -> Result<u64, Error> {
+        let (record, range_deletion_seq) = self.lookup(user_key, u64::MAX)?;
+        Ok(record.map_or(0, |(seq, _)| seq).max(range_deletion_seq))
+    }
+
+    /// Returns the latest record of `user_key` visible at `snapshot_seq`, and the seq of the
+    /// latest range deletion covering it that is at least as new.
+    fn lookup(&self, user_key: &[u8], snapshot_seq: u64) -> Result<(Option<(u64, Record)>, u64), Error> {
+        let partition = &self.0;
+        let data = partition.data.lock().unwrap();
+        data.background_error()?;
+
+        let key = InternalKey::new(snapshot_seq, UserKey::new_borrow(user_key, &*partition.options.comparator));
+        let mut range_deletion_seq = 0;
+        for memtable in once(&data.mem_table).chain(data.imm_table.iter()) {
+            range_deletion_seq = range_deletion_seq.max(memtable.range_tombstone_seq(&key));
+            if let Some((seq, record)) = memtable.get(&key) {
+                return Ok((Some((seq, record.clone())), range_deletion_seq))
+            }
+        }
+        for table in data.tables_newest_first() {
+            range_deletion_seq = range_deletion_seq.max(
+                table.range_deletion_seq(&key, partition.cache_manager, partition.io_manager)?);
+            if let Some(record) = table.get(&key, partition.cache_manager, partition.io_manager)? {
+                return Ok((Some(record), range_deletion_seq))
+            }
+        }
+        Ok((None, range_deletion_seq))
     }
 
-    pub(crate) fn explode(&self) -> (ArcPartition<'a, Comp>, ArcPartition<'a, Comp>) {
+    /// Returns all key value pairs within the given bounds visible at `snapshot_seq`, sorted by
+    /// key.
+    pub(crate) fn scan(&self,
+                       lower: Bound<&[u8]>,
+                       upper: Bound<&[u8]>,
+                       snapshot_seq: u64) -> Result<Vec<(Vec<u8>, Vec<u8>)>, Error> {
+        let partition = &self.0;
+        let data = partition.data.lock().unwrap();
+        data.background_error()?;
+
+        let mut records = Vec::new();
+        let mut range_tombstones = Vec::new();
+        for memtable in once(&data.mem_table).chain(data.imm_table.iter()) {
+            records.extend(memtable.iter()
+                .filter(|(key, _)| in_bounds(&*partition.options.comparator, key.user_key.key(), lower, upper))
+                .map(|(key, record)| (InternalKey::new(key.seq, key.user_key.clone()), record.clone())));
+            range_tombstones.extend_from_slice(memtable.range_tombstones());
+        }
+        for table in data.tables_newest_first() {
+            let (table_records, table_range_tombstones) =
+                table.scan(lower, upper, partition.cache_manager, partition.io_manager)?;
+            records.extend(table_records);
+            range_tombstones.extend(table_range_tombstones);
+        }
+        records.sort_by(|(key1, _), (key2, _)| key1.cmp(key2));
+
+        let now = partition.options.clock.now();
+        let mut ret = Vec::new();
+        let mut begin = 0;
+        while begin < records.len() {
+            let user_key = &records[begin].0.user_key;
+            let end = begin + records[begin..].iter().take_while(|(key, _)| &key.user_key == user_key).count();
+            if let Some(value) = resolve_value(&records[begin..end], &range_tombstones, snapshot_seq, now,
+                                               partition.options.merge_operator.as_deref())? {
+                ret.push((user_key.key().to_vec(), value));
+            }
+            begin = end;
+        }
+        Ok(ret)
+    }
+
+    /// Smallest and largest user key ever written into this partition.
+    pub(crate) fn bounds(&self) -> Option<(Vec<u8>, Vec<u8>)> {
+        let data = self.0.data.lock().unwrap();
+        match data.bounds() {
+            (Some(lower), Some(upper)) => Some((lower.key().to_vec(), upper.key().to_vec())),
+            _ => None
+        }
+    }
+
+    pub(crate) fn explode(&self) -> (ArcPartition<'a>, ArcPartition<'a>) {
         let partition = &self.0;
         let data = partition.data.lock().unwrap();
         // TODO
@@ -243,6 +447,84 @@ impl<'a, Comp: 'static + Comparator> ArcPartition<'a, Comp> {
         unimplemented!()
     }
 
+    /// Rewrites all tables of older format versions into the current version, returns the count
+    /// of rewritten tables.
+    pub(crate) fn upgrade_tables(&self) -> Result<usize, Error> {
+        let partition = &self.0;
+        let table_files: Vec<ScTableFile> = {
+            let data = partition.data.lock().unwrap();
+            data.background_error()?;
+            data.levels.iter().flat_map(|level| level.table_files()).collect()
+        };
+
+        let mut upgraded_count = 0;
+        for table_file in table_files {
+            let raw = partition.io_manager.acquire_quota().read_file(table_file.file_name())?;
+            if let Some(upgraded) = upgrade_table(&raw)? {
+                partition.io_manager.acquire_quota().replace_file(table_file.file_name(), &upgraded)?;
+                upgraded_count += 1;
+            }
+        }
+        Ok(upgraded_count)
+    }
+
+    /// Unlinks tables whose keys all lie in `[start, end)`, returns the count of dropped tables.
+    pub(crate) fn drop_tables(&self, start: &[u8], end: &[u8]) -> Result<usize, Error> {
+        let comparator = &self.0.options.comparator;
+        self.unlink_tables(|table| comparator.compare(start, table.lower_bound().key()) != Ordering::Greater
+            && comparator.compare(table.upper_bound().key(), end) == Ordering::Less)
+    }
+
+    /// Unlinks all tables, for a partition already removed from the database.
+    pub(crate) fn drop_all_tables(&self) -> Result<usize, Error> {
+        self.unlink_tables(|_| true)
+    }
+
+    fn unlink_tables<F>(&self, pred: F) -> Result<usize, Error>
+        where F: Fn(&dyn Table) -> bool {
+        let partition = &self.0;
+        let removed_files;
+        let removed_count;
+        {
+            let mut data = partition.data.lock().unwrap();
+            data.background_error()?;
+            let removed = data.levels.iter_mut()
+                .flat_map(|level| level.remove_tables(&pred))
+                .collect::<Vec<_>>();
+            removed_count = removed.len();
+            removed_files = data.unreferenced_files(removed);
+        }
+        self.remove_files(removed_files)?;
+        Ok(removed_count)
+    }
+
+    fn remove_files(&self, table_files: Vec<ScTableFile>) -> Result<(), Error> {
+        let partition = &self.0;
+        for table_file in table_files {
+            partition.cache_manager.remove_cache(table_file);
+            partition.io_manager.acquire_quota().remove_file(table_file.file_name())?;
+        }
+        Ok(())
+    }
+
+    /// Flushes the memtables into level 0, along with the compactions that triggers. Writes must
+    /// be excluded meanwhile, records of a batch failing later could not be reverted once flushed.
+    pub(crate) fn flush(&self) -> Result<(), Error> {
+        loop {
+            {
+                let mut data = self.0.data.lock().unwrap();
+                data.background_error()?;
+                if !data.has_imm() {
+                    if data.mem_table.is_empty() {
+                        return Ok(())
+                    }
+                    data.convert_mem_to_imm();
+                }
+            }
+            self.compact_memtable();
+        }
+    }
+
     fn compact_memtable(&self) {
         let partition = &self.0;
         let buffer;
@@ -254,9 +536,32 @@ impl<'a, Comp: 'static + Comparator> ArcPartition<'a, Comp> {
                 return;
             }
             imm_bounds = data.imm_bounds();
-            let mut builder = ScTableBuilder::new();
-            for (k, v) in data.imm_table.as_ref().unwrap().iter() {
-                builder.add_kv(k.seq, k.user_key.key(), &v);
+            let mut builder = {
+                let mutable_options = partition.mutable_options.read().unwrap();
+                ScTableBuilder::with_format(mutable_options.key_encoding, mutable_options.compression(0))
+            };
+            let imm_table = data.imm_table.as_ref().unwrap();
+            let compaction_filter = partition.options.compaction_filter.as_deref()
+                .filter(|compaction_filter| compaction_filter.filter_flush());
+            let context = CompactionContext { partition_id: partition.partition_id, output_level: 0, is_flush: true };
+            let now = partition.options.clock.now();
+            let records = imm_table.flush_records(partition.options.merge_operator.as_deref(),
+                                                  partition.snapshots.oldest());
+            for (k, record) in records {
+                let record = match compaction_filter {
+                    Some(compaction_filter) => filter_record(compaction_filter, &context, k.user_key.key(), record),
+                    None => record
+                };
+                match record {
+                    // expired values turn into deletions, which keep shadowing older versions
+                    Record::Expiring(_, expire_at) if expire_at <= now => builder.add_deletion(k.seq, k.user_key.key()),
+                    record => add_record(&mut builder, k, &record)
+                }
+            }
+            for range_tombstone in imm_table.range_tombstones() {
+                builder.add_range_deletion(range_tombstone.seq,
+                                           range_tombstone.start.key(),
+                                           range_tombstone.end.key());
             }
             buffer = builder.build();
             if data.levels.len() == 0 {
@@ -274,65 +579,213 @@ impl<'a, Comp: 'static + Comparator> ArcPartition<'a, Comp> {
         let table = ScTable::new(table_file, imm_lower, imm_upper);
         {
             let mut data = partition.data.lock().unwrap();
-            data.levels[0].add_file(table);
+            data.levels[0].add_file(table, buffer.len());
             // TODO flush metadata onto disk
             let _ = data.imm_table.take();
         }
         partition.condvar.notify_one();
-        self.schedule_compaction(0);
+        self.schedule_compaction();
     }
 
-    fn schedule_compaction(&self, input_level: usize) {
-        let output_level = input_level + 1;
+    /// Runs compactions until no level needs one. The partition stays locked meanwhile.
+    fn schedule_compaction(&self) {
         let partition = &self.0;
-        {
-            let data = partition.data.lock().unwrap();
-            if data.background_error().is_err() {
-                return;
+        let mut data = partition.data.lock().unwrap();
+        while data.background_error().is_ok() {
+            let mutable_options = partition.mutable_options.read().unwrap();
+            let compaction = match partition.options.compaction_style {
+                CompactionStyle::Leveled => pick_compaction(&data.levels, &mutable_options)
+                    .map(|(input_level, output_level)| (data.leveled_inputs(input_level, output_level), output_level)),
+                CompactionStyle::Universal { size_ratio, max_size_amplification } =>
+                    pick_universal_compaction(&data.levels, mutable_options.level0_size, size_ratio,
+                                              max_size_amplification)
+                        .map(|compaction| (data.universal_inputs(&compaction), compaction.output_level))
+            };
+            let (input_files, output_level) = match compaction {
+                Some(compaction) => compaction,
+                None => return
+            };
+            if let Err(e) = self.compact(&mut data, &input_files, output_level, &mutable_options) {
+                data.record_background_error(e);
             }
-            if data.levels[input_level].table_count() <= partition.options.level_size(input_level) {
-                return;
+        }
+    }
+
+    /// Merges the tables of `input_files` into new tables of `output_level`, which replace them.
+    /// Versions no snapshot can read get dropped, and so do deletions once nothing older may be
+    /// left below them.
+    fn compact(&self,
+               data: &mut PartitionData,
+               input_files: &[ScTableFile],
+               output_level: usize,
+               mutable_options: &MutableOptions) -> Result<(), Error> {
+        let partition = &self.0;
+        let mut merged = MemTable::new();
+        for table in data.tables_newest_first().filter(|table| input_files.contains(&table.file())) {
+            let (records, range_tombstones) =
+                table.scan(Bound::Unbounded, Bound::Unbounded, partition.cache_manager, partition.io_manager)?;
+            for (key, record) in records {
+                merged.put(key, record);
+            }
+            for range_tombstone in range_tombstones {
+                merged.add_range_tombstone(range_tombstone);
+            }
+        }
+        // older versions may be left in deeper levels, or in tables of level 0 not compacted
+        let bottommost = data.levels.iter()
+            .enumerate()
+            .filter(|&(n, _)| n > output_level || n == 0 && output_level == 0)
+            .all(|(_, level)| level.tables().iter().all(|table| input_files.contains(&table.file())));
+
+        let oldest_snapshot = partition.snapshots.oldest();
+        let context = CompactionContext { partition_id: partition.partition_id, output_level, is_flush: false };
+        let now = partition.options.clock.now();
+        let mut records = Vec::new();
+        // whether older versions of the current key are hidden from all snapshots
+        let mut hidden = false;
+        let mut current_key = None;
+        for (key, record) in merged.flush_records(partition.options.merge_operator.as_deref(), oldest_snapshot) {
+            if current_key != Some(&key.user_key) {
+                current_key = Some(&key.user_key);
+                hidden = false;
+            }
+            if hidden {
+                continue
             }
-            // TODO
-            // 1. pick files to be compacted in input_level
-            // 2. find overlapping files in output_level
-            // 3. temporary unlock
-            // 4. perform compaction
-            // 5. re-lock
-            // 6. update metadata
-            // 7. schedule another compaction
-            // 8. un-lock
-            unimplemented!()
+            let record = match partition.options.compaction_filter.as_deref() {
+                Some(compaction_filter) => filter_record(compaction_filter, &context, key.user_key.key(), record),
+                None => record
+            };
+            let record = match record {
+                Record::Expiring(_, expire_at) if expire_at <= now => Record::Deletion,
+                record => record
+            };
+            if key.seq <= oldest_snapshot && !matches!(record, Record::Merge(_)) {
+                hidden = true;
+                if bottommost && record == Record::Deletion {
+                    continue
+                }
+            }
+            records.push((key, record));
+        }
+        let range_tombstones: Vec<&RangeTombstone> = merged.range_tombstones().iter()
+            .filter(|range_tombstone| !bottommost || range_tombstone.seq > oldest_snapshot)
+            .collect();
+
+        while data.levels.len() <= output_level {
+            data.levels.push(Level::new());
+        }
+        let mut output_tables = Vec::new();
+        for (records, lower, upper) in split_records(&records, mutable_options.table_size) {
+            let mut builder = ScTableBuilder::with_format(mutable_options.key_encoding,
+                                                          mutable_options.compression(output_level));
+            let mut bounds = records.first().zip(records.last())
+                .map(|((first, _), (last, _))| (first.user_key.clone(), last.user_key.clone()));
+            for (key, record) in records {
+                add_record(&mut builder, key, record);
+            }
+            // range tombstones get split along with the records, so each table covers its own range
+            for range_tombstone in range_tombstones.iter() {
+                let start = match lower {
+                    Some(lower) if lower > &range_tombstone.start => lower,
+                    _ => &range_tombstone.start
+                };
+                let end = match upper {
+                    Some(upper) if upper < &range_tombstone.end => upper,
+                    _ => &range_tombstone.end
+                };
+                if start >= end {
+                    continue
+                }
+                builder.add_range_deletion(range_tombstone.seq, start.key(), end.key());
+                bounds = match bounds {
+                    Some((lower_bound, upper_bound)) =>
+                        Some((lower_bound.min(start.clone()), upper_bound.max(end.clone()))),
+                    None => Some((start.clone(), end.clone()))
+                };
+            }
+            let (lower_bound, upper_bound) = match bounds {
+                Some(bounds) => bounds,
+                None => continue
+            };
+            let buffer = builder.build();
+            let file_number = data.levels[output_level].level_next_file_id();
+            let table_file = ScTableFile::new(partition.partition_id, output_level as u32, file_number);
+            partition.io_manager.acquire_quota().write_file(table_file.file_name(), &buffer)?;
+            output_tables.push((ScTable::new(table_file, lower_bound, upper_bound), buffer.len()));
+        }
+
+        let removed = data.levels.iter_mut()
+            .flat_map(|level| level.remove_tables(|table| input_files.contains(&table.file())))
+            .collect();
+        for (table, size) in output_tables {
+            data.levels[output_level].add_file(table, size);
         }
+        // TODO flush metadata onto disk
+        self.remove_files(data.unreferenced_files(removed))
     }
 }
 
-pub(crate) struct PartitionData<'a, Comp: 'static + Comparator> {
-    mem_table: MemTable<Comp>,
-    mem_table_data_size: usize,
+fn add_record(builder: &mut ScTableBuilder, key: &InternalKey, record: &Record) {
+    match record {
+        Record::Value(value) => builder.add_kv(key.seq, key.user_key.key(), value),
+        Record::Expiring(value, expire_at) => builder.add_expiring(key.seq, key.user_key.key(), value, *expire_at),
+        Record::Deletion => builder.add_deletion(key.seq, key.user_key.key()),
+        Record::Merge(operand) => builder.add_merge(key.seq, key.user_key.key(), operand)
+    }
+}
+
+/// Records of an output table, along with the range of user keys the table owns.
+type RecordRun<'r> = (&'r [(&'r InternalKey, Record)], Option<&'r UserKey>, Option<&'r UserKey>);
+
+/// Splits sorted records into runs of about `table_size`, never splitting the versions of a key.
+/// Each run comes with the range of user keys it owns, from the first key of the run up to the
+/// first key of the next run, unbounded below the first run and above the last one. There is
+/// always at least one run, possibly empty.
+fn split_records<'r>(records: &'r [(&InternalKey, Record)], table_size: usize) -> Vec<RecordRun<'r>> {
+    let mut starts = vec![0];
+    let mut size = TABLE_EMPTY_SIZE;
+    for (n, (key, record)) in records.iter().enumerate() {
+        let pair_size = kv_pair_size(key, record);
+        if n > *starts.last().unwrap() && size + pair_size > table_size && records[n - 1].0.user_key != key.user_key {
+            starts.push(n);
+            size = TABLE_EMPTY_SIZE;
+        }
+        size += pair_size;
+    }
+    let first_key = |n: usize| records.get(n).map(|(key, _)| &key.user_key);
+    starts.iter()
+        .enumerate()
+        .map(|(i, &start)| {
+            let end = starts.get(i + 1).copied().unwrap_or(records.len());
+            let lower = if i == 0 { None } else { first_key(start) };
+            let upper = starts.get(i + 1).and_then(|&next| first_key(next));
+            (&records[start..end], lower, upper)
+        })
+        .collect()
+}
 
-    imm_table: Option<MemTable<Comp>>,
-    levels: Vec<Level<Comp>>,
+pub(crate) struct PartitionData {
+    mem_table: MemTable,
 
-    lower_bound: Option<UserKey<Comp>>,
-    upper_bound: Option<UserKey<Comp>>,
+    imm_table: Option<MemTable>,
+    levels: Vec<Level>,
 
-    background_error: Option<Error>,
+    lower_bound: Option<UserKey>,
+    upper_bound: Option<UserKey>,
 
-    options: &'a Options
+    background_error: Option<Error>
 }
 
-impl<'a, Comp: 'static + Comparator> PartitionData<'a, Comp> {
-    fn new(options: &'a Options) -> Self {
+impl PartitionData {
+    fn new() -> Self {
         Self {
             mem_table: MemTable::new(),
-            mem_table_data_size: 0,
             imm_table: None,
             levels: Vec::new(),
             lower_bound: None,
             upper_bound: None,
-            background_error: None,
-            options
+            background_error: None
         }
     }
 
@@ -348,28 +801,67 @@ impl<'a, Comp: 'static + Comparator> PartitionData<'a, Comp> {
         self.background_error.replace(error);
     }
 
+    /// Files of a leveled compaction from `input_level` into `output_level`: all tables of level 0,
+    /// or the first table of a deeper level, along with the tables of `output_level` they overlap.
+    fn leveled_inputs(&self, input_level: usize, output_level: usize) -> Vec<ScTableFile> {
+        let tables = self.levels[input_level].tables();
+        let inputs = if input_level == 0 { tables } else { &tables[..1] };
+        let lower = inputs.iter().map(|table| table.lower_bound()).min().unwrap();
+        let upper = inputs.iter().map(|table| table.upper_bound()).max().unwrap();
+        let overlapping = self.levels.get(output_level).into_iter()
+            .flat_map(|level| level.tables().iter())
+            .filter(|table| table.upper_bound() >= lower && table.lower_bound() <= upper);
+        inputs.iter().chain(overlapping).map(|table| table.file()).collect()
+    }
+
+    /// Files of the sorted runs picked by universal compaction.
+    fn universal_inputs(&self, compaction: &UniversalCompaction) -> Vec<ScTableFile> {
+        let level0 = self.levels[0].tables();
+        level0[level0.len() - compaction.level0_tables..].iter()
+            .chain(self.levels[1..=compaction.last_input_level].iter().flat_map(|level| level.tables().iter()))
+            .map(|table| table.file())
+            .collect()
+    }
+
+    /// Files of `removed` tables no remaining table refers to.
+    fn unreferenced_files(&self, mut removed: Vec<ScTableFile>) -> Vec<ScTableFile> {
+        let remaining_files: Vec<ScTableFile> = self.levels.iter().flat_map(|level| level.table_files()).collect();
+        removed.sort();
+        removed.dedup();
+        removed.retain(|table_file| !remaining_files.contains(table_file));
+        removed
+    }
+
     fn has_imm(&self) -> bool {
         self.imm_table.is_some()
     }
 
-    fn imm_bounds(&self) -> (UserKey<Comp>, UserKey<Comp>) {
-        let imm_table = self.imm_table.as_ref().unwrap();
-        let (lower_bound, _) = imm_table.first_key_value().unwrap();
-        let (upper_bound, _) = imm_table.last_key_value().unwrap();
-        (lower_bound.user_key.clone(), upper_bound.user_key.clone())
+    fn imm_bounds(&self) -> (UserKey, UserKey) {
+        self.imm_table.as_ref().unwrap().bounds().unwrap()
+    }
+
+    fn memtable_put(&mut self, key: InternalKey, record: Record, table_size: usize) {
+        debug_assert!(self.memtable_size() + kv_pair_size(&key, &record) <= table_size);
+        self.extend_bounds(&key.user_key);
+        self.mem_table.put(key, record);
+    }
+
+    fn memtable_add_range_tombstone(&mut self, range_tombstone: RangeTombstone, table_size: usize) {
+        debug_assert!(self.memtable_size() + range_tombstone.size() <= table_size);
+        self.extend_bounds(&range_tombstone.start);
+        self.extend_bounds(&range_tombstone.end);
+        self.mem_table.add_range_tombstone(range_tombstone);
     }
 
-    fn memtable_put(&mut self, key: InternalKey<Comp>, value: Vec<u8>) {
-        debug_assert!(self.memtable_size() + kv_pair_size(&key, &value) <= self.options.table_size);
+    fn extend_bounds(&mut self, user_key: &UserKey) {
         if self.lower_bound.is_none() && self.upper_bound.is_none() {
-            self.set_lower_bound(key.user_key.clone());
-            self.set_upper_bound(key.user_key.clone());
-        } else if &key.user_key < self.lower_bound.as_ref().unwrap() {
-            self.set_lower_bound(key.user_key.clone());
-        } else if &key.user_key > self.upper_bound.as_ref().unwrap() {
-            self.set_upper_bound(key.user_key.clone());
+            self.set_lower_bound(user_key.clone());
+            self.set_upper_bound(user_key.clone());
+        } else if user_key < self.lower_bound.as_ref().unwrap() {
+            self.set_lower_bound(user_key.clone());
+        } else if user_key > self.upper_bound.as_ref().unwrap() {
+            self.set_upper_bound(user_key.clone());
         }
-        self.mem_table.insert(key, value);
     }
 
     fn convert_mem_to_imm(&mut self) {
@@ -378,19 +870,24 @@ impl<'a, Comp: 'static + Comparator> PartitionData<'a, Comp> {
     }
 
     fn memtable_size(&self) -> usize {
-        self.mem_table_data_size + self.mem_table.len() * TABLE_CATALOG_ITEM_SIZE + TABLE_MIN_SIZE
+        self.mem_table.size()
+    }
+
+    /// Tables from the newest to the oldest one.
+    fn tables_newest_first(&self) -> impl Iterator<Item = &Box<dyn Table>> {
+        self.levels.iter().flat_map(|level| level.tables().iter().rev())
     }
 
-    fn bounds(&self) -> (Option<&UserKey<Comp>>, Option<&UserKey<Comp>>) {
+    fn bounds(&self) -> (Option<&UserKey>, Option<&UserKey>) {
         (self.lower_bound.as_ref(), self.upper_bound.as_ref())
     }
 
-    fn set_lower_bound(&mut self, lower_bound: UserKey<Comp>) {
+    fn set_lower_bound(&mut self, lower_bound: UserKey) {
         debug_assert!(lower_bound.is_owned());
         self.lower_bound.replace(lower_bound);
     }
 
-    fn set_upper_bound(&mut self, upper_bound: UserKey<Comp>) {
+    fn set_upper_bound(&mut self, upper_bound: UserKey) {
         debug_assert!(upper_bound.is_owned());
         self.upper_bound.replace(upper_bound);
     }
@@ -399,3 +896,565 @@ impl<'a, Comp: 'static + Comparator> PartitionData<'a, Comp> {
         self.lower_bound.is_some() == self.upper_bound.is_some()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex, RwLock};
+    use std::sync::atomic::AtomicU64;
+    use std::ops::Bound;
+    use std::time::Duration;
+    use crate::merge::MergeOperator;
+    use crate::clock::ManualClock;
+    use crate::compaction::{CompactionFilter, CompactionContext, FilterDecision};
+    use crate::partition::{ArcPartition, Partition, InternalKey, UserKey};
+    use crate::partition::memtable::{Record, RangeTombstone};
+    use crate::table::cache::TableCacheManager;
+    use crate::table::sctable::ScTableFile;
+    use crate::io::IOManager;
+    use crate::error::Error;
+    use crate::family::SnapshotList;
+    use crate::{Options, MutableOptions};
+
+    struct TestEnv {
+        options: Options,
+        mutable_options: RwLock<MutableOptions>,
+        seq: AtomicU64,
+        snapshots: SnapshotList,
+        cache_manager: TableCacheManager,
+        io_manager: IOManager
+    }
+
+    impl TestEnv {
+        fn new(options: Options) -> Self {
+            Self {
+                mutable_options: RwLock::new(MutableOptions::from(&options)),
+                options,
+                seq: AtomicU64::new(0),
+                snapshots: SnapshotList::new(),
+                cache_manager: TableCacheManager::new(4),
+                io_manager: IOManager::new(16, false)
+            }
+        }
+
+        fn test_partition(&self, partition_id: u32) -> ArcPartition<'_> {
+            ArcPartition::new(Partition::new(&self.options, &self.mutable_options, partition_id, &self.seq,
+                                             &self.snapshots, &self.cache_manager, &self.io_manager))
+        }
+
+        fn key(&self, key: &str) -> UserKey {
+            UserKey::new_owned(key.as_bytes().to_vec(), &*self.options.comparator)
+        }
+    }
+
+    fn write(partition: &ArcPartition, seq: u64, key: &str, record: Record) {
+        let key = UserKey::new_owned(key.as_bytes().to_vec(), &*partition.0.options.comparator);
+        partition.write(InternalKey::new(seq, key), record).unwrap();
+    }
+
+    fn put(partition: &ArcPartition, seq: u64, key: &str, value: &str) {
+        write(partition, seq, key, Record::Value(value.as_bytes().to_vec()));
+    }
+
+    fn flush(partition: &ArcPartition) {
+        partition.0.data.lock().unwrap().convert_mem_to_imm();
+        partition.compact_memtable();
+    }
+
+    #[test]
+    fn test_record_larger_than_table() {
+        let mut options = Options::new("test_record_larger_than_table", 4, 4, 10, 16, 4096, 64, 256);
+        options.value_size_max = 8192;
+        let env = TestEnv::new(options);
+        let partition = env.test_partition(31032);
+        let key = UserKey::new_owned("喀秋莎".as_bytes().to_vec(), &*env.options.comparator);
+        assert!(matches!(partition.write(InternalKey::new(1, key.clone()), Record::Value(vec![0; 4096])),
+                         Err(Error::RecordTooLarge { .. })));
+        partition.write(InternalKey::new(2, key), Record::Value(vec![0; 1024])).unwrap();
+    }
+
+    #[test]
+    fn test_range_deletion_across_tables() {
+        let options = Options::new("test_range_deletion_across_tables", 4, 4, 10, 16, 4096, 64, 256);
+        let env = TestEnv::new(options);
+        let partition = env.test_partition(31031);
+
+        put(&partition, 1, "正当梨花开遍了天涯", "Расцветали яблони и груши");
+        put(&partition, 2, "河上飘着柔软的轻纱", "Поплыли туманы над рекой");
+        put(&partition, 3, "喀秋莎站在那俊俏的岸上", "Выходила на берег Катюша");
+        flush(&partition);
+        partition.write_range_deletion(RangeTombstone::new(
+            4,
+            UserKey::new_owned("河".as_bytes().to_vec(), &*env.options.comparator),
+            UserKey::new_owned("洳".as_bytes().to_vec(), &*env.options.comparator))).unwrap();
+        put(&partition, 5, "河上飘着柔软的轻纱", "На высокий берег, на крутой");
+        put(&partition, 3, "河边", "");
+        flush(&partition);
+
+        for &snapshot_seq in [3u64, 5].iter() {
+            let get = |key: &str| partition.get(key.as_bytes(), snapshot_seq).unwrap();
+            assert_eq!(get("正当梨花开遍了天涯").unwrap(), "Расцветали яблони и груши".as_bytes());
+            assert_eq!(get("喀秋莎站在那俊俏的岸上").unwrap(), "Выходила на берег Катюша".as_bytes());
+        }
+        assert_eq!(partition.get("河边".as_bytes(), 5).unwrap(), None);
+        assert_eq!(partition.get("河上飘着柔软的轻纱".as_bytes(), 3).unwrap().unwrap(),
+                   "Поплыли туманы над рекой".as_bytes());
+        assert_eq!(partition.get("河上飘着柔软的轻纱".as_bytes(), 4).unwrap(), None);
+        assert_eq!(partition.get("河上飘着柔软的轻纱".as_bytes(), 5).unwrap().unwrap(),
+                   "На высокий берег, на крутой".as_bytes());
+
+        let scanned = partition.scan(Bound::Unbounded, Bound::Unbounded, 4).unwrap();
+        assert_eq!(scanned.len(), 2);
+
+        for file_number in 1..=2 {
+            std::fs::remove_file(ScTableFile::new(31031, 0, file_number).file_name()).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_range_deletion_kept_for_snapshots() {
+        let options = Options::new("test_range_deletion_kept_for_snapshots", 4, 4, 10, 16, 4096, 64, 256);
+        let env = TestEnv::new(options);
+        let partition = env.test_partition(31036);
+
+        put(&partition, 1, "喀秋莎", "Выходила на берег Катюша");
+        env.snapshots.publish(1);
+        let snapshot = env.snapshots.acquire();
+        partition.write_range_deletion(RangeTombstone::new(2, env.key("喀"), env.key("喀秋莎站"))).unwrap();
+        put(&partition, 3, "河上飘着柔软的轻纱", "Поплыли туманы над рекой");
+        env.snapshots.publish(3);
+        flush(&partition);
+        // the deleted value is kept for the snapshot taken before the deletion
+        assert_eq!(partition.get("喀秋莎".as_bytes(), snapshot.seq()).unwrap().unwrap(),
+                   "Выходила на берег Катюша".as_bytes());
+        assert_eq!(partition.get("喀秋莎".as_bytes(), 3).unwrap(), None);
+
+        drop(snapshot);
+        put(&partition, 4, "河边", "На высокий берег, на крутой");
+        partition.write_range_deletion(RangeTombstone::new(5, env.key("河"), env.key("洳"))).unwrap();
+        env.snapshots.publish(5);
+        flush(&partition);
+        // without older snapshots, covered values get dropped on flush
+        let data = partition.0.data.lock().unwrap();
+        let table = data.tables_newest_first().next().unwrap();
+        assert_eq!(table.get(&InternalKey::new(9, env.key("河边")), &env.cache_manager, &env.io_manager).unwrap(), None);
+        drop(data);
+
+        for file_number in 1..=2 {
+            std::fs::remove_file(ScTableFile::new(31036, 0, file_number).file_name()).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_drop_tables() {
+        let options = Options::new("test_drop_tables", 4, 4, 10, 16, 4096, 64, 256);
+        let env = TestEnv::new(options);
+        let partition = env.test_partition(31038);
+
+        put(&partition, 1, "katyusha/01", "Выходила на берег Катюша");
+        put(&partition, 2, "katyusha/02", "Выходила на берег Катюша");
+        flush(&partition);
+        put(&partition, 3, "katyusha/03", "Выходила на берег Катюша");
+        put(&partition, 4, "katyusha/05", "Выходила на берег Катюша");
+        flush(&partition);
+        put(&partition, 5, "katyusha/06", "Выходила на берег Катюша");
+        flush(&partition);
+
+        assert_eq!(partition.drop_tables("katyusha/03".as_bytes(), "katyusha/06".as_bytes()).unwrap(), 1);
+        assert!(!std::path::Path::new(&ScTableFile::new(31038, 0, 2).file_name()).exists());
+        assert_eq!(partition.get("katyusha/03".as_bytes(), 5).unwrap(), None);
+        assert!(partition.get("katyusha/02".as_bytes(), 5).unwrap().is_some());
+        assert!(partition.get("katyusha/06".as_bytes(), 5).unwrap().is_some());
+
+        assert_eq!(partition.drop_all_tables().unwrap(), 2);
+        assert!(!std::path::Path::new(&ScTableFile::new(31038, 0, 1).file_name()).exists());
+        assert!(!std::path::Path::new(&ScTableFile::new(31038, 0, 3).file_name()).exists());
+    }
+
+    #[test]
+    fn test_leveled_compaction() {
+        let env = TestEnv::new(Options::new("test_leveled_compaction", 4, 4, 10, 16, 4096, 64, 256));
+        let partition = env.test_partition(31039);
+        put(&partition, 1, "katyusha/1", "Расцветали яблони и груши");
+        put(&partition, 2, "katyusha/2", "Поплыли туманы над рекой");
+        put(&partition, 3, "katyusha/3", "Выходила на берег Катюша");
+        flush(&partition);
+        put(&partition, 4, "katyusha/1", "正当梨花开遍了天涯");
+        flush(&partition);
+        write(&partition, 5, "katyusha/2", Record::Deletion);
+        flush(&partition);
+        partition.write_range_deletion(RangeTombstone::new(6, env.key("katyusha/3"), env.key("katyusha/5"))).unwrap();
+        put(&partition, 7, "katyusha/4", "河上飘着柔软的轻纱");
+        env.snapshots.publish(7);
+        flush(&partition);
+
+        // the fourth table of level 0 got all of them merged into level 1, which is the bottom
+        // level, so neither overwritten values nor deletions are kept
+        let data = partition.0.data.lock().unwrap();
+        assert_eq!(data.levels[0].table_count(), 0);
+        assert_eq!(data.levels[1].table_count(), 1);
+        let table = data.tables_newest_first().next().unwrap();
+        let key = |seq, key: &str| InternalKey::new(seq, env.key(key));
+        assert_eq!(table.get(&key(9, "katyusha/1"), &env.cache_manager, &env.io_manager).unwrap(),
+                   Some((4, Record::Value("正当梨花开遍了天涯".as_bytes().to_vec()))));
+        assert_eq!(table.get(&key(3, "katyusha/1"), &env.cache_manager, &env.io_manager).unwrap(), None);
+        assert_eq!(table.get(&key(9, "katyusha/2"), &env.cache_manager, &env.io_manager).unwrap(), None);
+        assert_eq!(table.get(&key(9, "katyusha/3"), &env.cache_manager, &env.io_manager).unwrap(), None);
+        assert_eq!(table.range_deletion_seq(&key(9, "katyusha/3"), &env.cache_manager, &env.io_manager).unwrap(), 0);
+        drop(data);
+
+        assert_eq!(partition.get("katyusha/1".as_bytes(), 9).unwrap().unwrap(), "正当梨花开遍了天涯".as_bytes());
+        assert_eq!(partition.get("katyusha/2".as_bytes(), 9).unwrap(), None);
+        assert_eq!(partition.get("katyusha/3".as_bytes(), 9).unwrap(), None);
+        assert_eq!(partition.get("katyusha/4".as_bytes(), 9).unwrap().unwrap(), "河上飘着柔软的轻纱".as_bytes());
+        for file_number in 1..=4 {
+            assert!(!std::path::Path::new(&ScTableFile::new(31039, 0, file_number).file_name()).exists());
+        }
+        std::fs::remove_file(ScTableFile::new(31039, 1, 1).file_name()).unwrap();
+    }
+
+    #[test]
+    fn test_compaction_split_tables() {
+        let env = TestEnv::new(Options::new("test_compaction_split_tables", 4, 4, 10, 16, 4096, 64, 256));
+        let partition = env.test_partition(31040);
+        let value = "Выходила на берег Катюша".repeat(4);
+        for n in 0..30 {
+            put(&partition, n + 1, &format!("katyusha/{:02}", n), &value);
+            if n % 10 == 9 {
+                flush(&partition);
+            }
+        }
+        env.snapshots.publish(30);
+        let snapshot = env.snapshots.acquire();
+        for n in 30..40 {
+            put(&partition, n + 1, &format!("katyusha/{:02}", n), &value);
+        }
+        partition.write_range_deletion(RangeTombstone::new(41, env.key("katyusha/"), env.key("katyusha/99"))).unwrap();
+        env.snapshots.publish(41);
+        flush(&partition);
+
+        // the range deletion got split along with the values it deletes, which the snapshot
+        // still reads
+        let table_files = {
+            let data = partition.0.data.lock().unwrap();
+            assert_eq!(data.levels[0].table_count(), 0);
+            assert!(data.levels[1].table_count() > 1);
+            data.levels[1].table_files()
+        };
+        assert_eq!(partition.scan(Bound::Unbounded, Bound::Unbounded, snapshot.seq()).unwrap().len(), 30);
+        assert!(partition.scan(Bound::Unbounded, Bound::Unbounded, 41).unwrap().is_empty());
+        assert_eq!(partition.get("katyusha/00".as_bytes(), snapshot.seq()).unwrap().unwrap(), value.as_bytes());
+        for n in 0..40 {
+            assert_eq!(partition.get(format!("katyusha/{:02}", n).as_bytes(), 41).unwrap(), None);
+        }
+
+        for table_file in table_files {
+            std::fs::remove_file(table_file.file_name()).unwrap();
+        }
+    }
+
+    /// Records the output level of each compaction it filters.
+    struct LevelRecorder(Mutex<Vec<usize>>);
+
+    impl CompactionFilter for LevelRecorder {
+        fn name(&self) -> &str {
+            "level_recorder"
+        }
+
+        fn filter(&self, context: &CompactionContext, _key: &[u8], _value: &[u8]) -> FilterDecision {
+            let mut output_levels = self.0.lock().unwrap();
+            if output_levels.last() != Some(&context.output_level) {
+                output_levels.push(context.output_level);
+            }
+            FilterDecision::Keep
+        }
+    }
+
+    #[test]
+    fn test_dynamic_level_compaction() {
+        let recorder = Arc::new(LevelRecorder(Mutex::new(Vec::new())));
+        let mut options = Options::new("test_dynamic_level_compaction", 4, 4, 10, 16, 4096, 64, 256);
+        options.level_base_size = 1000;
+        options.level_dynamic_size = true;
+        options.compaction_filter = Some(recorder.clone());
+        let env = TestEnv::new(options);
+        let partition = env.test_partition(31043);
+        let value = "Выходила на берег Катюша".repeat(4);
+
+        let mut seq = 0;
+        for round in 0..2 {
+            for _ in 0..4 {
+                for _ in 0..3 {
+                    seq += 1;
+                    put(&partition, seq, &format!("katyusha/{}/{:02}", round, seq), &value);
+                }
+                env.snapshots.publish(seq);
+                flush(&partition);
+            }
+            let data = partition.0.data.lock().unwrap();
+            assert_eq!(data.levels[0].table_count(), 0);
+            assert_eq!(data.levels[1].table_count(), 0);
+        }
+        // level 1 overflowed its base size once, after which level 2 became the bottom level with
+        // too little data for a level above it, so level 0 got compacted into level 2 right away
+        assert_eq!(*recorder.0.lock().unwrap(), vec![1, 2]);
+        assert_eq!(partition.scan(Bound::Unbounded, Bound::Unbounded, seq).unwrap().len(), 24);
+
+        let table_files = partition.0.data.lock().unwrap().levels[2].table_files();
+        for table_file in table_files {
+            std::fs::remove_file(table_file.file_name()).unwrap();
+        }
+    }
+
+    struct AppendOperator();
+
+    impl MergeOperator for AppendOperator {
+        fn name(&self) -> &str {
+            "append"
+        }
+
+        fn full_merge(&self, _key: &[u8], existing: Option<&[u8]>, operands: &[&[u8]]) -> Option<Vec<u8>> {
+            let mut value = existing.map_or(Vec::new(), |existing| existing.to_vec());
+            for operand in operands {
+                value.extend_from_slice(operand);
+            }
+            Some(value)
+        }
+    }
+
+    #[test]
+    fn test_merge_across_tables() {
+        let mut options = Options::new("test_merge_across_tables", 4, 4, 10, 16, 4096, 64, 256);
+        options.merge_operator = Some(Arc::new(AppendOperator()));
+        let env = TestEnv::new(options);
+        let partition = env.test_partition(31033);
+
+        env.snapshots.publish(6);
+        write(&partition, 1, "喀秋莎", Record::Value("正当梨花".as_bytes().to_vec()));
+        write(&partition, 2, "喀秋莎", Record::Merge("开遍了天涯".as_bytes().to_vec()));
+        write(&partition, 3, "Катюша", Record::Merge("Выходила".as_bytes().to_vec()));
+        flush(&partition);
+        write(&partition, 4, "Катюша", Record::Merge(" на берег".as_bytes().to_vec()));
+        write(&partition, 5, "喀秋莎", Record::Merge("，河上飘着柔软的轻纱".as_bytes().to_vec()));
+        flush(&partition);
+        write(&partition, 6, "Катюша", Record::Merge(" Катюша".as_bytes().to_vec()));
+
+        // merges upon a base got collapsed on flush, others were kept as operands
+        let data = partition.0.data.lock().unwrap();
+        let table = data.tables_newest_first().last().unwrap();
+        let key = InternalKey::new(9, UserKey::new_borrow("喀秋莎".as_bytes(), &*env.options.comparator));
+        assert_eq!(table.get(&key, &env.cache_manager, &env.io_manager).unwrap(),
+                   Some((2, Record::Value("正当梨花开遍了天涯".as_bytes().to_vec()))));
+        drop(data);
+
+        assert_eq!(partition.get("喀秋莎".as_bytes(), 9).unwrap().unwrap(),
+                   "正当梨花开遍了天涯，河上飘着柔软的轻纱".as_bytes());
+        assert_eq!(partition.get("Катюша".as_bytes(), 9).unwrap().unwrap(),
+                   "Выходила на берег Катюша".as_bytes());
+        assert_eq!(partition.get("Катюша".as_bytes(), 4).unwrap().unwrap(), "Выходила на берег".as_bytes());
+        let scanned = partition.scan(Bound::Unbounded, Bound::Unbounded, 5).unwrap();
+        assert_eq!(scanned, vec![("Катюша".as_bytes().to_vec(), "Выходила на берег".as_bytes().to_vec()),
+                                 ("喀秋莎".as_bytes().to_vec(), "正当梨花开遍了天涯，河上飘着柔软的轻纱".as_bytes().to_vec())]);
+
+        for file_number in 1..=2 {
+            std::fs::remove_file(ScTableFile::new(31033, 0, file_number).file_name()).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_merge_kept_for_snapshots() {
+        let mut options = Options::new("test_merge_kept_for_snapshots", 4, 4, 10, 16, 4096, 64, 256);
+        options.merge_operator = Some(Arc::new(AppendOperator()));
+        let env = TestEnv::new(options);
+        let partition = env.test_partition(31037);
+
+        write(&partition, 1, "喀秋莎", Record::Value("正当梨花".as_bytes().to_vec()));
+        write(&partition, 2, "喀秋莎", Record::Merge("开遍了天涯".as_bytes().to_vec()));
+        env.snapshots.publish(2);
+        let snapshot = env.snapshots.acquire();
+        write(&partition, 3, "喀秋莎", Record::Merge("，河上飘着柔软的轻纱".as_bytes().to_vec()));
+        env.snapshots.publish(3);
+        flush(&partition);
+        drop(snapshot);
+
+        // only the operand the snapshot reads got collapsed, the newer one was kept
+        let data = partition.0.data.lock().unwrap();
+        let table = data.tables_newest_first().next().unwrap();
+        let key = |seq| InternalKey::new(seq, UserKey::new_borrow("喀秋莎".as_bytes(), &*env.options.comparator));
+        assert_eq!(table.get(&key(9), &env.cache_manager, &env.io_manager).unwrap(),
+                   Some((3, Record::Merge("，河上飘着柔软的轻纱".as_bytes().to_vec()))));
+        assert_eq!(table.get(&key(2), &env.cache_manager, &env.io_manager).unwrap(),
+                   Some((2, Record::Value("正当梨花开遍了天涯".as_bytes().to_vec()))));
+        drop(data);
+        assert_eq!(partition.get("喀秋莎".as_bytes(), 2).unwrap().unwrap(), "正当梨花开遍了天涯".as_bytes());
+        assert_eq!(partition.get("喀秋莎".as_bytes(), 3).unwrap().unwrap(),
+                   "正当梨花开遍了天涯，河上飘着柔软的轻纱".as_bytes());
+
+        std::fs::remove_file(ScTableFile::new(31037, 0, 1).file_name()).unwrap();
+    }
+
+    struct SessionFilter();
+
+    impl CompactionFilter for SessionFilter {
+        fn name(&self) -> &str {
+            "session"
+        }
+
+        fn filter(&self, context: &CompactionContext, key: &[u8], value: &[u8]) -> FilterDecision {
+            assert_eq!(*context, CompactionContext { partition_id: 31034, output_level: 0, is_flush: true });
+            if key.starts_with(b"expired/") {
+                FilterDecision::Remove
+            } else if value.starts_with(b"legacy:") {
+                FilterDecision::ChangeValue(value[7..].to_vec())
+            } else {
+                FilterDecision::Keep
+            }
+        }
+
+        fn filter_flush(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn test_compaction_filter_on_flush() {
+        let mut options = Options::new("test_compaction_filter_on_flush", 4, 4, 10, 16, 4096, 64, 256);
+        options.compaction_filter = Some(Arc::new(SessionFilter()));
+        let env = TestEnv::new(options);
+        let partition = env.test_partition(31034);
+
+        put(&partition, 1, "active/katyusha", "Выходила на берег Катюша");
+        put(&partition, 2, "expired/katyusha", "legacy:Расцветали яблони и груши");
+        put(&partition, 3, "legacy/katyusha", "legacy:喀秋莎站在那俊俏的岸上");
+        flush(&partition);
+        assert_eq!(partition.get("active/katyusha".as_bytes(), 9).unwrap().unwrap(),
+                   "Выходила на берег Катюша".as_bytes());
+        assert_eq!(partition.get("expired/katyusha".as_bytes(), 9).unwrap(), None);
+        assert_eq!(partition.get("legacy/katyusha".as_bytes(), 9).unwrap().unwrap(),
+                   "喀秋莎站在那俊俏的岸上".as_bytes());
+
+        std::fs::remove_file(ScTableFile::new(31034, 0, 1).file_name()).unwrap();
+    }
+
+    struct ExpiredFilter();
+
+    impl CompactionFilter for ExpiredFilter {
+        fn name(&self) -> &str {
+            "expired"
+        }
+
+        fn filter(&self, context: &CompactionContext, key: &[u8], _value: &[u8]) -> FilterDecision {
+            assert_eq!(*context, CompactionContext { partition_id: 31041, output_level: 1, is_flush: false });
+            if key.starts_with(b"expired/") {
+                FilterDecision::Remove
+            } else {
+                FilterDecision::Keep
+            }
+        }
+    }
+
+    #[test]
+    fn test_compaction_filter_on_compaction() {
+        let mut options = Options::new("test_compaction_filter_on_compaction", 4, 4, 10, 16, 4096, 64, 256);
+        options.compaction_filter = Some(Arc::new(ExpiredFilter()));
+        let env = TestEnv::new(options);
+        let partition = env.test_partition(31041);
+
+        put(&partition, 1, "active/katyusha", "Выходила на берег Катюша");
+        put(&partition, 2, "expired/katyusha", "Расцветали яблони и груши");
+        flush(&partition);
+        // flushes are not filtered without `filter_flush`
+        assert!(partition.get("expired/katyusha".as_bytes(), 9).unwrap().is_some());
+        put(&partition, 3, "active/喀秋莎", "正当梨花开遍了天涯");
+        flush(&partition);
+        put(&partition, 4, "expired/喀秋莎", "河上飘着柔软的轻纱");
+        flush(&partition);
+        put(&partition, 5, "active/Катюша", "Поплыли туманы над рекой");
+        env.snapshots.publish(5);
+        flush(&partition);
+
+        let data = partition.0.data.lock().unwrap();
+        assert_eq!(data.levels[0].table_count(), 0);
+        let table = data.tables_newest_first().next().unwrap();
+        let key = |key: &str| InternalKey::new(9, env.key(key));
+        for expired in ["expired/katyusha", "expired/喀秋莎"].iter() {
+            assert_eq!(table.get(&key(expired), &env.cache_manager, &env.io_manager).unwrap(), None);
+        }
+        assert_eq!(table.get(&key("active/katyusha"), &env.cache_manager, &env.io_manager).unwrap(),
+                   Some((1, Record::Value("Выходила на берег Катюша".as_bytes().to_vec()))));
+        drop(data);
+        assert_eq!(partition.get("expired/katyusha".as_bytes(), 9).unwrap(), None);
+        assert_eq!(partition.scan(Bound::Unbounded, Bound::Unbounded, 9).unwrap().len(), 3);
+
+        std::fs::remove_file(ScTableFile::new(31041, 1, 1).file_name()).unwrap();
+    }
+
+    #[test]
+    fn test_expiry_on_flush() {
+        let clock = Arc::new(ManualClock::new(1_000_000));
+        let mut options = Options::new("test_expiry_on_flush", 4, 4, 10, 16, 4096, 64, 256);
+        options.clock = clock.clone();
+        let env = TestEnv::new(options);
+        let partition = env.test_partition(31035);
+
+        write(&partition, 1, "喀秋莎", Record::Value("Выходила на берег Катюша".as_bytes().to_vec()));
+        flush(&partition);
+        write(&partition, 2, "喀秋莎", Record::Expiring("正当梨花开遍了天涯".as_bytes().to_vec(), 1_010_000));
+        write(&partition, 3, "Катюша", Record::Expiring("河上飘着柔软的轻纱".as_bytes().to_vec(), 1_060_000));
+        clock.advance(Duration::from_secs(10));
+        flush(&partition);
+
+        // the expired value got replaced by a deletion, which still hides the older value
+        let data = partition.0.data.lock().unwrap();
+        let table = data.tables_newest_first().next().unwrap();
+        let key = |key: &str| InternalKey::new(9, env.key(key));
+        assert_eq!(table.get(&key("喀秋莎"), &env.cache_manager, &env.io_manager).unwrap(), Some((2, Record::Deletion)));
+        assert_eq!(table.get(&key("Катюша"), &env.cache_manager, &env.io_manager).unwrap(),
+                   Some((3, Record::Expiring("河上飘着柔软的轻纱".as_bytes().to_vec(), 1_060_000))));
+        drop(data);
+
+        assert_eq!(partition.get("喀秋莎".as_bytes(), 9).unwrap(), None);
+        assert_eq!(partition.get("Катюша".as_bytes(), 9).unwrap().unwrap(), "河上飘着柔软的轻纱".as_bytes());
+        clock.advance(Duration::from_secs(50));
+        assert_eq!(partition.get("Катюша".as_bytes(), 9).unwrap(), None);
+        assert!(partition.scan(Bound::Unbounded, Bound::Unbounded, 9).unwrap().is_empty());
+
+        for file_number in 1..=2 {
+            std::fs::remove_file(ScTableFile::new(31035, 0, file_number).file_name()).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_expiry_on_compaction() {
+        let clock = Arc::new(ManualClock::new(1_000_000));
+        let mut options = Options::new("test_expiry_on_compaction", 4, 4, 10, 16, 4096, 64, 256);
+        options.clock = clock.clone();
+        let env = TestEnv::new(options);
+        let partition = env.test_partition(31042);
+
+        put(&partition, 1, "喀秋莎", "Выходила на берег Катюша");
+        flush(&partition);
+        write(&partition, 2, "喀秋莎", Record::Expiring("正当梨花开遍了天涯".as_bytes().to_vec(), 1_010_000));
+        flush(&partition);
+        write(&partition, 3, "Катюша", Record::Expiring("河上飘着柔软的轻纱".as_bytes().to_vec(), 1_060_000));
+        flush(&partition);
+        clock.advance(Duration::from_secs(10));
+        put(&partition, 4, "katyusha", "Расцветали яблони и груши");
+        env.snapshots.publish(4);
+        flush(&partition);
+
+        // the expired value is gone from the bottom level, along with the value it overwrote
+        let data = partition.0.data.lock().unwrap();
+        assert_eq!(data.levels[0].table_count(), 0);
+        let table = data.tables_newest_first().next().unwrap();
+        let key = |seq, key: &str| InternalKey::new(seq, env.key(key));
+        assert_eq!(table.get(&key(9, "喀秋莎"), &env.cache_manager, &env.io_manager).unwrap(), None);
+        assert_eq!(table.get(&key(1, "喀秋莎"), &env.cache_manager, &env.io_manager).unwrap(), None);
+        assert_eq!(table.get(&key(9, "Катюша"), &env.cache_manager, &env.io_manager).unwrap(),
+                   Some((3, Record::Expiring("河上飘着柔软的轻纱".as_bytes().to_vec(), 1_060_000))));
+        drop(data);
+        assert_eq!(partition.get("喀秋莎".as_bytes(), 9).unwrap(), None);
+        assert_eq!(partition.get("Катюша".as_bytes(), 9).unwrap().unwrap(), "河上飘着柔软的轻纱".as_bytes());
+
+        std::fs::remove_file(ScTableFile::new(31042, 1, 1).file_name()).unwrap();
+    }
+}
@@ -1,12 +1,21 @@
 use crc::crc32;
 
-use crate::table::tablefmt::{TABLE_MAGIC, TABLE_MIN_SIZE, TABLE_CATALOG_ITEM_SIZE};
-use crate::encode::{encode_fixed32_ret, encode_fixed32};
+use crate::table::tablefmt::{TABLE_EMPTY_SIZE, TABLE_CATALOG_ITEM_SIZE, TABLE_DELETION_BITMASK,
+                             TABLE_MERGE_BITMASK, TABLE_FLAG_PREFIX_DELTA,
+                             TABLE_FORMAT_VERSION};
+use crate::encode::{encode_fixed32_ret, encode_fixed32, encode_fixed64_ret};
 use crate::table::cache::ScTableCatalogItem;
+use crate::table::prefix;
+use crate::table::version::encode_footer;
+use crate::compress::{CompressionType, compress_table};
+use crate::KeyEncoding;
 
 pub(crate) struct ScTableBuilder {
     indexes: Vec<ScTableCatalogItem>,
-    data: Vec<u8>
+    data: Vec<u8>,
+    range_deletions: Vec<u8>,
+    key_encoding: KeyEncoding,
+    compression: CompressionType
 }
 
 impl Default for ScTableBuilder {
@@ -17,7 +26,11 @@ impl Default for ScTableBuilder {
 
 impl ScTableBuilder {
     pub(crate) fn new() -> Self {
-        Self { indexes: Vec::new(), data: Vec::new() }
+        Self::with_format(KeyEncoding::Plain, CompressionType::None)
+    }
+
+    pub(crate) fn with_format(key_encoding: KeyEncoding, compression: CompressionType) -> Self {
+        Self { indexes: Vec::new(), data: Vec::new(), range_deletions: Vec::new(), key_encoding, compression }
     }
 
     pub(crate) fn add_kv(&mut self, key_seq: u64, key: &[u8], value: &[u8]) {
@@ -32,7 +45,61 @@ impl ScTableBuilder {
         self.indexes.push(ScTableCatalogItem::new(key_seq, key_off, key_size, value_off, value_size));
     }
 
+    pub(crate) fn add_deletion(&mut self, key_seq: u64, key: &[u8]) {
+        let key_off = self.data.len() as u32;
+        let key_size = key.len() as u32;
+        self.data.extend_from_slice(key);
+
+        let value_off = self.data.len() as u32 | TABLE_DELETION_BITMASK;
+        self.indexes.push(ScTableCatalogItem::new(key_seq, key_off, key_size, value_off, 0));
+    }
+
+    pub(crate) fn add_merge(&mut self, key_seq: u64, key: &[u8], operand: &[u8]) {
+        self.add_kv(key_seq | TABLE_MERGE_BITMASK, key, operand)
+    }
+
+    /// Adds a value which expires at `expire_at`, in milliseconds since the Unix epoch.
+    pub(crate) fn add_expiring(&mut self, key_seq: u64, key: &[u8], value: &[u8], expire_at: u64) {
+        let mut expiring = encode_fixed64_ret(expire_at).to_vec();
+        expiring.extend_from_slice(value);
+        self.add_kv(key_seq, key, &expiring);
+        self.indexes.last_mut().unwrap().expiring = true;
+    }
+
+    pub(crate) fn add_range_deletion(&mut self, seq: u64, start: &[u8], end: &[u8]) {
+        self.range_deletions.extend_from_slice(&encode_fixed64_ret(seq));
+        self.range_deletions.extend_from_slice(&encode_fixed32_ret(start.len() as u32));
+        self.range_deletions.extend_from_slice(&encode_fixed32_ret(end.len() as u32));
+        self.range_deletions.extend_from_slice(start);
+        self.range_deletions.extend_from_slice(end);
+    }
+
     pub(crate) fn build(&self) -> Vec<u8> {
+        let (mut table, flags) = match self.key_encoding {
+            KeyEncoding::Plain => (self.build_plain(), 0),
+            KeyEncoding::PrefixDelta { restart_interval } =>
+                (prefix::build(&self.indexes, &self.data, restart_interval, TABLE_FORMAT_VERSION),
+                 TABLE_FLAG_PREFIX_DELTA)
+        };
+        table.extend_from_slice(&self.range_deletions);
+        table.extend_from_slice(&encode_fixed32_ret(self.range_deletions.len() as u32));
+        table.extend_from_slice(&encode_fixed32_ret(crc32::checksum_ieee(&self.range_deletions)));
+        encode_footer(&mut table, flags);
+        compress_table(table, self.compression)
+    }
+
+    /// Size of the table before compression
+    pub(crate) fn size(&self) -> usize {
+        let body_size = match self.key_encoding {
+            KeyEncoding::Plain =>
+                self.indexes.len() * TABLE_CATALOG_ITEM_SIZE + self.data.len(),
+            KeyEncoding::PrefixDelta { restart_interval } =>
+                prefix::encoded_size(&self.indexes, &self.data, restart_interval, TABLE_FORMAT_VERSION)
+        };
+        TABLE_EMPTY_SIZE + body_size + self.range_deletions.len()
+    }
+
+    fn build_plain(&self) -> Vec<u8> {
         let mut ret = Vec::with_capacity(self.size());
         ret.extend_from_slice(&encode_fixed32_ret((self.indexes.len() * TABLE_CATALOG_ITEM_SIZE) as u32));
         ret.extend_from_slice(&encode_fixed32_ret(self.data.len() as u32));
@@ -46,19 +113,16 @@ impl ScTableBuilder {
         ret.extend_from_slice(&self.data);
         let index_checksum = crc32::checksum_ieee(&ret[16..16 + self.indexes.len() * TABLE_CATALOG_ITEM_SIZE]);
         encode_fixed32(&mut ret[8..12], index_checksum);
-        ret.extend_from_slice(TABLE_MAGIC);
         ret
     }
-
-    pub(crate) fn size(&self) -> usize {
-        TABLE_MIN_SIZE + self.indexes.len() * TABLE_CATALOG_ITEM_SIZE + self.data.len()
-    }
 }
 
 #[cfg(test)]
 mod test {
     use crate::table::builder::ScTableBuilder;
     use crate::table::cache::{ScTableCache, TableCacheManager};
+    use crate::compress::CompressionType;
+    use crate::KeyEncoding;
 
     #[test]
     fn test_builder_1() {
@@ -90,4 +154,71 @@ mod test {
             assert_eq!(value1, value);
         }
     }
+
+    #[test]
+    fn test_builder_prefix() {
+        let data = [
+            (0x40490fd0fffffffeu64, "tenant/0001/session/0001".as_bytes(), "alive".as_bytes()),
+            (0x40490fd0fffffffeu64, "tenant/0001/session/0002".as_bytes(), "alive".as_bytes()),
+            (0x40490fd0ffffffffu64, "tenant/0001/session/0002".as_bytes(), "".as_bytes()),
+            (0x40490fd0fffffffeu64, "tenant/0001/session/0003".as_bytes(), "expired".as_bytes()),
+            (0x40490fd0fffffffeu64, "tenant/0002".as_bytes(), "".as_bytes()),
+            (0x40490fd0fffffffeu64, "tenant/0002/session/0001".as_bytes(), "alive".as_bytes()),
+            (0x40490fd0fffffffeu64, "tenant/0003/session/0001/ext".as_bytes(), "alive".as_bytes()),
+            (0x40490fd0fffffffeu64, "黑暗的中世纪".as_bytes(), "".as_bytes()),
+        ];
+
+        let mut plain_builder = ScTableBuilder::new();
+        for &(seq, key, value) in data.iter() {
+            plain_builder.add_kv(seq, key, value);
+        }
+
+        for &restart_interval in [1, 3, 16].iter() {
+            let mut builder =
+                ScTableBuilder::with_format(KeyEncoding::PrefixDelta { restart_interval }, CompressionType::None);
+            for &(seq, key, value) in data.iter() {
+                builder.add_kv(seq, key, value);
+            }
+            let buffer = builder.build();
+            assert_eq!(buffer.len(), builder.size());
+            assert!(buffer.len() < plain_builder.size());
+
+            let cache_manager = TableCacheManager::new(1);
+            let quota = cache_manager.acquire_quota();
+            let table = ScTableCache::from_raw(&buffer, quota).unwrap();
+            assert_eq!(table.catalog_size(), data.len());
+            for (i, &(seq, key, value)) in data.iter().enumerate() {
+                let (seq1, key1, value1) = table.nth_item(i);
+                assert_eq!(seq1, seq);
+                assert_eq!(key1, key);
+                assert_eq!(value1, value);
+            }
+        }
+    }
+
+    #[test]
+    fn test_builder_compressed() {
+        let lyrics = "Расцветали яблони и груши, Поплыли туманы над рекой".repeat(8);
+        for &key_encoding in [KeyEncoding::Plain, KeyEncoding::PrefixDelta { restart_interval: 4 }].iter() {
+            for &compression in [CompressionType::Snappy, CompressionType::Zlib].iter() {
+                let mut builder = ScTableBuilder::with_format(key_encoding, compression);
+                for i in 0..64u64 {
+                    builder.add_kv(i, format!("katyusha/{:04}", i).as_bytes(), lyrics.as_bytes());
+                }
+                let buffer = builder.build();
+                assert!(buffer.len() < builder.size());
+
+                let cache_manager = TableCacheManager::new(1);
+                let quota = cache_manager.acquire_quota();
+                let table = ScTableCache::from_raw(&buffer, quota).unwrap();
+                assert_eq!(table.catalog_size(), 64);
+                for i in 0..64u64 {
+                    let (seq, key, value) = table.nth_item(i as usize);
+                    assert_eq!(seq, i);
+                    assert_eq!(key, format!("katyusha/{:04}", i).as_bytes());
+                    assert_eq!(value, lyrics.as_bytes());
+                }
+            }
+        }
+    }
 }
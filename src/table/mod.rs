@@ -3,21 +3,42 @@ pub(crate) mod builder;
 pub(crate) mod cache;
 pub(crate) mod sctable;
 pub(crate) mod scsplit;
+pub(crate) mod prefix;
+pub(crate) mod version;
+pub(crate) mod upgrade;
 
 use std::cmp::Ordering;
+use std::ops::Bound;
 
-use crate::{Comparator, error};
+use crate::error;
 use crate::io::IOManager;
 use crate::table::cache::TableCacheManager;
+use crate::table::sctable::ScTableFile;
 use crate::partition::{InternalKey, UserKey};
+use crate::partition::memtable::{Record, RangeTombstone};
 
-pub(crate) trait Table<Comp: Comparator> {
+/// Records and range tombstones read out of a table
+pub(crate) type TableScan = (Vec<(InternalKey, Record)>, Vec<RangeTombstone>);
+
+pub(crate) trait Table {
     fn get<'a>(&self,
-               key: &InternalKey<Comp>,
+               key: &InternalKey,
                cache_manager: &'a TableCacheManager,
-               io_manager: &'a IOManager) -> Result<Option<Vec<u8>>, error::Error>;
+               io_manager: &'a IOManager) -> Result<Option<(u64, Record)>, error::Error>;
+
+    fn range_deletion_seq<'a>(&self,
+                              key: &InternalKey,
+                              cache_manager: &'a TableCacheManager,
+                              io_manager: &'a IOManager) -> Result<u64, error::Error>;
 
-    fn cmp_key(&self, key: &UserKey<Comp>) -> Ordering {
+    /// Reads all records with user keys in the given bounds, and all range tombstones.
+    fn scan<'a>(&self,
+                lower: Bound<&[u8]>,
+                upper: Bound<&[u8]>,
+                cache_manager: &'a TableCacheManager,
+                io_manager: &'a IOManager) -> Result<TableScan, error::Error>;
+
+    fn cmp_key(&self, key: &UserKey) -> Ordering {
         if key.cmp(self.lower_bound()) == Ordering::Less {
             Ordering::Less
         } else if key.cmp(self.upper_bound()) == Ordering::Greater {
@@ -27,9 +48,11 @@ pub(crate) trait Table<Comp: Comparator> {
         }
     }
 
-    fn lower_bound(&self) -> &UserKey<Comp>;
+    fn lower_bound(&self) -> &UserKey;
 
-    fn upper_bound(&self) -> &UserKey<Comp>;
+    fn upper_bound(&self) -> &UserKey;
 
     fn is_lazy(&self) -> bool;
+
+    fn file(&self) -> ScTableFile;
 }
@@ -0,0 +1,227 @@
+use crc::crc32;
+
+use crate::table::tablefmt::{TABLE_HEAD_SIZE, TABLE_DELETION_BITMASK, TABLE_RESTART_SIZE, TABLE_MAX_SIZE,
+                             TABLE_MAX_DATA_SIZE};
+use crate::table::cache::ScTableCatalogItem;
+use crate::encode::{encode_fixed32_ret, encode_fixed32, decode_fixed32, encode_fixed64_ret, decode_fixed64};
+use crate::encode::varint::{put_varint32, decode_varint32, varint32_len};
+use crate::error::Error;
+
+/// Builds the header, catalog and data sections of a prefix compressed table in format `version`
+pub(crate) fn build(indexes: &[ScTableCatalogItem], data: &[u8], restart_interval: usize, version: u32) -> Vec<u8> {
+    let restart_interval = restart_interval.max(1);
+    let mut catalog = Vec::new();
+    let mut delta = Vec::new();
+    let mut restarts = Vec::new();
+    let mut last_key: &[u8] = &[];
+    for (i, index) in indexes.iter().enumerate() {
+        let key = index.key(data);
+        let shared = if i % restart_interval == 0 {
+            restarts.push(catalog.len() as u32);
+            0
+        } else {
+            shared_prefix_len(last_key, key)
+        };
+        let value = index.value(data);
+
+        catalog.extend_from_slice(&encode_fixed64_ret(index.key_seq));
+        put_varint32(&mut catalog, shared as u32);
+        put_varint32(&mut catalog, (key.len() - shared) as u32);
+        put_varint32(&mut catalog, value_field(index, version));
+
+        delta.extend_from_slice(&key[shared..]);
+        delta.extend_from_slice(value);
+        last_key = key;
+    }
+    for restart in restarts.iter() {
+        catalog.extend_from_slice(&encode_fixed32_ret(*restart));
+    }
+    catalog.extend_from_slice(&encode_fixed32_ret(restarts.len() as u32));
+
+    let mut ret = Vec::with_capacity(TABLE_HEAD_SIZE + catalog.len() + delta.len());
+    ret.extend_from_slice(&encode_fixed32_ret(catalog.len() as u32));
+    ret.extend_from_slice(&encode_fixed32_ret(delta.len() as u32));
+    for _ in 0..4 {
+        ret.push(0)
+    }
+    ret.extend_from_slice(&encode_fixed32_ret(crc32::checksum_ieee(&delta)));
+    ret.extend_from_slice(&catalog);
+    ret.extend_from_slice(&delta);
+    encode_fixed32(&mut ret[8..12], crc32::checksum_ieee(&catalog));
+    ret
+}
+
+/// Size of the catalog and data sections
+pub(crate) fn encoded_size(indexes: &[ScTableCatalogItem],
+                           data: &[u8],
+                           restart_interval: usize,
+                           version: u32) -> usize {
+    let restart_interval = restart_interval.max(1);
+    let mut size = TABLE_RESTART_SIZE;
+    let mut last_key: &[u8] = &[];
+    for (i, index) in indexes.iter().enumerate() {
+        let key = index.key(data);
+        let shared = if i % restart_interval == 0 {
+            size += TABLE_RESTART_SIZE;
+            0
+        } else {
+            shared_prefix_len(last_key, key)
+        };
+        size += 8
+            + varint32_len(shared as u32)
+            + varint32_len((key.len() - shared) as u32)
+            + varint32_len(value_field(index, version))
+            + key.len() - shared
+            + index.value(data).len();
+        last_key = key;
+    }
+    size
+}
+
+/// Decodes the catalog and data sections of a prefix compressed table, reconstructing full keys.
+/// The returned catalog items refer to the returned data buffer, just like a plain table, thus
+/// decoded tables are no smaller in memory. Restart points are validated but not used to seek.
+/// Value fields carry an expiring bit since table format version 4.
+pub(crate) fn decode(catalog: &[u8],
+                     data: &[u8],
+                     version: u32) -> Result<(Vec<ScTableCatalogItem>, Vec<u8>), Error> {
+    let flag_bits = if version >= 4 { 2 } else { 1 };
+    // value offsets of version 4 tables share their word with the expiring bit
+    let max_size = if version >= 4 { TABLE_MAX_DATA_SIZE } else { TABLE_MAX_SIZE };
+    if catalog.len() < TABLE_RESTART_SIZE {
+        return Err(Error::sc_table_corrupt(
+            format!("catalog size {} too small to hold restart count", catalog.len()).into()))
+    }
+    let restart_count = decode_fixed32(&catalog[catalog.len() - TABLE_RESTART_SIZE..]) as usize;
+    let entries_size = restart_count.checked_mul(TABLE_RESTART_SIZE)
+        .and_then(|restarts_size| (catalog.len() - TABLE_RESTART_SIZE).checked_sub(restarts_size))
+        .ok_or_else(|| Error::sc_table_corrupt(
+            format!("restart count {} exceeds catalog size {}", restart_count, catalog.len()).into()))?;
+    let entries = &catalog[..entries_size];
+    let restarts = &catalog[entries_size..catalog.len() - TABLE_RESTART_SIZE];
+
+    let mut items = Vec::new();
+    let mut out = Vec::with_capacity(data.len());
+    let mut catalog_pos = 0;
+    let mut data_pos = 0;
+    let mut next_restart = 0;
+    let mut last_key_off = 0;
+    let mut last_key_len = 0;
+    while catalog_pos < entries.len() {
+        let is_restart = next_restart < restart_count
+            && decode_fixed32(&restarts[next_restart * TABLE_RESTART_SIZE
+                                        ..(next_restart + 1) * TABLE_RESTART_SIZE]) as usize == catalog_pos;
+        if is_restart {
+            next_restart += 1;
+        }
+
+        if entries.len() - catalog_pos < 8 {
+            return Err(Error::sc_table_corrupt(
+                format!("catalog item {}: truncated at catalog offset {}", items.len(), catalog_pos).into()))
+        }
+        let key_seq = decode_fixed64(&entries[catalog_pos..catalog_pos + 8]);
+        catalog_pos += 8;
+        let shared = get_varint32(entries, &mut catalog_pos)? as usize;
+        let unshared = get_varint32(entries, &mut catalog_pos)? as usize;
+        let value_field = get_varint32(entries, &mut catalog_pos)?;
+        let value_len = (value_field >> flag_bits) as usize;
+        let expiring = version >= 4 && value_field & 2 != 0;
+
+        if shared > last_key_len || (is_restart && shared != 0) {
+            return Err(Error::sc_table_corrupt(
+                format!("catalog item {}: shared key length {} with previous key length {}{}",
+                        items.len(), shared, last_key_len,
+                        if is_restart { " at restart point" } else { "" }).into()))
+        }
+        if data.len() - data_pos < unshared || data.len() - data_pos - unshared < value_len {
+            return Err(Error::sc_table_corrupt(
+                format!("catalog item {}: key of {} bytes and value of {} bytes at data offset {} \
+                         exceed data size {}", items.len(), unshared, value_len, data_pos, data.len()).into()))
+        }
+        if value_field & 1 != 0 && value_len != 0 {
+            return Err(Error::sc_table_corrupt(
+                format!("catalog item {}: deletion carries a value of {} bytes", items.len(), value_len).into()))
+        }
+        if out.len() + shared + unshared + value_len > max_size {
+            return Err(Error::sc_table_corrupt(
+                format!("catalog item {}: decoded table exceeds {} bytes", items.len(), max_size).into()))
+        }
+
+        let key_off = out.len();
+        out.extend_from_within(last_key_off..last_key_off + shared);
+        out.extend_from_slice(&data[data_pos..data_pos + unshared]);
+        data_pos += unshared;
+        let value_off = out.len();
+        out.extend_from_slice(&data[data_pos..data_pos + value_len]);
+        data_pos += value_len;
+
+        let value_off = if value_field & 1 != 0 {
+            value_off as u32 | TABLE_DELETION_BITMASK
+        } else {
+            value_off as u32
+        };
+        let mut item = ScTableCatalogItem::new(key_seq, key_off as u32, (shared + unshared) as u32,
+                                               value_off, value_len as u32);
+        item.expiring = expiring;
+        items.push(item);
+        last_key_off = key_off;
+        last_key_len = shared + unshared;
+    }
+
+    if next_restart != restart_count {
+        return Err(Error::sc_table_corrupt(
+            format!("restart point {} does not point to a catalog item", next_restart).into()))
+    }
+    if data_pos != data.len() {
+        return Err(Error::sc_table_corrupt(
+            format!("{} trailing bytes in data", data.len() - data_pos).into()))
+    }
+    Ok((items, out))
+}
+
+/// Recovers the restart interval a catalog was built with, from the position of its second restart
+/// point. The catalog must have been validated by `decode`.
+pub(crate) fn restart_interval(catalog: &[u8]) -> usize {
+    let restart_count = decode_fixed32(&catalog[catalog.len() - TABLE_RESTART_SIZE..]) as usize;
+    let entries_size = catalog.len() - TABLE_RESTART_SIZE - restart_count * TABLE_RESTART_SIZE;
+    let second_restart = if restart_count >= 2 {
+        Some(decode_fixed32(&catalog[entries_size + TABLE_RESTART_SIZE..entries_size + 2 * TABLE_RESTART_SIZE])
+            as usize)
+    } else {
+        None
+    };
+
+    let mut catalog_pos = 0;
+    let mut item_count = 0;
+    while catalog_pos < entries_size {
+        if second_restart == Some(catalog_pos) {
+            return item_count
+        }
+        catalog_pos += 8;
+        for _ in 0..3 {
+            catalog_pos += decode_varint32(&catalog[catalog_pos..entries_size]).map_or(entries_size, |(_, len)| len);
+        }
+        item_count += 1;
+    }
+    item_count.max(1)
+}
+
+fn value_field(index: &ScTableCatalogItem, version: u32) -> u32 {
+    if version >= 4 {
+        (index.value_len << 2) | (index.is_expiring() as u32) << 1 | index.is_deletion() as u32
+    } else {
+        (index.value_len << 1) | index.is_deletion() as u32
+    }
+}
+
+fn shared_prefix_len(lhs: &[u8], rhs: &[u8]) -> usize {
+    lhs.iter().zip(rhs.iter()).take_while(|(l, r)| l == r).count()
+}
+
+fn get_varint32(src: &[u8], pos: &mut usize) -> Result<u32, Error> {
+    let (num, len) = decode_varint32(&src[(*pos).min(src.len())..])
+        .ok_or_else(|| Error::sc_table_corrupt(
+            format!("truncated or overflowing varint at catalog offset {}", *pos).into()))?;
+    *pos += len;
+    Ok(num)
+}
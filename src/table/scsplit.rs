@@ -1,38 +1,138 @@
-use crate::table::sctable::ScTableFile;
-use crate::Comparator;
+use std::cmp::Ordering;
+use std::ops::Bound;
+use std::sync::Arc;
+
+use crate::table::sctable::{ScTableFile, load_table, collect_scan, in_bounds};
 use crate::table::Table;
-use crate::table::cache::TableCacheManager;
+use crate::table::cache::{TableCacheManager, ScTableCache};
 use crate::io::IOManager;
 use crate::error;
 use crate::partition::{InternalKey, UserKey};
+use crate::partition::memtable::Record;
+use crate::table::TableScan;
 
-pub(crate) struct ScSplit<Comp: Comparator> {
+/// The catalog items `first_kv_index..=last_kv_index` of a table file, which hold the user keys
+/// within `[lower_bound, upper_bound]`.
+pub(crate) struct ScSplit {
     file: ScTableFile,
 
     first_kv_index: u32,
     last_kv_index: u32,
 
-    lower_bound: UserKey<Comp>,
-    upper_bound: UserKey<Comp>
+    lower_bound: UserKey,
+    upper_bound: UserKey
+}
+
+impl ScSplit {
+    pub(crate) fn new(file: ScTableFile,
+                      first_kv_index: u32,
+                      last_kv_index: u32,
+                      lower_bound: UserKey,
+                      upper_bound: UserKey) -> Self {
+        Self { file, first_kv_index, last_kv_index, lower_bound, upper_bound }
+    }
+
+    fn load(&self, cache_manager: &TableCacheManager, io_manager: &IOManager)
+            -> Result<Arc<ScTableCache>, error::Error> {
+        load_table(self.file, self.lower_bound.comparator(), cache_manager, io_manager)
+    }
 }
 
-impl<Comp: Comparator> Table<Comp> for ScSplit<Comp> {
+impl Table for ScSplit {
     fn get<'a>(&self,
-               key: &InternalKey<Comp>,
+               key: &InternalKey,
                cache_manager: &'a TableCacheManager,
-               io_manager: &'a IOManager) -> Result<Option<Vec<u8>>, error::Error> {
-        unimplemented!()
+               io_manager: &'a IOManager) -> Result<Option<(u64, Record)>, error::Error> {
+        if self.cmp_key(&key.user_key) != Ordering::Equal {
+            return Ok(None)
+        }
+
+        Ok(self.load(cache_manager, io_manager)?.get(key))
     }
 
-    fn lower_bound(&self) -> &UserKey<Comp> {
+    fn range_deletion_seq<'a>(&self,
+                              key: &InternalKey,
+                              cache_manager: &'a TableCacheManager,
+                              io_manager: &'a IOManager) -> Result<u64, error::Error> {
+        if self.cmp_key(&key.user_key) != Ordering::Equal {
+            return Ok(0)
+        }
+
+        Ok(self.load(cache_manager, io_manager)?.range_deletion_seq(key))
+    }
+
+    fn scan<'a>(&self,
+                lower: Bound<&[u8]>,
+                upper: Bound<&[u8]>,
+                cache_manager: &'a TableCacheManager,
+                io_manager: &'a IOManager) -> Result<TableScan, error::Error> {
+        let cache = self.load(cache_manager, io_manager)?;
+        let comparator = self.lower_bound.comparator();
+        let records = cache.iter()
+            .skip(self.first_kv_index as usize)
+            .take((self.last_kv_index - self.first_kv_index) as usize + 1)
+            .filter(|(_, key, _)| in_bounds(comparator, key, lower, upper));
+        Ok(collect_scan(&cache, comparator, records))
+    }
+
+    fn lower_bound(&self) -> &UserKey {
         &self.lower_bound
     }
 
-    fn upper_bound(&self) -> &UserKey<Comp> {
+    fn upper_bound(&self) -> &UserKey {
         &self.upper_bound
     }
 
     fn is_lazy(&self) -> bool {
         false
     }
+
+    fn file(&self) -> ScTableFile {
+        self.file
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::ops::Bound;
+    use crate::table::Table;
+    use crate::table::builder::ScTableBuilder;
+    use crate::table::cache::TableCacheManager;
+    use crate::table::scsplit::ScSplit;
+    use crate::table::sctable::ScTableFile;
+    use crate::io::IOManager;
+    use crate::partition::{InternalKey, UserKey};
+    use crate::partition::memtable::Record;
+    use crate::DefaultComparator;
+
+    #[test]
+    fn test_split_reads() {
+        let mut builder = ScTableBuilder::new();
+        builder.add_kv(1, "katyusha/1".as_bytes(), "Расцветали яблони и груши".as_bytes());
+        builder.add_kv(2, "katyusha/2".as_bytes(), "Поплыли туманы над рекой".as_bytes());
+        builder.add_kv(3, "katyusha/3".as_bytes(), "Выходила на берег Катюша".as_bytes());
+        builder.add_kv(4, "katyusha/4".as_bytes(), "На высокий берег, на крутой".as_bytes());
+        builder.add_range_deletion(5, "katyusha/0".as_bytes(), "katyusha/9".as_bytes());
+        let table_file = ScTableFile::new(51051, 0, 1);
+        let cache_manager = TableCacheManager::new(1);
+        let io_manager = IOManager::new(4, false);
+        io_manager.acquire_quota().write_file(table_file.file_name(), &builder.build()).unwrap();
+
+        let key = |key: &str| UserKey::new_owned(key.into(), &DefaultComparator());
+        let split = ScSplit::new(table_file, 1, 2, key("katyusha/2"), key("katyusha/3"));
+        let internal_key = |user_key: &str| InternalKey::new(9, key(user_key));
+        assert_eq!(split.get(&internal_key("katyusha/2"), &cache_manager, &io_manager).unwrap(),
+                   Some((2, Record::Value("Поплыли туманы над рекой".as_bytes().to_vec()))));
+        assert_eq!(split.get(&internal_key("katyusha/4"), &cache_manager, &io_manager).unwrap(), None);
+        assert_eq!(split.range_deletion_seq(&internal_key("katyusha/3"), &cache_manager, &io_manager).unwrap(), 5);
+        assert_eq!(split.range_deletion_seq(&internal_key("katyusha/1"), &cache_manager, &io_manager).unwrap(), 0);
+
+        let (records, range_tombstones) =
+            split.scan(Bound::Unbounded, Bound::Unbounded, &cache_manager, &io_manager).unwrap();
+        let keys: Vec<&[u8]> = records.iter().map(|(key, _)| key.user_key.key()).collect();
+        assert_eq!(keys, vec!["katyusha/2".as_bytes(), "katyusha/3".as_bytes()]);
+        assert_eq!(range_tombstones.len(), 1);
+
+        std::fs::remove_file(table_file.file_name()).unwrap();
+    }
 }
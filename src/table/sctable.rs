@@ -1,11 +1,14 @@
 use std::cmp::Ordering;
+use std::sync::Arc;
+use std::ops::Bound;
 
 use crate::error::Error;
-use crate::table::Table;
+use crate::table::{Table, TableScan};
 use crate::table::cache::{TableCacheManager, ScTableCache};
 use crate::Comparator;
 use crate::io::IOManager;
 use crate::partition::{InternalKey, UserKey};
+use crate::partition::memtable::{Record, RangeTombstone};
 
 #[derive(Ord, Eq, PartialOrd, PartialEq, Hash, Copy, Clone)]
 pub(crate) struct ScTableFile {
@@ -26,52 +29,121 @@ impl ScTableFile {
     }
 }
 
-pub(crate) struct ScTable<Comp: Comparator> {
+pub(crate) struct ScTable {
     table_file: ScTableFile,
 
-    key_lower_bound: UserKey<Comp>,
-    key_upper_bound: UserKey<Comp>
+    key_lower_bound: UserKey,
+    key_upper_bound: UserKey
 }
 
-impl<Comp: Comparator> ScTable<Comp> {
-    pub(crate) fn new(table_file: ScTableFile, key_lower_bound: UserKey<Comp>, key_upper_bound: UserKey<Comp>) -> Self {
+impl ScTable {
+    pub(crate) fn new(table_file: ScTableFile, key_lower_bound: UserKey, key_upper_bound: UserKey) -> Self {
         Self { table_file, key_lower_bound, key_upper_bound }
     }
+
+    fn load(&self, cache_manager: &TableCacheManager, io_manager: &IOManager) -> Result<Arc<ScTableCache>, Error> {
+        load_table(self.table_file, self.key_lower_bound.comparator(), cache_manager, io_manager)
+    }
 }
 
-impl<Comp: Comparator> Table<Comp> for ScTable<Comp> {
+/// Loads a table file through the cache, checking its order against `comparator` once it is read.
+pub(crate) fn load_table(table_file: ScTableFile,
+                         comparator: &dyn Comparator,
+                         cache_manager: &TableCacheManager,
+                         io_manager: &IOManager) -> Result<Arc<ScTableCache>, Error> {
+    if let Some(cache) = cache_manager.get_cache(table_file) {
+        return Ok(cache)
+    }
+
+    let cache_quota = cache_manager.acquire_quota();
+    let cache = if io_manager.use_mmap() {
+        ScTableCache::from_mapped(io_manager.acquire_quota().map_file(table_file.file_name())?, cache_quota)?
+    } else {
+        ScTableCache::from_raw(&io_manager.acquire_quota().read_file(table_file.file_name())?, cache_quota)?
+    };
+    cache.validate_order(comparator)?;
+    Ok(cache_manager.add_cache(table_file, cache))
+}
+
+/// Collects `records` of a loaded table along with all of its range deletions.
+pub(crate) fn collect_scan<'c>(cache: &ScTableCache,
+                               comparator: &(dyn Comparator + 'static),
+                               records: impl Iterator<Item = (u64, &'c [u8], Record)>) -> TableScan {
+    let records = records
+        .map(|(seq, key, record)| (InternalKey::new(seq, UserKey::new_owned(key.to_vec(), comparator)), record))
+        .collect();
+    let range_tombstones = cache.range_deletions().iter()
+        .map(|range_deletion| RangeTombstone::new(range_deletion.seq,
+                                                  UserKey::new_owned(range_deletion.start.clone(), comparator),
+                                                  UserKey::new_owned(range_deletion.end.clone(), comparator)))
+        .collect();
+    (records, range_tombstones)
+}
+
+impl Table for ScTable {
     fn get<'a>(&self,
-               key: &InternalKey<Comp>,
+               key: &InternalKey,
                cache_manager: &'a TableCacheManager,
-               io_manager: &'a IOManager) -> Result<Option<Vec<u8>>, Error> {
-        if key.user_key.cmp(self.lower_bound()) == Ordering::Less {
-            return Ok(None)
-        } else if key.user_key.cmp(self.upper_bound()) == Ordering::Greater {
+               io_manager: &'a IOManager) -> Result<Option<(u64, Record)>, Error> {
+        if self.cmp_key(&key.user_key) != Ordering::Equal {
             return Ok(None)
         }
 
-        if let Some(cache) = cache_manager.get_cache(self.table_file) {
-            Ok(cache.get::<Comp>(key))
-        } else {
-            let cache_quota = cache_manager.acquire_quota();
-            let cache =
-                ScTableCache::from_raw(
-                    &io_manager.acquire_quota()
-                                    .read_file(self.table_file.file_name())?, cache_quota)?;
-            let cache = cache_manager.add_cache(self.table_file, cache);
-            Ok(cache.get::<Comp>(key))
+        Ok(self.load(cache_manager, io_manager)?.get(key))
+    }
+
+    fn range_deletion_seq<'a>(&self,
+                              key: &InternalKey,
+                              cache_manager: &'a TableCacheManager,
+                              io_manager: &'a IOManager) -> Result<u64, Error> {
+        if self.cmp_key(&key.user_key) != Ordering::Equal {
+            return Ok(0)
         }
+
+        Ok(self.load(cache_manager, io_manager)?.range_deletion_seq(key))
+    }
+
+    fn scan<'a>(&self,
+                lower: Bound<&[u8]>,
+                upper: Bound<&[u8]>,
+                cache_manager: &'a TableCacheManager,
+                io_manager: &'a IOManager) -> Result<TableScan, Error> {
+        let cache = self.load(cache_manager, io_manager)?;
+        let comparator = self.key_lower_bound.comparator();
+        let records = cache.iter().filter(|(_, key, _)| in_bounds(comparator, key, lower, upper));
+        Ok(collect_scan(&cache, comparator, records))
     }
 
-    fn lower_bound(&self) -> &UserKey<Comp> {
+    fn lower_bound(&self) -> &UserKey {
         &self.key_lower_bound
     }
 
-    fn upper_bound(&self) -> &UserKey<Comp> {
+    fn upper_bound(&self) -> &UserKey {
         &self.key_upper_bound
     }
 
     fn is_lazy(&self) -> bool {
         false
     }
+
+    fn file(&self) -> ScTableFile {
+        self.table_file
+    }
+}
+
+pub(crate) fn in_bounds(comparator: &dyn Comparator,
+                        key: &[u8],
+                        lower: Bound<&[u8]>,
+                        upper: Bound<&[u8]>) -> bool {
+    let above_lower = match lower {
+        Bound::Included(lower) => comparator.compare(key, lower) != Ordering::Less,
+        Bound::Excluded(lower) => comparator.compare(key, lower) == Ordering::Greater,
+        Bound::Unbounded => true
+    };
+    let below_upper = match upper {
+        Bound::Included(upper) => comparator.compare(key, upper) != Ordering::Greater,
+        Bound::Excluded(upper) => comparator.compare(key, upper) == Ordering::Less,
+        Bound::Unbounded => true
+    };
+    above_lower && below_upper
 }
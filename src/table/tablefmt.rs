@@ -12,10 +12,71 @@
 //! +-DATA------+---------------+----------------+
 //! | data_size binary data                      |
 //! |                                            |
+//! +-RANGE DELETIONS----------------------------+
+//! | 8byte seq | 4byte start size | 4byte end size |
+//! | start key | end key                        |
+//! | ...                                        |
+//! | 4byte range deletions size                 |
+//! | 4byte range deletions crc                  |
+//! +-FOOTER-------------------------------------+
+//! | 4byte format version                       |
+//! | 4byte flags                                |
+//! | 8byte TABLE_VERSIONED_MAGIC                |
+//! +--------------------------------------------+
+//! ```
+//!
+//! Prefix compressed table format, marked by `TABLE_FLAG_PREFIX_DELTA`
+//! ```raw
+//! +-HEADER-------------------------------------+
+//! | same as above                              |
+//! +-CATALOG------------------------------------+
+//! | 8byte seq | varint shared | varint unshared |
+//! |           | varint value_size << 2          |
+//! |           |   | expiring << 1 | deleted     |
+//! | ...                                        |
+//! | 4byte restart offset                       |
+//! | ...                                        |
+//! | 4byte restart count                        |
+//! +-DATA---------------------------------------+
+//! | unshared key bytes | value bytes           |
+//! | ...                                        |
+//! +-RANGE DELETIONS----------------------------+
+//! | same as above                              |
+//! +-FOOTER-------------------------------------+
+//! | same as above                              |
+//! +--------------------------------------------+
+//! ```
+//! `shared` is the length of the prefix shared with the previous key. Every
+//! `restart_interval` entries the full key is stored (`shared` = 0), restart
+//! offsets are relative to the beginning of the catalog. Restarts are only checked when
+//! loading, not used to seek: keys get expanded in full, so prefix compression saves disk space
+//! but a loaded table takes as much memory as a plain one.
+//!
+//! A range deletion deletes all keys in `[start, end)` written before its seq. Version 1 tables
+//! have no range deletions section.
+//!
+//! Since version 3 the highest bit of a catalog seq (`TABLE_MERGE_BITMASK`) marks the value as a
+//! merge operand, seqs are thus limited to 63 bits. Since version 4 the second highest bit of a
+//! catalog value offset (`TABLE_EXPIRING_BITMASK`) marks a value which expires, prefixed with its
+//! 8byte expiration time in milliseconds since the Unix epoch, limiting data sections to
+//! `TABLE_MAX_DATA_SIZE`. Prefix compressed catalogs store `value_size << 2 | expiring << 1 |
+//! deleted` instead since version 4.
+//!
+//! Compressed table format
+//! ```raw
+//! +-PAYLOAD------------------------------------+
+//! | compressed table of any version            |
 //! +-TAIL---------------------------------------+
-//! | 8byte TABLE_MAGIC                          |
+//! | 4byte uncompressed size                    |
+//! | 4byte payload crc                          |
+//! | 1byte codec id                             |
+//! | 8byte TABLE_COMPRESSED_MAGIC               |
 //! +--------------------------------------------+
 //! ```
+//!
+//! Version 0 tables have no footer, they end with `TABLE_MAGIC` (plain) or `TABLE_PREFIX_MAGIC`
+//! (prefix compressed) directly. They are still readable, and can be rewritten into the current
+//! version with `upgrade_table_file`.
 
 pub const TABLE_HEAD_SIZE: usize = 16;
 pub const TABLE_MIN_SIZE: usize = TABLE_MAGIC_SIZE + TABLE_HEAD_SIZE;
@@ -23,6 +84,24 @@ pub const TABLE_CATALOG_ITEM_SIZE: usize = 24;
 
 pub const TABLE_MAX_SIZE: usize = 0x7FFFFFFF;
 pub const TABLE_DELETION_BITMASK: u32 = 0x80000000;
+pub const TABLE_MERGE_BITMASK: u64 = 0x8000000000000000;
+pub const TABLE_EXPIRING_BITMASK: u32 = 0x40000000;
+pub const TABLE_MAX_DATA_SIZE: usize = 0x3FFFFFFF;
+pub const TABLE_MAX_SEQ: u64 = !TABLE_MERGE_BITMASK;
 
 pub const TABLE_MAGIC: &'static [u8] = b"40490fd0";
+pub const TABLE_PREFIX_MAGIC: &'static [u8] = b"40490fd1";
+pub const TABLE_COMPRESSED_MAGIC: &'static [u8] = b"40490fdc";
 pub const TABLE_MAGIC_SIZE: usize = TABLE_MAGIC.len();
+pub const TABLE_COMPRESSED_TAIL_SIZE: usize = 9 + TABLE_MAGIC_SIZE;
+
+pub const TABLE_VERSIONED_MAGIC: &'static [u8] = b"40490fdb";
+pub const TABLE_FOOTER_SIZE: usize = 8 + TABLE_MAGIC_SIZE;
+pub const TABLE_RANGE_DELETION_ITEM_SIZE: usize = 16;
+pub const TABLE_RANGE_DELETION_TAIL_SIZE: usize = 8;
+pub const TABLE_EMPTY_SIZE: usize = TABLE_HEAD_SIZE + TABLE_RANGE_DELETION_TAIL_SIZE + TABLE_FOOTER_SIZE;
+pub const TABLE_FORMAT_VERSION: u32 = 4;
+pub const TABLE_FLAG_PREFIX_DELTA: u32 = 0x1;
+
+pub const TABLE_RESTART_SIZE: usize = 4;
+pub const TABLE_DEFAULT_RESTART_INTERVAL: usize = 16;
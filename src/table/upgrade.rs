@@ -0,0 +1,170 @@
+use crate::table::builder::ScTableBuilder;
+use crate::table::cache::ScTableCache;
+use crate::table::version::decode_footer;
+use crate::table::tablefmt::TABLE_HEAD_SIZE;
+use crate::table::prefix;
+use crate::compress::{self, CompressionType};
+use crate::io::IOManager;
+use crate::encode::{decode_fixed32, decode_fixed64};
+use crate::error::Error;
+use crate::KeyEncoding;
+
+/// Rewrites a table of an older format version into the current version, keeping its key
+/// encoding, restart interval and compression. Returns `None` if the table is already of the current version.
+pub(crate) fn upgrade_table(raw: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+    let compression = compress::table_compression(raw)?;
+    let decompressed;
+    let raw = if compression != CompressionType::None {
+        decompressed = compress::decompress_table(raw)?;
+        &decompressed
+    } else {
+        raw
+    };
+
+    let footer = decode_footer(raw)?;
+    if footer.is_current() {
+        return Ok(None)
+    }
+    let (catalog, data, range_deletions) = ScTableCache::decode(raw)?;
+    let key_encoding = if footer.prefix_delta() {
+        let kv_catalog_size = decode_fixed32(&raw[0..4]) as usize;
+        let kv_catalog = &raw[TABLE_HEAD_SIZE..TABLE_HEAD_SIZE + kv_catalog_size];
+        KeyEncoding::PrefixDelta { restart_interval: prefix::restart_interval(kv_catalog) }
+    } else {
+        KeyEncoding::Plain
+    };
+    let mut builder = ScTableBuilder::with_format(key_encoding, compression);
+    for item in catalog.iter() {
+        if item.is_deletion() {
+            builder.add_deletion(item.seq(), item.key(&data));
+        } else if item.is_merge() {
+            builder.add_merge(item.seq(), item.key(&data), item.value(&data));
+        } else if item.is_expiring() {
+            let value = item.value(&data);
+            builder.add_expiring(item.seq(), item.key(&data), &value[8..], decode_fixed64(&value[..8]));
+        } else {
+            builder.add_kv(item.seq(), item.key(&data), item.value(&data));
+        }
+    }
+    for range_deletion in range_deletions.iter() {
+        builder.add_range_deletion(range_deletion.seq, &range_deletion.start, &range_deletion.end);
+    }
+    Ok(Some(builder.build()))
+}
+
+/// Upgrades a table file in place, for offline use when the database is not open. Returns
+/// whether the file got rewritten.
+pub fn upgrade_table_file(file_name: &str) -> Result<bool, Error> {
+    let io_manager = IOManager::new(1, false);
+    let raw = io_manager.acquire_quota().read_file(file_name.to_string())?;
+    if let Some(upgraded) = upgrade_table(&raw)? {
+        io_manager.acquire_quota().replace_file(file_name.to_string(), &upgraded)?;
+        Ok(true)
+    } else {
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::table::builder::ScTableBuilder;
+    use crate::table::cache::ScTableCache;
+    use crate::table::upgrade::upgrade_table;
+    use crate::table::version::decode_footer;
+    use crate::table::prefix;
+    use crate::table::tablefmt::{TABLE_MAGIC, TABLE_PREFIX_MAGIC, TABLE_FOOTER_SIZE, TABLE_FORMAT_VERSION,
+                                 TABLE_RANGE_DELETION_TAIL_SIZE, TABLE_HEAD_SIZE, TABLE_COMPRESSED_MAGIC,
+                                 TABLE_MAGIC_SIZE};
+    use crate::compress::{CompressionType, compress_table, decompress_table, table_compression};
+    use crate::encode::{encode_fixed32, decode_fixed32};
+    use crate::KeyEncoding;
+
+    /// Strips the footer and the empty range deletions section off a table.
+    fn to_version0(mut raw: Vec<u8>) -> Vec<u8> {
+        raw.truncate(raw.len() - TABLE_FOOTER_SIZE - TABLE_RANGE_DELETION_TAIL_SIZE);
+        raw.extend_from_slice(TABLE_MAGIC);
+        raw
+    }
+
+    #[test]
+    fn test_upgrade_version0() {
+        let mut builder = ScTableBuilder::new();
+        builder.add_kv(1, "正当梨花开遍了天涯".as_bytes(), "Расцветали яблони и груши".as_bytes());
+        builder.add_deletion(2, "河上飘着柔软的轻纱".as_bytes());
+        builder.add_kv(3, "喀秋莎站在那俊俏的岸上".as_bytes(), "Выходила на берег Катюша".as_bytes());
+        let raw = to_version0(builder.build());
+        assert_eq!(decode_footer(&raw).unwrap().version, 0);
+
+        let upgraded = upgrade_table(&raw).unwrap().unwrap();
+        assert_eq!(decode_footer(&upgraded).unwrap().version, TABLE_FORMAT_VERSION);
+        assert!(upgrade_table(&upgraded).unwrap().is_none());
+
+        let (catalog0, data0, _) = ScTableCache::decode(&raw).unwrap();
+        let (catalog1, data1, _) = ScTableCache::decode(&upgraded).unwrap();
+        assert_eq!(catalog0.len(), catalog1.len());
+        for (item0, item1) in catalog0.iter().zip(catalog1.iter()) {
+            assert_eq!(item0.key_seq, item1.key_seq);
+            assert_eq!(item0.is_deletion(), item1.is_deletion());
+            assert_eq!(item0.key(&data0), item1.key(&data1));
+            assert_eq!(item0.value(&data0), item1.value(&data1));
+        }
+    }
+
+    #[test]
+    fn test_upgrade_keeps_compression() {
+        let mut builder = ScTableBuilder::new();
+        for i in 0..64u64 {
+            builder.add_kv(i, format!("katyusha/{:04}", i).as_bytes(), "歌声好像明媚的春光".as_bytes());
+        }
+        let raw = compress_table(to_version0(builder.build()), CompressionType::Zlib);
+        assert_eq!(table_compression(&raw).unwrap(), CompressionType::Zlib);
+
+        let upgraded = upgrade_table(&raw).unwrap().unwrap();
+        assert_eq!(table_compression(&upgraded).unwrap(), CompressionType::Zlib);
+        let inner = decompress_table(&upgraded).unwrap();
+        assert_eq!(decode_footer(&inner).unwrap().version, TABLE_FORMAT_VERSION);
+        assert_eq!(ScTableCache::decode(&upgraded).unwrap().0.len(), 64);
+    }
+
+    #[test]
+    fn test_upgrade_keeps_restart_interval() {
+        for &restart_interval in [1, 3, 5].iter() {
+            let mut builder = ScTableBuilder::new();
+            for i in 0..20u64 {
+                builder.add_kv(i, format!("katyusha/{:04}", i).as_bytes(), "Выходила на берег Катюша".as_bytes());
+            }
+            let (catalog, data, _) = ScTableCache::decode(&builder.build()).unwrap();
+            let mut raw = prefix::build(&catalog, &data, restart_interval, 0);
+            raw.extend_from_slice(TABLE_PREFIX_MAGIC);
+            assert!(decode_footer(&raw).unwrap().prefix_delta());
+
+            let upgraded = upgrade_table(&raw).unwrap().unwrap();
+            let kv_catalog_size = decode_fixed32(&upgraded[0..4]) as usize;
+            let kv_catalog = &upgraded[TABLE_HEAD_SIZE..TABLE_HEAD_SIZE + kv_catalog_size];
+            assert_eq!(prefix::restart_interval(kv_catalog), restart_interval);
+        }
+    }
+
+    #[test]
+    fn test_unknown_codec() {
+        let mut builder = ScTableBuilder::new();
+        for i in 0..64u64 {
+            builder.add_kv(i, format!("katyusha/{:04}", i).as_bytes(), "歌声好像明媚的春光".as_bytes());
+        }
+        let mut raw = compress_table(builder.build(), CompressionType::Snappy);
+        assert_eq!(&raw[raw.len() - TABLE_MAGIC_SIZE..], TABLE_COMPRESSED_MAGIC);
+        let id_off = raw.len() - TABLE_MAGIC_SIZE - 1;
+        raw[id_off] = 0xEE;
+        let err = upgrade_table(&raw).unwrap_err();
+        assert!(format!("{}", err).contains("unknown compression codec 238"));
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let mut raw = ScTableBuilder::new().build();
+        let version_off = raw.len() - TABLE_FOOTER_SIZE;
+        encode_fixed32(&mut raw[version_off..version_off + 4], TABLE_FORMAT_VERSION + 1);
+        assert!(ScTableCache::decode(&raw).is_err());
+        assert!(upgrade_table(&raw).is_err());
+    }
+}
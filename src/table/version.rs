@@ -0,0 +1,52 @@
+use crate::table::tablefmt::{TABLE_MAGIC, TABLE_PREFIX_MAGIC, TABLE_VERSIONED_MAGIC, TABLE_MAGIC_SIZE,
+                             TABLE_FOOTER_SIZE, TABLE_FORMAT_VERSION, TABLE_FLAG_PREFIX_DELTA};
+use crate::encode::{encode_fixed32_ret, decode_fixed32};
+use crate::error::Error;
+
+pub(crate) struct TableFooter {
+    pub(crate) version: u32,
+    pub(crate) flags: u32,
+    pub(crate) size: usize
+}
+
+impl TableFooter {
+    pub(crate) fn prefix_delta(&self) -> bool {
+        self.flags & TABLE_FLAG_PREFIX_DELTA != 0
+    }
+
+    pub(crate) fn is_current(&self) -> bool {
+        self.version == TABLE_FORMAT_VERSION
+    }
+}
+
+pub(crate) fn encode_footer(dest: &mut Vec<u8>, flags: u32) {
+    dest.extend_from_slice(&encode_fixed32_ret(TABLE_FORMAT_VERSION));
+    dest.extend_from_slice(&encode_fixed32_ret(flags));
+    dest.extend_from_slice(TABLE_VERSIONED_MAGIC);
+}
+
+/// Reads the footer of an uncompressed table. Version 0 tables, which have only a magic at the
+/// end, are reported as well.
+pub(crate) fn decode_footer(raw: &[u8]) -> Result<TableFooter, Error> {
+    if raw.len() < TABLE_MAGIC_SIZE {
+        return Err(Error::sc_table_corrupt("too small to be a table file".into()))
+    }
+    let magic = &raw[raw.len() - TABLE_MAGIC_SIZE..];
+    if magic == TABLE_MAGIC {
+        Ok(TableFooter { version: 0, flags: 0, size: TABLE_MAGIC_SIZE })
+    } else if magic == TABLE_PREFIX_MAGIC {
+        Ok(TableFooter { version: 0, flags: TABLE_FLAG_PREFIX_DELTA, size: TABLE_MAGIC_SIZE })
+    } else if magic == TABLE_VERSIONED_MAGIC {
+        if raw.len() < TABLE_FOOTER_SIZE {
+            return Err(Error::sc_table_corrupt("too small to be a table file".into()))
+        }
+        let footer = &raw[raw.len() - TABLE_FOOTER_SIZE..];
+        Ok(TableFooter {
+            version: decode_fixed32(&footer[0..4]),
+            flags: decode_fixed32(&footer[4..8]),
+            size: TABLE_FOOTER_SIZE
+        })
+    } else {
+        Err(Error::sc_table_corrupt("incorrect table magic".into()))
+    }
+}
@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{Mutex, Condvar};
+use std::time::{Duration, Instant};
+
+use crate::error::Error;
+
+/// Column family address and user key of a lock.
+type LockKey = (usize, Vec<u8>);
+
+struct LockStripe {
+    /// Holder of each locked key
+    locks: Mutex<HashMap<LockKey, u64>>,
+    condvar: Condvar
+}
+
+/// Exclusive per-key locks held by transactions, striped by key hash to reduce contention.
+pub(crate) struct LockManager {
+    stripes: Vec<LockStripe>,
+    /// The transaction each waiting transaction waits for
+    waits_for: Mutex<HashMap<u64, u64>>,
+    timeout: Duration
+}
+
+impl LockManager {
+    pub(crate) fn new(stripe_count: usize, timeout: Duration) -> Self {
+        let stripes = (0..stripe_count.max(1))
+            .map(|_| LockStripe { locks: Mutex::new(HashMap::new()), condvar: Condvar::new() })
+            .collect();
+        Self { stripes, waits_for: Mutex::new(HashMap::new()), timeout }
+    }
+
+    /// Locks `key` for `txn_id`. Returns whether the lock is newly acquired, or fails when
+    /// waiting would deadlock or takes longer than the lock timeout.
+    pub(crate) fn lock(&self, txn_id: u64, family: usize, key: &[u8]) -> Result<bool, Error> {
+        let lock_key = (family, key.to_vec());
+        let stripe = &self.stripes[self.stripe_index(&lock_key)];
+        let deadline = Instant::now() + self.timeout;
+        let mut locks = stripe.locks.lock().unwrap();
+        loop {
+            let holder = match locks.get(&lock_key) {
+                None => {
+                    locks.insert(lock_key, txn_id);
+                    self.stop_waiting(txn_id);
+                    return Ok(true)
+                },
+                Some(&holder) if holder == txn_id => {
+                    self.stop_waiting(txn_id);
+                    return Ok(false)
+                },
+                Some(&holder) => holder
+            };
+            self.start_waiting(txn_id, holder)?;
+            let now = Instant::now();
+            if now >= deadline {
+                self.stop_waiting(txn_id);
+                return Err(Error::lock_timeout(
+                    format!("{} locked by transaction {}", String::from_utf8_lossy(key), holder).into()))
+            }
+            locks = stripe.condvar.wait_timeout(locks, deadline - now).unwrap().0;
+        }
+    }
+
+    pub(crate) fn unlock(&self, txn_id: u64, family: usize, key: &[u8]) {
+        let lock_key = (family, key.to_vec());
+        let stripe = &self.stripes[self.stripe_index(&lock_key)];
+        let mut locks = stripe.locks.lock().unwrap();
+        if locks.get(&lock_key) == Some(&txn_id) {
+            locks.remove(&lock_key);
+            stripe.condvar.notify_all();
+        }
+    }
+
+    /// Records that `txn_id` waits for `holder`, unless that closes a cycle in the wait-for
+    /// graph.
+    fn start_waiting(&self, txn_id: u64, holder: u64) -> Result<(), Error> {
+        let mut waits_for = self.waits_for.lock().unwrap();
+        let mut next = Some(holder);
+        while let Some(waiting) = next {
+            if waiting == txn_id {
+                waits_for.remove(&txn_id);
+                return Err(Error::deadlock(
+                    format!("transaction {} waits for transaction {}", txn_id, holder).into()))
+            }
+            next = waits_for.get(&waiting).copied();
+        }
+        waits_for.insert(txn_id, holder);
+        Ok(())
+    }
+
+    fn stop_waiting(&self, txn_id: u64) {
+        self.waits_for.lock().unwrap().remove(&txn_id);
+    }
+
+    fn stripe_index(&self, lock_key: &LockKey) -> usize {
+        let mut hasher = DefaultHasher::new();
+        lock_key.hash(&mut hasher);
+        (hasher.finish() % self.stripes.len() as u64) as usize
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+    use crate::transaction::lock::LockManager;
+    use crate::error::Error;
+
+    #[test]
+    fn test_deadlock_detection() {
+        let lock_manager = Arc::new(LockManager::new(4, Duration::from_secs(10)));
+        assert!(lock_manager.lock(1, 0, "喀秋莎".as_bytes()).unwrap());
+        assert!(!lock_manager.lock(1, 0, "喀秋莎".as_bytes()).unwrap());
+        assert!(lock_manager.lock(2, 0, "Катюша".as_bytes()).unwrap());
+
+        let waiter = {
+            let lock_manager = lock_manager.clone();
+            thread::spawn(move || lock_manager.lock(1, 0, "Катюша".as_bytes()))
+        };
+        while lock_manager.waits_for.lock().unwrap().get(&1) != Some(&2) {
+            thread::yield_now();
+        }
+        match lock_manager.lock(2, 0, "喀秋莎".as_bytes()) {
+            Err(Error::Deadlock { .. }) => {},
+            _ => panic!("deadlock not detected")
+        }
+        lock_manager.unlock(2, 0, "Катюша".as_bytes());
+        assert!(waiter.join().unwrap().unwrap());
+    }
+
+    #[test]
+    fn test_lock_timeout() {
+        let lock_manager = LockManager::new(1, Duration::from_millis(20));
+        lock_manager.lock(1, 0, "喀秋莎".as_bytes()).unwrap();
+        lock_manager.lock(1, 1, "喀秋莎".as_bytes()).unwrap();
+        match lock_manager.lock(2, 0, "喀秋莎".as_bytes()) {
+            Err(Error::LockTimeout { .. }) => {},
+            _ => panic!("lock timeout expected")
+        }
+        lock_manager.unlock(1, 0, "喀秋莎".as_bytes());
+        assert!(lock_manager.lock(2, 0, "喀秋莎".as_bytes()).unwrap());
+    }
+}
@@ -0,0 +1,6 @@
+mod optimistic;
+mod pessimistic;
+mod lock;
+
+pub use optimistic::OptimisticTransaction;
+pub use pessimistic::{TransactionDB, TransactionOptions, PessimisticTransaction};
@@ -0,0 +1,134 @@
+use crate::{ScottDB, ColumnFamily, WriteBatch};
+use crate::error::Error;
+use crate::family::Snapshot;
+
+/// A transaction which buffers its writes, and validates on commit that none of the keys it read
+/// got written since it began.
+pub struct OptimisticTransaction<'a> {
+    db: &'a ScottDB<'a>,
+    snapshot: Snapshot<'a>,
+    batch: WriteBatch<'a>,
+    read_set: Vec<(&'a ColumnFamily<'a>, Vec<u8>)>
+}
+
+impl<'a> OptimisticTransaction<'a> {
+    pub(crate) fn new(db: &'a ScottDB<'a>) -> Self {
+        Self { db, snapshot: db.shared.snapshots.acquire(), batch: WriteBatch::new(), read_set: Vec::new() }
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        self.get_cf(&self.db.default_family, key)
+    }
+
+    /// Reads `key` as of the beginning of the transaction, with the writes of the transaction
+    /// applied.
+    pub fn get_cf(&mut self, cf: &'a ColumnFamily<'a>, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        self.read_set.push((cf, key.to_vec()));
+        if let Some(value) = self.batch.lookup(cf, key) {
+            return Ok(value)
+        }
+        cf.get_at(key, self.snapshot.seq())
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) {
+        self.batch.put(&self.db.default_family, key, value)
+    }
+
+    pub fn put_cf(&mut self, cf: &'a ColumnFamily<'a>, key: &[u8], value: &[u8]) {
+        self.batch.put(cf, key, value)
+    }
+
+    pub fn delete(&mut self, key: &[u8]) {
+        self.batch.delete(&self.db.default_family, key)
+    }
+
+    pub fn delete_cf(&mut self, cf: &'a ColumnFamily<'a>, key: &[u8]) {
+        self.batch.delete(cf, key)
+    }
+
+    /// Deletes all keys in `[start, end)`.
+    pub fn delete_range(&mut self, start: &[u8], end: &[u8]) {
+        self.batch.delete_range(&self.db.default_family, start, end)
+    }
+
+    pub fn delete_range_cf(&mut self, cf: &'a ColumnFamily<'a>, start: &[u8], end: &[u8]) {
+        self.batch.delete_range(cf, start, end)
+    }
+
+    /// Applies the writes of the transaction atomically, or fails with `Error::Conflict` if any
+    /// key read by the transaction was written since it began.
+    pub fn commit(self) -> Result<(), Error> {
+        self.batch.validate()?;
+        let shared = &self.db.shared;
+        shared.commit(self.batch.len(), |first_seq| {
+            for (family, key) in self.read_set.iter() {
+                if family.latest_seq(key)? > self.snapshot.seq() {
+                    return Err(Error::conflict(
+                        format!("{} written since transaction began", String::from_utf8_lossy(key)).into()))
+                }
+            }
+            self.batch.apply(shared, first_seq)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{ScottDB, Options, Error};
+
+    #[test]
+    fn test_optimistic_conflict() {
+        let db = ScottDB::new(Options::new("test_optimistic_conflict", 4, 4, 10, 16, 4096, 64, 256)).unwrap();
+        db.put("喀秋莎".as_bytes(), "Выходила на берег Катюша".as_bytes()).unwrap();
+
+        let mut txn1 = db.begin_optimistic();
+        let mut txn2 = db.begin_optimistic();
+        assert_eq!(txn1.get("喀秋莎".as_bytes()).unwrap().unwrap(), "Выходила на берег Катюша".as_bytes());
+        txn1.put("喀秋莎".as_bytes(), "歌声好像明媚的春光".as_bytes());
+        assert_eq!(txn1.get("喀秋莎".as_bytes()).unwrap().unwrap(), "歌声好像明媚的春光".as_bytes());
+        txn2.get("喀秋莎".as_bytes()).unwrap();
+        txn2.delete_range("喀".as_bytes(), "喀秋莎站".as_bytes());
+        assert_eq!(txn2.get("喀秋莎".as_bytes()).unwrap(), None);
+
+        txn1.commit().unwrap();
+        match txn2.commit() {
+            Err(Error::Conflict { .. }) => {},
+            _ => panic!("conflict not detected")
+        }
+        assert_eq!(db.get("喀秋莎".as_bytes()).unwrap().unwrap(), "歌声好像明媚的春光".as_bytes());
+
+        let mut txn3 = db.begin_optimistic();
+        txn3.get("河上飘着柔软的轻纱".as_bytes()).unwrap();
+        db.delete_range("河".as_bytes(), "洳".as_bytes()).unwrap();
+        assert!(txn3.commit().is_err());
+
+        let mut txn4 = db.begin_optimistic();
+        txn4.get("喀秋莎".as_bytes()).unwrap();
+        db.put("河上飘着柔软的轻纱".as_bytes(), "Поплыли туманы над рекой".as_bytes()).unwrap();
+        txn4.put("正当梨花开遍了天涯".as_bytes(), "Расцветали яблони и груши".as_bytes());
+        txn4.commit().unwrap();
+        assert!(db.get("正当梨花开遍了天涯".as_bytes()).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_optimistic_conflict_with_drop() {
+        let db = ScottDB::new(Options::new("test_optimistic_conflict_with_drop", 4, 4, 10, 16, 4096, 64, 256)).unwrap();
+        db.put("katyusha/01".as_bytes(), "Выходила на берег Катюша".as_bytes()).unwrap();
+
+        let mut txn = db.begin_optimistic();
+        assert!(txn.get("katyusha/01".as_bytes()).unwrap().is_some());
+        txn.put("katyusha/02".as_bytes(), "歌声好像明媚的春光".as_bytes());
+        // drops the partition holding the key read by the transaction
+        db.drop_range("katyusha".as_bytes(), "katyusha/99".as_bytes()).unwrap();
+        match txn.commit() {
+            Err(Error::Conflict { .. }) => {},
+            _ => panic!("conflict not detected")
+        }
+        assert_eq!(db.get("katyusha/02".as_bytes()).unwrap(), None);
+
+        let mut txn = db.begin_optimistic();
+        assert_eq!(txn.get("katyusha/01".as_bytes()).unwrap(), None);
+        txn.put("katyusha/02".as_bytes(), "歌声好像明媚的春光".as_bytes());
+        txn.commit().unwrap();
+    }
+}
@@ -0,0 +1,165 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::{ScottDB, ColumnFamily, WriteBatch, Options};
+use crate::transaction::lock::LockManager;
+use crate::error::Error;
+
+pub struct TransactionOptions {
+    /// How long a transaction waits for a key locked by another transaction
+    pub lock_timeout: Duration,
+    pub lock_stripes: usize
+}
+
+impl Default for TransactionOptions {
+    fn default() -> Self {
+        Self { lock_timeout: Duration::from_secs(1), lock_stripes: 16 }
+    }
+}
+
+/// A database whose transactions lock the keys they write, or read with `get_for_update`, until
+/// they commit or roll back.
+pub struct TransactionDB<'a> {
+    db: ScottDB<'a>,
+    lock_manager: LockManager,
+    next_txn_id: AtomicU64
+}
+
+impl<'a> TransactionDB<'a> {
+    pub fn new(options: Options, txn_options: TransactionOptions) -> Result<Self, Error> {
+        Ok(Self {
+            db: ScottDB::new(options)?,
+            lock_manager: LockManager::new(txn_options.lock_stripes, txn_options.lock_timeout),
+            next_txn_id: AtomicU64::new(1)
+        })
+    }
+
+    /// The underlying database. Writes through it bypass the locks of transactions.
+    pub fn db(&self) -> &ScottDB<'a> {
+        &self.db
+    }
+
+    pub fn begin(&'a self) -> PessimisticTransaction<'a> {
+        PessimisticTransaction {
+            txn_db: self,
+            txn_id: self.next_txn_id.fetch_add(1, Ordering::SeqCst),
+            batch: WriteBatch::new(),
+            locked_keys: Vec::new()
+        }
+    }
+}
+
+/// A transaction buffering its writes. Locks are released on commit, on rollback, or when the
+/// transaction is dropped.
+pub struct PessimisticTransaction<'a> {
+    txn_db: &'a TransactionDB<'a>,
+    txn_id: u64,
+    batch: WriteBatch<'a>,
+    locked_keys: Vec<(usize, Vec<u8>)>
+}
+
+impl<'a> PessimisticTransaction<'a> {
+    /// Reads the latest committed value of `key`, with the writes of the transaction applied.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        self.get_cf(&self.txn_db.db.default_family, key)
+    }
+
+    pub fn get_cf(&self, cf: &'a ColumnFamily<'a>, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        if let Some(value) = self.batch.lookup(cf, key) {
+            return Ok(value)
+        }
+        self.txn_db.db.get_cf(cf, key)
+    }
+
+    /// Locks `key` and reads it, so no other transaction writes it before this one finishes.
+    pub fn get_for_update(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        self.get_for_update_cf(&self.txn_db.db.default_family, key)
+    }
+
+    pub fn get_for_update_cf(&mut self, cf: &'a ColumnFamily<'a>, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        self.lock(cf, key)?;
+        self.get_cf(cf, key)
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        self.put_cf(&self.txn_db.db.default_family, key, value)
+    }
+
+    pub fn put_cf(&mut self, cf: &'a ColumnFamily<'a>, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        self.lock(cf, key)?;
+        self.batch.put(cf, key, value);
+        Ok(())
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> Result<(), Error> {
+        self.delete_cf(&self.txn_db.db.default_family, key)
+    }
+
+    pub fn delete_cf(&mut self, cf: &'a ColumnFamily<'a>, key: &[u8]) -> Result<(), Error> {
+        self.lock(cf, key)?;
+        self.batch.delete(cf, key);
+        Ok(())
+    }
+
+    /// Applies the writes of the transaction atomically, then releases its locks.
+    pub fn commit(mut self) -> Result<(), Error> {
+        let batch = std::mem::take(&mut self.batch);
+        self.txn_db.db.write(batch)
+    }
+
+    /// Discards the writes of the transaction, and releases its locks.
+    pub fn rollback(self) {}
+
+    fn lock(&mut self, cf: &ColumnFamily<'a>, key: &[u8]) -> Result<(), Error> {
+        let family = cf as *const ColumnFamily<'a> as usize;
+        if self.txn_db.lock_manager.lock(self.txn_id, family, key)? {
+            self.locked_keys.push((family, key.to_vec()));
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Drop for PessimisticTransaction<'a> {
+    fn drop(&mut self) {
+        for (family, key) in self.locked_keys.iter() {
+            self.txn_db.lock_manager.unlock(self.txn_id, *family, key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use crate::{Options, Error};
+    use crate::transaction::{TransactionDB, TransactionOptions};
+
+    #[test]
+    fn test_pessimistic_transaction() {
+        let txn_db = TransactionDB::new(
+            Options::new("test_pessimistic_transaction", 4, 4, 10, 16, 4096, 64, 256),
+            TransactionOptions { lock_timeout: Duration::from_millis(20), lock_stripes: 4 }).unwrap();
+        txn_db.db().put("喀秋莎".as_bytes(), "Выходила на берег Катюша".as_bytes()).unwrap();
+
+        let mut txn1 = txn_db.begin();
+        let mut txn2 = txn_db.begin();
+        assert!(txn1.get_for_update("喀秋莎".as_bytes()).unwrap().is_some());
+        match txn2.put("喀秋莎".as_bytes(), "".as_bytes()) {
+            Err(Error::LockTimeout { .. }) => {},
+            _ => panic!("lock timeout expected")
+        }
+        txn1.put("喀秋莎".as_bytes(), "歌声好像明媚的春光".as_bytes()).unwrap();
+        txn1.delete("河上飘着柔软的轻纱".as_bytes()).unwrap();
+        assert_eq!(txn1.get("喀秋莎".as_bytes()).unwrap().unwrap(), "歌声好像明媚的春光".as_bytes());
+        assert_eq!(txn2.get("喀秋莎".as_bytes()).unwrap().unwrap(), "Выходила на берег Катюша".as_bytes());
+        txn1.commit().unwrap();
+
+        txn2.put("喀秋莎".as_bytes(), "Поплыли туманы над рекой".as_bytes()).unwrap();
+        txn2.rollback();
+        assert_eq!(txn_db.db().get("喀秋莎".as_bytes()).unwrap().unwrap(), "歌声好像明媚的春光".as_bytes());
+
+        let mut txn3 = txn_db.begin();
+        txn3.put("喀秋莎".as_bytes(), "Поплыли туманы над рекой".as_bytes()).unwrap();
+        txn3.commit().unwrap();
+        assert_eq!(txn_db.db().get("喀秋莎".as_bytes()).unwrap().unwrap(), "Поплыли туманы над рекой".as_bytes());
+    }
+}
@@ -0,0 +1,217 @@
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+
+use crate::{ScottDB, ScottIterator, ColumnFamily, Comparator, DefaultComparator};
+use crate::encode::memcmp::MemComparable;
+use crate::error::Error;
+
+/// Encodes keys of a `TypedDb`. Encodings must compare bytewise in the order of the keys, which
+/// holds for all `MemComparable` types.
+pub trait KeyCodec: Sized {
+    fn encode_key(&self) -> Vec<u8>;
+
+    fn decode_key(raw: &[u8]) -> Result<Self, Error>;
+}
+
+impl<T: MemComparable> KeyCodec for T {
+    fn encode_key(&self) -> Vec<u8> {
+        crate::encode::memcmp::encode_key(self)
+    }
+
+    fn decode_key(raw: &[u8]) -> Result<Self, Error> {
+        crate::encode::memcmp::decode_key(raw)
+    }
+}
+
+/// Encodes values of a `TypedDb`.
+pub trait ValueCodec: Sized {
+    fn encode_value(&self) -> Vec<u8>;
+
+    fn decode_value(raw: &[u8]) -> Result<Self, Error>;
+}
+
+impl ValueCodec for Vec<u8> {
+    fn encode_value(&self) -> Vec<u8> {
+        self.clone()
+    }
+
+    fn decode_value(raw: &[u8]) -> Result<Self, Error> {
+        Ok(raw.to_vec())
+    }
+}
+
+impl ValueCodec for String {
+    fn encode_value(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+
+    fn decode_value(raw: &[u8]) -> Result<Self, Error> {
+        String::from_utf8(raw.to_vec()).map_err(|e| Error::invalid_argument(e.to_string().into()))
+    }
+}
+
+macro_rules! impl_value_codec_int {
+    ($($t:ty),*) => {$(
+        impl ValueCodec for $t {
+            fn encode_value(&self) -> Vec<u8> {
+                self.to_be_bytes().to_vec()
+            }
+
+            fn decode_value(raw: &[u8]) -> Result<Self, Error> {
+                let mut bytes = [0u8; std::mem::size_of::<$t>()];
+                if raw.len() != bytes.len() {
+                    return Err(Error::invalid_argument(
+                        format!("expected {} value bytes, got {}", bytes.len(), raw.len()).into()))
+                }
+                bytes.copy_from_slice(raw);
+                Ok(<$t>::from_be_bytes(bytes))
+            }
+        }
+    )*}
+}
+
+impl_value_codec_int!(u8, u16, u32, u64, i8, i16, i32, i64, f32, f64);
+
+/// A view of a column family with typed keys and values. The column family has to order keys
+/// bytewise, so that encoded keys sort like the keys.
+pub struct TypedDb<'a, K, V> {
+    db: &'a ScottDB<'a>,
+    cf: &'a ColumnFamily<'a>,
+    _marker: PhantomData<fn() -> (K, V)>
+}
+
+impl<'a, K: KeyCodec, V: ValueCodec> TypedDb<'a, K, V> {
+    /// A typed view of the default column family.
+    pub fn new(db: &'a ScottDB<'a>) -> Result<Self, Error> {
+        Self::with_column_family(db, db.default_column_family())
+    }
+
+    pub fn with_column_family(db: &'a ScottDB<'a>, cf: &'a ColumnFamily<'a>) -> Result<Self, Error> {
+        let comparator = cf.options().comparator.name();
+        if comparator != DefaultComparator().name() {
+            return Err(Error::invalid_argument(
+                format!("column family {} orders keys by {}, typed keys need bytewise order",
+                        cf.name(), comparator).into()))
+        }
+        Ok(Self { db, cf, _marker: PhantomData })
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>, Error> {
+        self.db.get_cf(self.cf, &key.encode_key())?
+            .map(|value| V::decode_value(&value))
+            .transpose()
+    }
+
+    pub fn put(&self, key: &K, value: &V) -> Result<(), Error> {
+        self.db.put_cf(self.cf, &key.encode_key(), &value.encode_value())
+    }
+
+    pub fn delete(&self, key: &K) -> Result<(), Error> {
+        self.db.delete_cf(self.cf, &key.encode_key())
+    }
+
+    /// Iterates over all key value pairs within `range` in key order.
+    pub fn range(&self, range: impl RangeBounds<K>) -> Result<TypedIterator<K, V>, Error> {
+        let encode = |bound: Bound<&K>| match bound {
+            Bound::Included(key) => Bound::Included(key.encode_key()),
+            Bound::Excluded(key) => Bound::Excluded(key.encode_key()),
+            Bound::Unbounded => Bound::Unbounded
+        };
+        let (lower, upper) = (encode(range.start_bound()), encode(range.end_bound()));
+        let inner = self.db.scan_cf(self.cf, as_slice(&lower), as_slice(&upper))?;
+        Ok(TypedIterator { inner, _marker: PhantomData })
+    }
+}
+
+fn as_slice(bound: &Bound<Vec<u8>>) -> Bound<&[u8]> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key),
+        Bound::Excluded(key) => Bound::Excluded(key),
+        Bound::Unbounded => Bound::Unbounded
+    }
+}
+
+/// Iterates over decoded key value pairs, yields an error for pairs which fail to decode.
+pub struct TypedIterator<K, V> {
+    inner: ScottIterator,
+    _marker: PhantomData<fn() -> (K, V)>
+}
+
+impl<K: KeyCodec, V: ValueCodec> Iterator for TypedIterator<K, V> {
+    type Item = Result<(K, V), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(key, value)| Ok((K::decode_key(&key)?, V::decode_value(&value)?)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use crate::{ScottDB, Options, ReverseBytewiseComparator, Error};
+    use crate::encode::memcmp::Descending;
+    use crate::typed::{TypedDb, KeyCodec, ValueCodec};
+
+    #[derive(Debug, PartialEq)]
+    struct Verse {
+        line: String,
+        times_sung: u64
+    }
+
+    impl ValueCodec for Verse {
+        fn encode_value(&self) -> Vec<u8> {
+            let mut ret = self.times_sung.to_be_bytes().to_vec();
+            ret.extend_from_slice(self.line.as_bytes());
+            ret
+        }
+
+        fn decode_value(raw: &[u8]) -> Result<Self, Error> {
+            if raw.len() < 8 {
+                return Err(Error::invalid_argument("verse too short".into()))
+            }
+            Ok(Verse { times_sung: u64::decode_value(&raw[..8])?, line: String::decode_value(&raw[8..])? })
+        }
+    }
+
+    fn verse(line: &str, times_sung: u64) -> Verse {
+        Verse { line: line.to_string(), times_sung }
+    }
+
+    #[test]
+    fn test_typed_db() {
+        let db = ScottDB::new(Options::new("test_typed_db", 4, 4, 10, 16, 4096, 64, 256)).unwrap();
+        let verses: TypedDb<(String, Descending<i32>), Verse> = TypedDb::new(&db).unwrap();
+        let key = |song: &str, n: i32| (song.to_string(), Descending(n));
+        verses.put(&key("Катюша", 1), &verse("Расцветали яблони и груши", 3)).unwrap();
+        verses.put(&key("Катюша", 2), &verse("Поплыли туманы над рекой", 2)).unwrap();
+        verses.put(&key("Катюша", -1), &verse("Выходила на берег Катюша", 1)).unwrap();
+        verses.put(&key("喀秋莎", 1), &verse("正当梨花开遍了天涯", 5)).unwrap();
+        verses.delete(&key("Катюша", 2)).unwrap();
+
+        assert_eq!(verses.get(&key("Катюша", 1)).unwrap(), Some(verse("Расцветали яблони и груши", 3)));
+        assert_eq!(verses.get(&key("Катюша", 2)).unwrap(), None);
+
+        let keys: Vec<(String, Descending<i32>)> = verses.range(key("Катюша", i32::MAX)..key("喀秋莎", 1)).unwrap()
+            .map(|kv| kv.unwrap().0)
+            .collect();
+        assert_eq!(keys, vec![key("Катюша", 1), key("Катюша", -1)]);
+        assert_eq!(verses.range(..).unwrap().count(), 3);
+        assert_eq!(verses.range(key("喀秋莎", 1)..=key("喀秋莎", 1)).unwrap().count(), 1);
+
+        db.put(&key("Катюша", 3).encode_key(), "?".as_bytes()).unwrap();
+        assert!(verses.get(&key("Катюша", 3)).is_err());
+        let numbers: TypedDb<u64, String> = TypedDb::new(&db).unwrap();
+        assert!(numbers.range(..).unwrap().any(|kv| kv.is_err()));
+    }
+
+    #[test]
+    fn test_typed_db_requires_bytewise_order() {
+        let db = ScottDB::new(Options::new("test_typed_db_requires_bytewise_order", 4, 4, 10, 16, 4096, 64, 256))
+            .unwrap();
+        let mut options = Options::new("test_typed_db_requires_bytewise_order", 4, 4, 10, 16, 4096, 64, 256);
+        options.comparator = Arc::new(ReverseBytewiseComparator());
+        let reversed = db.create_column_family("reversed", options).unwrap();
+        assert!(TypedDb::<u64, u64>::with_column_family(&db, reversed).is_err());
+    }
+}